@@ -1,7 +1,7 @@
 use crate::schema::apps;
 use crate::team::Team;
 use chrono::NaiveDateTime;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Clone, Debug, Queryable, Serialize, Identifiable, Associations)]
 #[belongs_to(Team)]
@@ -15,6 +15,36 @@ pub struct App {
 	pub container_id: Option<String>,
 	#[serde(skip_serializing)]
 	pub network_id: Option<String>,
+	/// Memory limit in bytes
+	pub memory_limit: i64,
+	/// Relative CPU weight
+	pub cpu_shares: i64,
+	/// CPU time per 100ms period, in microseconds
+	pub cpu_quota: i64,
+	pub pids_limit: i64,
+	/// Build timeout in seconds
+	pub build_timeout: i32,
+	/// Public half of the SSH key used to clone the app's repository
+	#[serde(skip_serializing)]
+	pub deploy_key_public: Option<String>,
+	/// Private half of the deploy key, encrypted
+	#[serde(skip_serializing)]
+	pub deploy_key_private: Option<String>,
+	/// Token used to clone the app's repository over HTTPS, encrypted
+	#[serde(skip_serializing)]
+	pub git_https_token: Option<String>,
+	/// Repository deployed when no other is given, and on pushes to `git_branch`
+	pub git_repository: Option<String>,
+	/// Branch deployed when no other ref is given, and whose pushes trigger deploys
+	pub git_branch: Option<String>,
+	/// Secret used to sign webhook deliveries, encrypted
+	#[serde(skip_serializing)]
+	pub webhook_secret: Option<String>,
+	/// Run before each deploy takes traffic, in a container from the new image
+	pub release_command: Option<String>,
+	/// Set when the app's domains changed, until its Caddy route is updated accordingly
+	#[serde(skip_serializing)]
+	pub hosts_sync_requested_at: Option<NaiveDateTime>,
 }
 
 #[derive(Clone, Insertable, Deserialize, Debug)]
@@ -24,3 +54,31 @@ pub struct NewApp {
 	#[serde(skip_deserializing)]
 	pub team_id: i32,
 }
+
+#[derive(Clone, Debug, Default, AsChangeset, Deserialize)]
+#[table_name = "apps"]
+pub struct UpdatedApp {
+	pub memory_limit: Option<i64>,
+	pub cpu_shares: Option<i64>,
+	pub cpu_quota: Option<i64>,
+	pub pids_limit: Option<i64>,
+	pub build_timeout: Option<i32>,
+	/// `null` unlinks the repository
+	#[serde(default, deserialize_with = "deserialize_some")]
+	pub git_repository: Option<Option<String>>,
+	/// `null` deploys the repository's default branch
+	#[serde(default, deserialize_with = "deserialize_some")]
+	pub git_branch: Option<Option<String>>,
+	/// `null` removes the release command
+	#[serde(default, deserialize_with = "deserialize_some")]
+	pub release_command: Option<Option<String>>,
+}
+
+/// Tells fields set to `null`, which are cleared, apart from missing ones, which are left as is
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+	T: Deserialize<'de>,
+	D: Deserializer<'de>,
+{
+	T::deserialize(deserializer).map(Some)
+}
@@ -0,0 +1,32 @@
+use crate::app::App;
+use crate::schema::app_env_vars;
+use chrono::NaiveDateTime;
+
+/// An environment variable passed to an app's containers. The value is encrypted with the
+/// server's key, so this is never serialized directly.
+#[derive(Clone, Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(App)]
+pub struct AppEnvVar {
+	pub id: i32,
+	pub created_at: NaiveDateTime,
+	pub app_id: i32,
+	pub key: String,
+	pub encrypted_value: String,
+	pub secret: bool,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "app_env_vars"]
+pub struct NewAppEnvVar {
+	pub app_id: i32,
+	pub key: String,
+	pub encrypted_value: String,
+	pub secret: bool,
+}
+
+#[derive(Clone, Debug, Default, AsChangeset)]
+#[table_name = "app_env_vars"]
+pub struct UpdatedAppEnvVar {
+	pub encrypted_value: Option<String>,
+	pub secret: Option<bool>,
+}
@@ -1,7 +1,56 @@
 use crate::app::App;
 use crate::schema::builds;
 use chrono::NaiveDateTime;
+use diesel::{
+	deserialize::{self, FromSql},
+	pg::Pg,
+	serialize::{self, Output, ToSql},
+	sql_types::Text,
+};
 use serde::Serialize;
+use std::io::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, AsExpression, FromSqlRow)]
+#[serde(rename_all = "snake_case")]
+#[sql_type = "Text"]
+pub enum BuildStatus {
+	Queued,
+	Running,
+	Succeeded,
+	Failed,
+	Cancelled,
+}
+
+impl BuildStatus {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Queued => "queued",
+			Self::Running => "running",
+			Self::Succeeded => "succeeded",
+			Self::Failed => "failed",
+			Self::Cancelled => "cancelled",
+		}
+	}
+}
+
+impl ToSql<Text, Pg> for BuildStatus {
+	fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+		<str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+	}
+}
+
+impl FromSql<Text, Pg> for BuildStatus {
+	fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+		match not_none!(bytes) {
+			b"queued" => Ok(Self::Queued),
+			b"running" => Ok(Self::Running),
+			b"succeeded" => Ok(Self::Succeeded),
+			b"failed" => Ok(Self::Failed),
+			b"cancelled" => Ok(Self::Cancelled),
+			_ => Err("Unrecognized build status".into()),
+		}
+	}
+}
 
 #[derive(Clone, Debug, Queryable, Serialize, Identifiable, Associations)]
 #[belongs_to(App)]
@@ -9,12 +58,45 @@ pub struct Build {
 	pub id: i32,
 	pub started_at: NaiveDateTime,
 	pub ended_at: Option<NaiveDateTime>,
-	pub events: Vec<String>,
 	pub app_id: i32,
+	pub status: BuildStatus,
+	#[serde(skip_serializing)]
+	pub git_repository: Option<String>,
+	#[serde(skip_serializing)]
+	pub heartbeat_at: Option<NaiveDateTime>,
+	/// Set when the build was asked to stop while it was running
+	pub cancel_requested: bool,
+	/// Branch, tag or commit SHA that was requested, the default branch if unset
+	pub git_ref: Option<String>,
+	/// Commit that was built, set once its image is built
+	pub commit_sha: Option<String>,
+	pub commit_author: Option<String>,
+	pub commit_message: Option<String>,
+	/// Earlier build whose image is deployed instead of building one, when rolling back
+	pub image_build_id: Option<i32>,
+	/// Settings read from the repository's haas.toml, set once its image is built
+	pub config: Option<serde_json::Value>,
+	/// Queued by a push to the app's branch, rather than by a user
+	pub from_webhook: bool,
+}
+
+impl Build {
+	/// The build whose image this build deploys
+	pub fn image_build_id(&self) -> i32 {
+		self.image_build_id.unwrap_or(self.id)
+	}
 }
 
-#[derive(Clone, Insertable, Debug)]
+#[derive(Clone, Insertable, Debug, Default)]
 #[table_name = "builds"]
 pub struct NewBuild {
 	pub app_id: i32,
+	pub git_repository: String,
+	pub git_ref: Option<String>,
+	pub commit_sha: Option<String>,
+	pub commit_author: Option<String>,
+	pub commit_message: Option<String>,
+	pub image_build_id: Option<i32>,
+	pub config: Option<serde_json::Value>,
+	pub from_webhook: bool,
 }
@@ -0,0 +1,74 @@
+use crate::build::Build;
+use crate::schema::build_events;
+use chrono::NaiveDateTime;
+use diesel::{
+	deserialize::{self, FromSql},
+	pg::Pg,
+	serialize::{self, Output, ToSql},
+	sql_types::Text,
+};
+use serde::Serialize;
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, AsExpression, FromSqlRow)]
+#[serde(rename_all = "snake_case")]
+#[sql_type = "Text"]
+pub enum BuildEventKind {
+	GitClone,
+	DockerBuild,
+	Deploy,
+	Error,
+}
+
+impl BuildEventKind {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::GitClone => "git_clone",
+			Self::DockerBuild => "docker_build",
+			Self::Deploy => "deploy",
+			Self::Error => "error",
+		}
+	}
+}
+
+impl FromStr for BuildEventKind {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"git_clone" => Ok(Self::GitClone),
+			"docker_build" => Ok(Self::DockerBuild),
+			"deploy" => Ok(Self::Deploy),
+			"error" => Ok(Self::Error),
+			_ => Err(()),
+		}
+	}
+}
+
+impl ToSql<Text, Pg> for BuildEventKind {
+	fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+		<str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+	}
+}
+
+impl FromSql<Text, Pg> for BuildEventKind {
+	fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+		std::str::from_utf8(not_none!(bytes))?
+			.parse()
+			.map_err(|_| "Unrecognized build event kind".into())
+	}
+}
+
+#[derive(Clone, Debug, Queryable, Insertable, Serialize, Associations)]
+#[belongs_to(Build)]
+#[table_name = "build_events"]
+pub struct BuildEvent {
+	#[serde(skip_serializing)]
+	pub build_id: i32,
+	/// Position of the event in the build, starting at 1
+	pub seq: i32,
+	pub ts: NaiveDateTime,
+	pub kind: BuildEventKind,
+	pub payload: serde_json::Value,
+}
@@ -0,0 +1,31 @@
+use super::{app::App, user::User};
+use crate::schema::console_sessions;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+/// An interactive shell opened into an app's container, kept for auditing. Sessions outlive the
+/// app and the user, which are then unset, so the names they had are kept as well.
+#[derive(Clone, Debug, Queryable, Serialize, Identifiable, Associations)]
+#[belongs_to(App)]
+#[belongs_to(User)]
+pub struct ConsoleSession {
+	pub id: i32,
+	pub app_id: Option<i32>,
+	pub user_id: Option<i32>,
+	pub started_at: NaiveDateTime,
+	/// Unset while the session is open
+	pub ended_at: Option<NaiveDateTime>,
+	pub app_slug: String,
+	pub user_name: String,
+	#[serde(skip_serializing)]
+	pub heartbeat_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "console_sessions"]
+pub struct NewConsoleSession {
+	pub app_id: i32,
+	pub user_id: i32,
+	pub app_slug: String,
+	pub user_name: String,
+}
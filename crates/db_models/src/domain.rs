@@ -1,6 +1,48 @@
 use super::app::App;
 use crate::schema::domains;
+use chrono::NaiveDateTime;
+use diesel::{
+	deserialize::{self, FromSql},
+	pg::Pg,
+	serialize::{self, Output, ToSql},
+	sql_types::Text,
+};
 use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, AsExpression, FromSqlRow)]
+#[serde(rename_all = "snake_case")]
+#[sql_type = "Text"]
+pub enum CertificateState {
+	/// Caddy hasn't obtained a certificate for the domain yet, or it expired
+	Pending,
+	Issued,
+}
+
+impl CertificateState {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Pending => "pending",
+			Self::Issued => "issued",
+		}
+	}
+}
+
+impl ToSql<Text, Pg> for CertificateState {
+	fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+		<str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+	}
+}
+
+impl FromSql<Text, Pg> for CertificateState {
+	fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+		match not_none!(bytes) {
+			b"pending" => Ok(Self::Pending),
+			b"issued" => Ok(Self::Issued),
+			_ => Err("Unrecognized certificate state".into()),
+		}
+	}
+}
 
 #[derive(Clone, Debug, Queryable, Serialize, Identifiable, Associations)]
 #[belongs_to(App)]
@@ -9,6 +51,23 @@ pub struct Domain {
 	pub domain: String,
 	pub verified: bool,
 	pub app_id: i32,
+	/// Last time the domain's DNS records were checked
+	pub last_checked_at: Option<NaiveDateTime>,
+	/// Last time the domain's DNS records were found to point to us
+	pub last_verified_at: Option<NaiveDateTime>,
+	/// Expected in the domain's `_haas-challenge` TXT record, as proof of ownership
+	pub challenge_token: String,
+	/// State of the certificate Caddy served for the domain when it was last checked, unset until
+	/// the domain's first check after being verified
+	#[serde(skip_serializing)]
+	pub certificate_state: Option<CertificateState>,
+	#[serde(skip_serializing)]
+	pub certificate_expires_at: Option<NaiveDateTime>,
+	/// Why the served certificate wasn't trusted, while pending
+	#[serde(skip_serializing)]
+	pub certificate_error: Option<String>,
+	#[serde(skip_serializing)]
+	pub certificate_checked_at: Option<NaiveDateTime>,
 }
 
 #[derive(Clone, Deserialize, Debug, Insertable)]
@@ -19,4 +78,6 @@ pub struct NewDomain {
 	pub verified: bool,
 	#[serde(skip_deserializing)]
 	pub app_id: i32,
+	#[serde(skip_deserializing)]
+	pub challenge_token: String,
 }
@@ -3,14 +3,26 @@ extern crate diesel;
 
 mod app;
 pub use app::*;
+mod app_env_var;
+pub use app_env_var::*;
 mod build;
 pub use build::*;
+mod build_event;
+pub use build_event::*;
+mod console_session;
+pub use console_session::*;
 mod domain;
 pub use domain::*;
 mod oauth_app;
 pub use oauth_app::*;
 mod oauth_device_request;
 pub use oauth_device_request::*;
+mod release;
+pub use release::*;
+mod run;
+pub use run::*;
+mod task_schedule;
+pub use task_schedule::*;
 mod team;
 pub use team::*;
 mod team_user;
@@ -0,0 +1,25 @@
+use super::app::App;
+use crate::schema::releases;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+/// A deploy that made it live. The app's latest release is the one currently serving traffic.
+#[derive(Clone, Debug, Queryable, Serialize, Identifiable, Associations)]
+#[belongs_to(App)]
+pub struct Release {
+	pub id: i32,
+	pub created_at: NaiveDateTime,
+	pub app_id: i32,
+	/// Build that deployed the release
+	pub build_id: i32,
+	/// Build whose image is running, which differs from `build_id` for rollbacks
+	pub image_build_id: i32,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "releases"]
+pub struct NewRelease {
+	pub app_id: i32,
+	pub build_id: i32,
+	pub image_build_id: i32,
+}
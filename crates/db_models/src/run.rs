@@ -0,0 +1,97 @@
+use crate::app::App;
+use crate::schema::runs;
+use chrono::NaiveDateTime;
+use diesel::{
+	deserialize::{self, FromSql},
+	pg::Pg,
+	serialize::{self, Output, ToSql},
+	sql_types::Text,
+};
+use serde::Serialize;
+use std::io::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, AsExpression, FromSqlRow)]
+#[serde(rename_all = "snake_case")]
+#[sql_type = "Text"]
+pub enum RunStatus {
+	Queued,
+	Running,
+	/// The command exited with 0
+	Succeeded,
+	Failed,
+	/// Never ran, since an earlier run of its schedule was still going
+	Skipped,
+}
+
+impl RunStatus {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Queued => "queued",
+			Self::Running => "running",
+			Self::Succeeded => "succeeded",
+			Self::Failed => "failed",
+			Self::Skipped => "skipped",
+		}
+	}
+}
+
+impl ToSql<Text, Pg> for RunStatus {
+	fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+		<str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+	}
+}
+
+impl FromSql<Text, Pg> for RunStatus {
+	fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+		match not_none!(bytes) {
+			b"queued" => Ok(Self::Queued),
+			b"running" => Ok(Self::Running),
+			b"succeeded" => Ok(Self::Succeeded),
+			b"failed" => Ok(Self::Failed),
+			b"skipped" => Ok(Self::Skipped),
+			_ => Err("Unrecognized run status".into()),
+		}
+	}
+}
+
+/// A command run once in a short-lived container, from the image the app is running
+#[derive(Clone, Debug, Queryable, Serialize, Identifiable, Associations)]
+#[belongs_to(App)]
+pub struct Run {
+	pub id: i32,
+	pub created_at: NaiveDateTime,
+	pub app_id: i32,
+	/// Schedule that triggered the run, unset for runs triggered by hand
+	pub schedule_id: Option<i32>,
+	pub command: String,
+	pub status: RunStatus,
+	pub started_at: Option<NaiveDateTime>,
+	pub ended_at: Option<NaiveDateTime>,
+	#[serde(skip_serializing)]
+	pub heartbeat_at: Option<NaiveDateTime>,
+	/// Unset if the command never exited, e.g. when it timed out
+	pub exit_code: Option<i32>,
+	/// Output of the command, stdout and stderr interleaved. Only the end of long logs is kept.
+	#[serde(skip_serializing)]
+	pub logs: String,
+}
+
+impl Run {
+	/// How long the run took, in seconds, once it ended
+	pub fn duration_secs(&self) -> Option<i64> {
+		match (self.started_at, self.ended_at) {
+			(Some(started_at), Some(ended_at)) => Some((ended_at - started_at).num_seconds()),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "runs"]
+pub struct NewRun {
+	pub app_id: i32,
+	pub schedule_id: Option<i32>,
+	pub command: String,
+	pub status: RunStatus,
+	pub ended_at: Option<NaiveDateTime>,
+}
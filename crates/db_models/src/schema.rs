@@ -1,3 +1,14 @@
+table! {
+	app_env_vars (id) {
+		id -> Int4,
+		created_at -> Timestamp,
+		app_id -> Int4,
+		key -> Text,
+		encrypted_value -> Text,
+		secret -> Bool,
+	}
+}
+
 table! {
 	apps (id) {
 		id -> Int4,
@@ -7,6 +18,29 @@ table! {
 		enabled -> Bool,
 		container_id -> Nullable<Text>,
 		network_id -> Nullable<Text>,
+		memory_limit -> Int8,
+		cpu_shares -> Int8,
+		cpu_quota -> Int8,
+		pids_limit -> Int8,
+		build_timeout -> Int4,
+		deploy_key_public -> Nullable<Text>,
+		deploy_key_private -> Nullable<Text>,
+		git_https_token -> Nullable<Text>,
+		git_repository -> Nullable<Text>,
+		git_branch -> Nullable<Text>,
+		webhook_secret -> Nullable<Text>,
+		release_command -> Nullable<Text>,
+		hosts_sync_requested_at -> Nullable<Timestamp>,
+	}
+}
+
+table! {
+	build_events (build_id, seq) {
+		build_id -> Int4,
+		seq -> Int4,
+		ts -> Timestamp,
+		kind -> Text,
+		payload -> Jsonb,
 	}
 }
 
@@ -15,8 +49,31 @@ table! {
 		id -> Int4,
 		started_at -> Timestamp,
 		ended_at -> Nullable<Timestamp>,
-		events -> Array<Text>,
 		app_id -> Int4,
+		status -> Text,
+		git_repository -> Nullable<Text>,
+		heartbeat_at -> Nullable<Timestamp>,
+		cancel_requested -> Bool,
+		git_ref -> Nullable<Text>,
+		commit_sha -> Nullable<Text>,
+		commit_author -> Nullable<Text>,
+		commit_message -> Nullable<Text>,
+		image_build_id -> Nullable<Int4>,
+		config -> Nullable<Jsonb>,
+		from_webhook -> Bool,
+	}
+}
+
+table! {
+	console_sessions (id) {
+		id -> Int4,
+		app_id -> Nullable<Int4>,
+		user_id -> Nullable<Int4>,
+		started_at -> Timestamp,
+		ended_at -> Nullable<Timestamp>,
+		app_slug -> Text,
+		user_name -> Text,
+		heartbeat_at -> Nullable<Timestamp>,
 	}
 }
 
@@ -26,6 +83,13 @@ table! {
 		domain -> Text,
 		verified -> Bool,
 		app_id -> Int4,
+		last_checked_at -> Nullable<Timestamp>,
+		last_verified_at -> Nullable<Timestamp>,
+		challenge_token -> Text,
+		certificate_state -> Nullable<Text>,
+		certificate_expires_at -> Nullable<Timestamp>,
+		certificate_error -> Nullable<Text>,
+		certificate_checked_at -> Nullable<Timestamp>,
 	}
 }
 
@@ -57,6 +121,45 @@ table! {
 	}
 }
 
+table! {
+	releases (id) {
+		id -> Int4,
+		created_at -> Timestamp,
+		app_id -> Int4,
+		build_id -> Int4,
+		image_build_id -> Int4,
+	}
+}
+
+table! {
+	runs (id) {
+		id -> Int4,
+		created_at -> Timestamp,
+		app_id -> Int4,
+		schedule_id -> Nullable<Int4>,
+		command -> Text,
+		status -> Text,
+		started_at -> Nullable<Timestamp>,
+		ended_at -> Nullable<Timestamp>,
+		heartbeat_at -> Nullable<Timestamp>,
+		exit_code -> Nullable<Int4>,
+		logs -> Text,
+	}
+}
+
+table! {
+	task_schedules (id) {
+		id -> Int4,
+		created_at -> Timestamp,
+		app_id -> Int4,
+		name -> Text,
+		command -> Text,
+		cron -> Text,
+		concurrency_policy -> Text,
+		next_run_at -> Nullable<Timestamp>,
+	}
+}
+
 table! {
 	team_users (team_id, user_id) {
 		user_id -> Int4,
@@ -100,24 +203,38 @@ table! {
 	}
 }
 
+joinable!(app_env_vars -> apps (app_id));
 joinable!(apps -> teams (team_id));
+joinable!(build_events -> builds (build_id));
 joinable!(builds -> apps (app_id));
+joinable!(console_sessions -> apps (app_id));
+joinable!(console_sessions -> users (user_id));
 joinable!(domains -> apps (app_id));
 joinable!(invites -> teams (team_id));
 joinable!(invites -> users (user_id));
 joinable!(oauth_device_requests -> oauth_apps (oauth_app_id));
 joinable!(oauth_device_requests -> tokens (token));
+joinable!(releases -> apps (app_id));
+joinable!(runs -> apps (app_id));
+joinable!(runs -> task_schedules (schedule_id));
+joinable!(task_schedules -> apps (app_id));
 joinable!(team_users -> teams (team_id));
 joinable!(team_users -> users (user_id));
 joinable!(tokens -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+	app_env_vars,
 	apps,
+	build_events,
 	builds,
+	console_sessions,
 	domains,
 	invites,
 	oauth_apps,
 	oauth_device_requests,
+	releases,
+	runs,
+	task_schedules,
 	team_users,
 	teams,
 	tokens,
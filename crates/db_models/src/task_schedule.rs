@@ -0,0 +1,80 @@
+use crate::app::App;
+use crate::schema::task_schedules;
+use chrono::NaiveDateTime;
+use diesel::{
+	deserialize::{self, FromSql},
+	pg::Pg,
+	serialize::{self, Output, ToSql},
+	sql_types::Text,
+};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+/// What happens when a schedule is due while its previous run is still queued or running
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[serde(rename_all = "snake_case")]
+#[sql_type = "Text"]
+pub enum ConcurrencyPolicy {
+	/// The new run is recorded as skipped
+	Skip,
+	/// The new run waits for the previous one to end
+	Queue,
+}
+
+impl Default for ConcurrencyPolicy {
+	fn default() -> Self {
+		Self::Skip
+	}
+}
+
+impl ConcurrencyPolicy {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Skip => "skip",
+			Self::Queue => "queue",
+		}
+	}
+}
+
+impl ToSql<Text, Pg> for ConcurrencyPolicy {
+	fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+		<str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+	}
+}
+
+impl FromSql<Text, Pg> for ConcurrencyPolicy {
+	fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+		match not_none!(bytes) {
+			b"skip" => Ok(Self::Skip),
+			b"queue" => Ok(Self::Queue),
+			_ => Err("Unrecognized concurrency policy".into()),
+		}
+	}
+}
+
+/// A command run on a cron schedule
+#[derive(Clone, Debug, Queryable, Serialize, Identifiable, Associations)]
+#[belongs_to(App)]
+pub struct TaskSchedule {
+	pub id: i32,
+	pub created_at: NaiveDateTime,
+	pub app_id: i32,
+	pub name: String,
+	pub command: String,
+	/// Cron expression, evaluated in UTC
+	pub cron: String,
+	pub concurrency_policy: ConcurrencyPolicy,
+	/// Unset once the cron expression won't match again
+	pub next_run_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "task_schedules"]
+pub struct NewTaskSchedule {
+	pub app_id: i32,
+	pub name: String,
+	pub command: String,
+	pub cron: String,
+	pub concurrency_policy: ConcurrencyPolicy,
+	pub next_run_at: Option<NaiveDateTime>,
+}
@@ -0,0 +1,229 @@
+use std::path::Path;
+
+use crate::{
+	git::{fetched_file, fetched_object_type},
+	repo_config::join_repo_path,
+	ProvisionerError, Result,
+};
+
+/// How the image of a build is made
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Builder {
+	/// The repository's own Dockerfile
+	Dockerfile,
+	Node,
+	Python,
+	Rust,
+	Go,
+	/// Static files served by nginx
+	Static,
+}
+
+/// Files that give away how to build a project without a Dockerfile, in order of precedence. Node
+/// projects often have an index.html as well, for instance.
+const MARKERS: &[(&str, Builder)] = &[
+	("package.json", Builder::Node),
+	("requirements.txt", Builder::Python),
+	("pyproject.toml", Builder::Python),
+	("Cargo.toml", Builder::Rust),
+	("go.mod", Builder::Go),
+	("index.html", Builder::Static),
+];
+
+const NODE_DOCKERFILE: &str = r#"# Generated, add a Dockerfile to the repository to customize the build
+FROM node:16.18.1-bullseye-slim
+WORKDIR /app
+COPY package*.json yarn.lock* ./
+RUN if [ -f yarn.lock ]; then yarn install --frozen-lockfile; \
+	elif [ -f package-lock.json ]; then npm ci; \
+	else npm install; fi
+COPY . .
+RUN npm run build --if-present
+ENV NODE_ENV=production PORT=3000
+EXPOSE 3000
+CMD ["npm", "start"]
+"#;
+
+const PYTHON_DOCKERFILE: &str = r#"# Generated, add a Dockerfile to the repository to customize the build
+FROM python:3.10.8-slim-bullseye
+WORKDIR /app
+ENV PYTHONUNBUFFERED=1 PIP_NO_CACHE_DIR=1 PIP_DISABLE_PIP_VERSION_CHECK=1
+COPY . .
+RUN if [ -f requirements.txt ]; then pip install -r requirements.txt; else pip install .; fi
+ENV PORT=8000
+EXPOSE 8000
+CMD if [ -f main.py ]; then exec python main.py; else exec python app.py; fi
+"#;
+
+const GO_DOCKERFILE: &str = r#"# Generated, add a Dockerfile to the repository to customize the build
+FROM golang:1.19.3-bullseye AS build
+WORKDIR /src
+COPY go.* ./
+RUN go mod download
+COPY . .
+RUN CGO_ENABLED=0 go build -o /app .
+
+FROM debian:bullseye-20221114-slim
+RUN apt-get update && apt-get install -y --no-install-recommends ca-certificates \
+	&& rm -rf /var/lib/apt/lists/*
+COPY --from=build /app /usr/local/bin/app
+ENV PORT=8080
+EXPOSE 8080
+CMD ["app"]
+"#;
+
+const STATIC_DOCKERFILE: &str = r#"# Generated, add a Dockerfile to the repository to customize the build
+FROM nginx:1.23.2-alpine
+COPY . /usr/share/nginx/html
+"#;
+
+fn rust_dockerfile(bin: &str) -> String {
+	format!(
+		r#"# Generated, add a Dockerfile to the repository to customize the build
+FROM rust:1.65.0-slim-bullseye AS build
+WORKDIR /src
+COPY . .
+RUN cargo build --release --bin {bin} && cp target/release/{bin} /app
+
+FROM debian:bullseye-20221114-slim
+RUN apt-get update && apt-get install -y --no-install-recommends ca-certificates \
+	&& rm -rf /var/lib/apt/lists/*
+COPY --from=build /app /usr/local/bin/app
+ENV PORT=8080
+EXPOSE 8080
+CMD ["app"]
+"#,
+		bin = bin
+	)
+}
+
+/// Reads the name of the binary to build from a Cargo.toml: its first `[[bin]]`, or the package
+/// itself
+fn cargo_bin_name(manifest: &str) -> Result<String> {
+	let manifest = manifest
+		.parse::<toml::Value>()
+		.map_err(|e| ProvisionerError::BuildpackError(format!("Invalid Cargo.toml: {}", e)))?;
+	let bin = manifest
+		.get("bin")
+		.and_then(|bins| bins.as_array()?.first()?.get("name"))
+		.or_else(|| manifest.get("package")?.get("name"))
+		.and_then(toml::Value::as_str)
+		.ok_or_else(|| {
+			ProvisionerError::BuildpackError(
+				"Cargo.toml has no package to build, workspaces need a Dockerfile".to_owned(),
+			)
+		})?;
+	// It ends up in a shell command
+	if bin.is_empty()
+		|| !bin
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+	{
+		return Err(ProvisionerError::BuildpackError(format!(
+			"Invalid binary name {:?} in Cargo.toml",
+			bin
+		)));
+	}
+	Ok(bin.to_owned())
+}
+
+/// Picks how to build the fetched commit, from the files at the root of its build context
+pub(crate) async fn detect_builder(dir: &Path, context: &str) -> Result<Builder> {
+	for (marker, builder) in MARKERS {
+		let path = join_repo_path(context, marker);
+		if fetched_object_type(dir, &path).await?.as_deref() == Some("blob") {
+			return Ok(*builder);
+		}
+	}
+
+	Err(ProvisionerError::BuildpackError(format!(
+		"There is no Dockerfile, nor any of {} to tell how to build it",
+		MARKERS
+			.iter()
+			.map(|(marker, _)| *marker)
+			.collect::<Vec<_>>()
+			.join(", ")
+	)))
+}
+
+/// Returns the Dockerfile to add to the build context for the builder, if it is not the
+/// repository's own
+pub(crate) async fn generated_dockerfile(
+	dir: &Path,
+	context: &str,
+	builder: Builder,
+) -> Result<Option<String>> {
+	Ok(match builder {
+		Builder::Dockerfile => None,
+		Builder::Node => Some(NODE_DOCKERFILE.to_owned()),
+		Builder::Python => Some(PYTHON_DOCKERFILE.to_owned()),
+		Builder::Go => Some(GO_DOCKERFILE.to_owned()),
+		Builder::Static => Some(STATIC_DOCKERFILE.to_owned()),
+		Builder::Rust => {
+			let manifest = fetched_file(dir, &join_repo_path(context, "Cargo.toml"))
+				.await?
+				.ok_or(ProvisionerError::GitCloneFailed)?;
+			let bin = cargo_bin_name(&String::from_utf8_lossy(&manifest))?;
+			Some(rust_dockerfile(&bin))
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn uses_the_package_name() {
+		let manifest = "[package]\nname = \"dinopoll\"\nversion = \"0.1.0\"\n";
+		assert_eq!(cargo_bin_name(manifest).unwrap(), "dinopoll");
+	}
+
+	#[test]
+	fn prefers_the_first_bin() {
+		let manifest = r#"
+			[package]
+			name = "dinopoll"
+
+			[[bin]]
+			name = "dinopoll-server"
+			path = "src/server.rs"
+
+			[[bin]]
+			name = "dinopoll-cli"
+			path = "src/cli.rs"
+		"#;
+		assert_eq!(cargo_bin_name(manifest).unwrap(), "dinopoll-server");
+	}
+
+	#[test]
+	fn falls_back_to_the_package_when_bins_are_unnamed() {
+		let manifest = "[package]\nname = \"dinopoll\"\n\n[[bin]]\npath = \"src/main.rs\"\n";
+		assert_eq!(cargo_bin_name(manifest).unwrap(), "dinopoll");
+	}
+
+	#[test]
+	fn rejects_workspaces() {
+		let manifest = "[workspace]\nmembers = [\"api\", \"web\"]\n";
+		assert!(cargo_bin_name(manifest).is_err());
+	}
+
+	#[test]
+	fn rejects_names_unsafe_in_a_shell() {
+		for name in ["", "app; rm -rf /", "app name", "$(whoami)", "../app"] {
+			let manifest = format!("[package]\nname = {:?}\n", name);
+			assert!(
+				cargo_bin_name(&manifest).is_err(),
+				"{:?} was accepted",
+				name
+			);
+		}
+	}
+
+	#[test]
+	fn rejects_invalid_manifests() {
+		assert!(cargo_bin_name("[package\nname = \"dinopoll\"").is_err());
+		assert!(cargo_bin_name("[package]\nname = 3\n").is_err());
+	}
+}
@@ -0,0 +1,23 @@
+use crate::{Provisioner, Result};
+
+/// Prints the certificates stored for the domain `$1` under Caddy's data directory `$0`, one per
+/// issuer it obtained one from. Prints nothing when there are none yet.
+const CAT_CERTIFICATES: &str = r#"cat "$0"/certificates/*/"$1"/"$1".crt 2>/dev/null || true"#;
+
+impl Provisioner {
+	/// Reads the certificates Caddy obtained for a domain from its storage, as PEM chains. Caddy's
+	/// admin API doesn't expose the certificates it manages, so they are read from within its
+	/// container. Empty until Caddy obtains one.
+	pub async fn caddy_certificates(&self, domain: &str) -> Result<Vec<u8>> {
+		// The arguments are passed separately so that the shell doesn't interpret them
+		let cmd = vec![
+			"sh".to_owned(),
+			"-c".to_owned(),
+			CAT_CERTIFICATES.to_owned(),
+			self.caddy_storage.clone(),
+			domain.to_owned(),
+		];
+		let (_, stdout) = self.run_in_caddy(cmd).await?;
+		Ok(stdout)
+	}
+}
@@ -0,0 +1,68 @@
+use std::pin::Pin;
+
+use bollard::{
+	container::LogOutput,
+	exec::{CreateExecOptions, ResizeExecOptions, StartExecResults},
+};
+use tokio::io::AsyncWrite;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{Provisioner, ProvisionerError, Result};
+
+/// Prefers bash, which most images don't ship
+const CONSOLE_SHELL: &str = "if command -v bash >/dev/null 2>&1; then exec bash; else exec sh; fi";
+
+/// A shell running in a container, attached to a TTY
+pub struct Console {
+	pub exec_id: String,
+	/// What the TTY prints
+	pub output: Pin<Box<dyn Stream<Item = Result<Vec<u8>>> + Send>>,
+	/// What gets typed into the TTY
+	pub input: Pin<Box<dyn AsyncWrite + Send>>,
+}
+
+impl Provisioner {
+	/// Opens a shell into a running container. The shell exits when `input` is dropped.
+	/// !!! This does not do any privilege checks
+	pub async fn open_console(&self, container_id: &str) -> Result<Console> {
+		let exec = self
+			.docker
+			.create_exec(
+				container_id,
+				CreateExecOptions {
+					attach_stdin: Some(true),
+					attach_stdout: Some(true),
+					attach_stderr: Some(true),
+					tty: Some(true),
+					env: Some(vec!["TERM=xterm-256color"]),
+					cmd: Some(vec!["/bin/sh", "-c", CONSOLE_SHELL]),
+					..Default::default()
+				},
+			)
+			.await?;
+
+		match self.docker.start_exec(&exec.id, None).await? {
+			StartExecResults::Attached { output, input } => Ok(Console {
+				exec_id: exec.id,
+				output: Box::pin(output.filter_map(|output| match output {
+					// With a TTY, everything is written to stdout
+					Ok(LogOutput::StdOut { message })
+					| Ok(LogOutput::StdErr { message })
+					| Ok(LogOutput::Console { message }) => Some(Ok(message.to_vec())),
+					Ok(LogOutput::StdIn { .. }) => None,
+					Err(e) => Some(Err(e.into())),
+				})),
+				input,
+			}),
+			StartExecResults::Detached => Err(ProvisionerError::ConsoleDetached),
+		}
+	}
+
+	/// Resizes the TTY of a console, in characters
+	pub async fn resize_console(&self, exec_id: &str, width: u16, height: u16) -> Result<()> {
+		self.docker
+			.resize_exec(exec_id, ResizeExecOptions { width, height })
+			.await
+			.map_err(Into::into)
+	}
+}
@@ -0,0 +1,449 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Stdio;
+
+use hyper::{Body, Uri};
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tokio_stream::Stream;
+
+use crate::{
+	buildpack::{self, Builder},
+	repo_config::join_repo_path,
+	Provisioner, ProvisionerError, ProvisionerEvent, RepoConfig, Result, REPO_CONFIG_FILE,
+};
+
+/// GitHub's SSH host key, trusted when cloning over SSH unless configured otherwise
+pub const GITHUB_KNOWN_HOST: &str =
+	"github.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+/// A repository to build, and the branch, tag or commit SHA to build from it
+#[derive(Debug, Clone)]
+pub struct GitSource {
+	pub uri: Uri,
+	/// Defaults to the repository's default branch
+	pub git_ref: Option<String>,
+	/// Needed for private repositories
+	pub credentials: Option<GitCredentials>,
+}
+
+/// Credentials used to fetch a private repository
+#[derive(Clone)]
+pub enum GitCredentials {
+	/// Private half of an SSH deploy key, in OpenSSH format. Used with `ssh://` URIs.
+	SshKey(String),
+	/// Access token sent over HTTPS, e.g. a GitHub personal access token
+	HttpsToken(String),
+}
+
+// Keeps secrets out of logs
+impl std::fmt::Debug for GitCredentials {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Self::SshKey(_) => write!(f, "SshKey(..)"),
+			Self::HttpsToken(_) => write!(f, "HttpsToken(..)"),
+		}
+	}
+}
+
+/// An SSH keypair, both halves in OpenSSH format
+pub struct DeployKey {
+	pub public_key: String,
+	pub private_key: String,
+}
+
+/// Generates an ed25519 deploy key with `ssh-keygen`
+pub async fn generate_deploy_key(comment: &str) -> Result<DeployKey> {
+	use mktemp::Temp;
+	use tokio::fs;
+	let key_dir = Temp::new_path();
+	fs::create_dir(&key_dir).await?;
+	let key_path = AsRef::<Path>::as_ref(&key_dir).join("id_ed25519");
+	let output = Command::new("ssh-keygen")
+		.args(["-q", "-t", "ed25519", "-N", "", "-C", comment, "-f"])
+		.arg(&key_path)
+		.stdin(Stdio::null())
+		.output()
+		.await?;
+	if !output.status.success() {
+		return Err(ProvisionerError::KeyGenerationFailed);
+	}
+	Ok(DeployKey {
+		public_key: fs::read_to_string(key_path.with_extension("pub"))
+			.await?
+			.trim_end()
+			.to_owned(),
+		private_key: fs::read_to_string(&key_path).await?,
+	})
+}
+
+/// The commit a build was made from
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CommitInfo {
+	pub sha: String,
+	pub author: String,
+	pub message: String,
+}
+
+/// Checks that `git_ref` can be passed to `git fetch` as a refspec source, and won't be taken for
+/// an option or a refspec with a destination
+pub fn is_valid_git_ref(git_ref: &str) -> bool {
+	!git_ref.is_empty()
+		&& git_ref.len() <= 255
+		&& !git_ref.starts_with('-')
+		&& !git_ref.contains("..")
+		&& !git_ref
+			.chars()
+			.any(|c| c.is_whitespace() || c.is_control() || "~^:?*[\\".contains(c))
+}
+
+/// Runs git in `dir`, forwarding its output as [ProvisionerEvent::GitClone] events
+async fn run_git<I, S>(
+	dir: &Path,
+	args: I,
+	env: &[(&str, String)],
+	chan: &Option<broadcast::Sender<ProvisionerEvent>>,
+) -> Result<()>
+where
+	I: IntoIterator<Item = S>,
+	S: AsRef<OsStr>,
+{
+	let mut child = Command::new("git")
+		.args(args)
+		.envs(env.iter().map(|(k, v)| (k, v)))
+		// Fail instead of waiting for credentials that will never come
+		.env("GIT_TERMINAL_PROMPT", "0")
+		.current_dir(dir)
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		// Don't leave git running if the build is cancelled
+		.kill_on_drop(true)
+		.spawn()?;
+	let stdout = child.stdout.take().unwrap();
+	let stderr = child.stderr.take().unwrap();
+	let chan2 = chan.clone();
+	tokio::spawn(async move {
+		let chan = match chan2 {
+			Some(c) => c,
+			None => return,
+		};
+		use tokio::io::{AsyncBufReadExt, BufReader};
+		let mut stdout_lines = BufReader::new(stdout).lines();
+		let mut stderr_lines = BufReader::new(stderr).lines();
+		loop {
+			let line = tokio::select! {
+				stdout_line = stdout_lines.next_line() => match stdout_line {
+					Ok(Some(s)) => s,
+					_ => continue,
+				},
+				stderr_line = stderr_lines.next_line() => match stderr_line {
+					Ok(Some(s)) => s,
+					_ => continue,
+				},
+				else => break,
+			};
+			chan.send(ProvisionerEvent::GitClone(line)).unwrap();
+		}
+	});
+	let status = child.wait().await?;
+	if !status.success() {
+		return Err(ProvisionerError::GitCloneFailed);
+	}
+	Ok(())
+}
+
+/// Reads the SHA, author and message of the fetched commit
+async fn fetched_commit(dir: &Path) -> Result<CommitInfo> {
+	let output = Command::new("git")
+		.args(["log", "-1", "--format=%H%n%an <%ae>%n%B", "FETCH_HEAD"])
+		.current_dir(dir)
+		.kill_on_drop(true)
+		.output()
+		.await?;
+	if !output.status.success() {
+		return Err(ProvisionerError::GitCloneFailed);
+	}
+	let output = String::from_utf8_lossy(&output.stdout);
+	let mut lines = output.splitn(3, '\n');
+	Ok(CommitInfo {
+		sha: lines.next().unwrap_or_default().to_owned(),
+		author: lines.next().unwrap_or_default().to_owned(),
+		message: lines.next().unwrap_or_default().trim_end().to_owned(),
+	})
+}
+
+/// Returns the type of the object at `path` in the fetched commit, e.g. `blob` for files and
+/// `tree` for directories, or `None` if there is nothing there
+pub(crate) async fn fetched_object_type(dir: &Path, path: &str) -> Result<Option<String>> {
+	let output = Command::new("git")
+		.args(["cat-file", "-t"])
+		.arg(format!("FETCH_HEAD:{}", path))
+		.current_dir(dir)
+		.kill_on_drop(true)
+		.output()
+		.await?;
+	Ok(if output.status.success() {
+		Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+	} else {
+		None
+	})
+}
+
+/// Reads the file at `path` in the fetched commit, if there is one
+pub(crate) async fn fetched_file(dir: &Path, path: &str) -> Result<Option<Vec<u8>>> {
+	if fetched_object_type(dir, path).await?.as_deref() != Some("blob") {
+		return Ok(None);
+	}
+	let output = Command::new("git")
+		.args(["cat-file", "blob"])
+		.arg(format!("FETCH_HEAD:{}", path))
+		.current_dir(dir)
+		.kill_on_drop(true)
+		.output()
+		.await?;
+	if !output.status.success() {
+		return Err(ProvisionerError::GitCloneFailed);
+	}
+	Ok(Some(output.stdout))
+}
+
+/// Runs a git plumbing command in `dir`, feeding it `input`, and returns its trimmed output
+async fn git_plumbing(dir: &Path, args: &[&str], input: &[u8]) -> Result<String> {
+	use tokio::io::AsyncWriteExt;
+	let mut child = Command::new("git")
+		.args(args)
+		.current_dir(dir)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::null())
+		.kill_on_drop(true)
+		.spawn()?;
+	let mut stdin = child.stdin.take().unwrap();
+	stdin.write_all(input).await?;
+	// Closing stdin lets git know the input is complete
+	drop(stdin);
+	let output = child.wait_with_output().await?;
+	if !output.status.success() {
+		return Err(ProvisionerError::GitCloneFailed);
+	}
+	Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Writes a copy of `tree` with a file added at its root, and returns the new tree's id. Goes
+/// through the clone's index, which is otherwise unused since nothing is checked out.
+async fn tree_with_file(dir: &Path, tree: &str, name: &str, contents: &[u8]) -> Result<String> {
+	git_plumbing(dir, &["read-tree", tree], b"").await?;
+	let blob = git_plumbing(dir, &["hash-object", "-w", "--stdin"], contents).await?;
+	git_plumbing(
+		dir,
+		&[
+			"update-index",
+			"--add",
+			"--cacheinfo",
+			&format!("100644,{},{}", blob, name),
+		],
+		b"",
+	)
+	.await?;
+	git_plumbing(dir, &["write-tree"], b"").await
+}
+
+/// Reads and validates the fetched commit's `haas.toml`, if it has one. The build context and
+/// Dockerfile it points to have to exist.
+async fn fetched_repo_config(dir: &Path) -> Result<Option<RepoConfig>> {
+	let contents = match fetched_file(dir, REPO_CONFIG_FILE).await? {
+		Some(contents) => contents,
+		None => return Ok(None),
+	};
+	let contents = String::from_utf8(contents).map_err(|_| {
+		ProvisionerError::InvalidRepoConfig("the file is not valid UTF-8".to_owned())
+	})?;
+	let config = RepoConfig::parse(&contents).map_err(ProvisionerError::InvalidRepoConfig)?;
+
+	let context = config.context_path();
+	if fetched_object_type(dir, &context).await?.as_deref() != Some("tree") {
+		return Err(ProvisionerError::InvalidRepoConfig(format!(
+			"build.context: there is no directory {:?} in the repository",
+			context
+		)));
+	}
+	if let Some(dockerfile) = config.dockerfile_path() {
+		let path = join_repo_path(&context, &dockerfile);
+		if fetched_object_type(dir, &path).await?.as_deref() != Some("blob") {
+			return Err(ProvisionerError::InvalidRepoConfig(format!(
+				"build.dockerfile: there is no file {:?} in the repository",
+				path
+			)));
+		}
+	}
+
+	Ok(Some(config))
+}
+
+impl Provisioner {
+	/// Returns the environment that makes git use the source's credentials, writing the files
+	/// that requires to `secrets_dir`
+	async fn git_credentials_env(
+		&self,
+		source: &GitSource,
+		secrets_dir: &Path,
+	) -> Result<Vec<(&'static str, String)>> {
+		use tokio::{fs, io::AsyncWriteExt};
+		match &source.credentials {
+			Some(GitCredentials::SshKey(private_key)) => {
+				fs::create_dir(secrets_dir).await?;
+				let key_path = secrets_dir.join("deploy_key");
+				// ssh refuses keys readable by others
+				let mut key_file = fs::OpenOptions::new()
+					.write(true)
+					.create_new(true)
+					.mode(0o600)
+					.open(&key_path)
+					.await?;
+				key_file.write_all(private_key.as_bytes()).await?;
+				key_file.flush().await?;
+				let known_hosts_path = secrets_dir.join("known_hosts");
+				fs::write(&known_hosts_path, self.known_hosts.join("\n") + "\n").await?;
+				Ok(vec![(
+					"GIT_SSH_COMMAND",
+					format!(
+						"ssh -F /dev/null -i {} -o IdentitiesOnly=yes -o UserKnownHostsFile={} -o StrictHostKeyChecking=yes",
+						key_path.display(),
+						known_hosts_path.display()
+					),
+				)])
+			}
+			Some(GitCredentials::HttpsToken(token)) => {
+				// Passed through the environment rather than the command line, where other
+				// processes could read it
+				let credentials = base64::encode(format!("x-access-token:{}", token));
+				Ok(vec![
+					("GIT_CONFIG_COUNT", "1".to_owned()),
+					("GIT_CONFIG_KEY_0", "http.extraHeader".to_owned()),
+					(
+						"GIT_CONFIG_VALUE_0",
+						format!("Authorization: Basic {}", credentials),
+					),
+				])
+			}
+			None => Ok(Vec::new()),
+		}
+	}
+
+	/// Fetches `source` and archives it as a tarball, ready to be sent to Docker as a build
+	/// context. Only the requested commit is fetched, without its history. Its `haas.toml` is
+	/// returned along with it, and only the build context it sets is archived. Projects without a
+	/// Dockerfile get a generated one, see [Builder].
+	pub async fn tarball_body_for_git_source(
+		&self,
+		source: &GitSource,
+		chan: Option<broadcast::Sender<ProvisionerEvent>>,
+	) -> Result<(Body, CommitInfo, Option<RepoConfig>)> {
+		use mktemp::Temp;
+		use tokio::fs;
+		use tokio_stream::StreamExt;
+		let git_ref = source.git_ref.as_deref().unwrap_or("HEAD");
+		if !is_valid_git_ref(git_ref) {
+			return Err(ProvisionerError::InvalidGitRef(git_ref.to_owned()));
+		}
+		let clone_dir = Temp::new_path();
+		fs::create_dir(&clone_dir).await?;
+		let dir: &Path = clone_dir.as_ref();
+		// Kept out of the clone directory, and removed as soon as the fetch is done
+		let secrets_dir = Temp::new_path();
+		let fetch_env = self
+			.git_credentials_env(source, secrets_dir.as_ref())
+			.await?;
+		run_git(dir, &["init", "--quiet"], &[], &chan).await?;
+		run_git(
+			dir,
+			&["remote", "add", "origin", &source.uri.to_string()],
+			&[],
+			&chan,
+		)
+		.await?;
+		// `--` makes sure the ref can't be taken for an option
+		run_git(
+			dir,
+			&["fetch", "--depth=1", "origin", "--", git_ref],
+			&fetch_env,
+			&chan,
+		)
+		.await?;
+		drop(secrets_dir);
+		let commit = fetched_commit(dir).await?;
+		if let Some(chan) = &chan {
+			chan.send(ProvisionerEvent::GitClone(format!(
+				"Building commit {} by {}",
+				commit.sha, commit.author
+			)))
+			.unwrap();
+		}
+		let config = fetched_repo_config(dir).await?;
+		if let (Some(chan), Some(_)) = (&chan, &config) {
+			chan.send(ProvisionerEvent::GitClone(format!(
+				"Using the settings of {}",
+				REPO_CONFIG_FILE
+			)))
+			.unwrap();
+		}
+		let context = config
+			.as_ref()
+			.map(RepoConfig::context_path)
+			.unwrap_or_default();
+		let mut tree = if context.is_empty() {
+			"FETCH_HEAD".to_owned()
+		} else {
+			format!("FETCH_HEAD:{}", context)
+		};
+		// A Dockerfile set in haas.toml was already checked to exist
+		let has_dockerfile = config
+			.as_ref()
+			.and_then(RepoConfig::dockerfile_path)
+			.is_some()
+			|| fetched_object_type(dir, &join_repo_path(&context, "Dockerfile"))
+				.await?
+				.as_deref() == Some("blob");
+		let builder = if has_dockerfile {
+			Builder::Dockerfile
+		} else {
+			buildpack::detect_builder(dir, &context).await?
+		};
+		if let Some(chan) = &chan {
+			chan.send(ProvisionerEvent::Builder(builder)).unwrap();
+		}
+		if let Some(dockerfile) = buildpack::generated_dockerfile(dir, &context, builder).await? {
+			tree = tree_with_file(dir, &tree, "Dockerfile", dockerfile.as_bytes()).await?;
+		}
+		let archive_path = Temp::new_path();
+		run_git(
+			dir,
+			&[
+				OsStr::new("archive"),
+				OsStr::new("-o"),
+				archive_path.as_os_str(),
+				OsStr::new(&tree),
+			],
+			&[],
+			&chan,
+		)
+		.await?;
+		let f = fs::File::open(archive_path).await?;
+		let stream = tokio_util::io::ReaderStream::new(f);
+		let mapped_stream = stream.map(|i| {
+			// Has to be coerced for Into<Body>
+			i.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync + 'static>)
+		});
+		// Type that Into<Body> expects
+		let s2: Box<
+			dyn Stream<
+					Item = std::result::Result<
+						hyper::body::Bytes,
+						Box<dyn std::error::Error + Send + Sync + 'static>,
+					>,
+				> + Send
+				+ 'static,
+		> = Box::new(mapped_stream);
+		Ok((s2.into(), commit, config))
+	}
+}
@@ -0,0 +1,283 @@
+use std::time::Duration;
+
+use bollard::models::{HealthConfig, HealthStatusEnum};
+use tokio::sync::broadcast;
+
+use crate::{Provisioner, ProvisionerDeployEvent, ProvisionerError, ProvisionerEvent, Result};
+
+/// Settings for the readiness probes run against a freshly started container
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HealthCheckConfig {
+	/// Path to probe over HTTP. When unset, a plain TCP connect is used instead.
+	pub http_path: Option<String>,
+	/// How long a single probe may take, in milliseconds
+	pub timeout_ms: u64,
+	/// Delay between two probes, in milliseconds
+	pub interval_ms: u64,
+	/// Number of probes to run before giving up on the container
+	pub retries: u32,
+}
+
+impl Default for HealthCheckConfig {
+	fn default() -> Self {
+		Self {
+			http_path: None,
+			timeout_ms: 2000,
+			interval_ms: 1000,
+			retries: 30,
+		}
+	}
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckMethod {
+	/// The image's own HEALTHCHECK, as reported by Docker
+	Docker,
+	Tcp,
+	Http {
+		path: String,
+	},
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HealthCheckAttempt {
+	pub attempt: u32,
+	pub retries: u32,
+	pub healthy: bool,
+	pub error: Option<String>,
+}
+
+/// Returns the image's HEALTHCHECK, unless it is missing or explicitly disabled
+pub(crate) fn effective_docker_healthcheck(hc: Option<HealthConfig>) -> Option<HealthConfig> {
+	hc.filter(|hc| match hc.test.as_deref() {
+		None | Some([]) => false,
+		Some([first, ..]) => first != "NONE",
+	})
+}
+
+/// Picks how to probe a container. `http_path` comes from the app's settings and takes
+/// precedence over the provisioner-wide one.
+pub(crate) fn probe_method(
+	docker_healthcheck: Option<&HealthConfig>,
+	http_path: Option<&str>,
+	config: &HealthCheckConfig,
+) -> HealthCheckMethod {
+	if docker_healthcheck.is_some() {
+		HealthCheckMethod::Docker
+	} else if let Some(path) = http_path.or(config.http_path.as_deref()) {
+		HealthCheckMethod::Http {
+			path: path.to_owned(),
+		}
+	} else {
+		HealthCheckMethod::Tcp
+	}
+}
+
+/// The command probing `ip:port` from within the Caddy container, using the tools of its
+/// busybox. `None` for [HealthCheckMethod::Docker], which needs no command.
+pub(crate) fn probe_command(
+	method: &HealthCheckMethod,
+	ip: &str,
+	port: u16,
+	timeout: Duration,
+) -> Option<Vec<String>> {
+	// Both tools only take whole seconds
+	let secs = ((timeout.as_millis() + 999) / 1000).max(1).to_string();
+	match method {
+		HealthCheckMethod::Docker => None,
+		HealthCheckMethod::Tcp => Some(vec![
+			"nc".to_owned(),
+			"-z".to_owned(),
+			"-w".to_owned(),
+			secs,
+			ip.to_owned(),
+			port.to_string(),
+		]),
+		HealthCheckMethod::Http { path } => Some(vec![
+			"wget".to_owned(),
+			"-q".to_owned(),
+			"-T".to_owned(),
+			secs,
+			"-O".to_owned(),
+			"/dev/null".to_owned(),
+			format!("http://{}:{}{}", ip, port, path),
+		]),
+	}
+}
+
+impl Provisioner {
+	/// Waits until the container is ready to receive traffic.
+	///
+	/// Images with a HEALTHCHECK are trusted to report their own health through Docker. Other
+	/// containers are probed on `ip:port`, either with an HTTP request to the configured path or
+	/// with a TCP connect. Probes run in the Caddy container: `ip` is on the app's network, which
+	/// Caddy is connected to but the API may not be. Fails as soon as the container exits.
+	pub(crate) async fn wait_until_healthy(
+		&self,
+		container_id: &str,
+		docker_healthcheck: Option<&HealthConfig>,
+		ip: &str,
+		port: u16,
+		http_path: Option<&str>,
+		chan: &Option<broadcast::Sender<ProvisionerEvent>>,
+	) -> Result<()> {
+		let config = &self.health_check;
+		let method = probe_method(docker_healthcheck, http_path, config);
+		let interval = Duration::from_millis(config.interval_ms);
+		let retries = match docker_healthcheck {
+			// Docker only reports a status once the image's own checks have run, so make sure we
+			// wait at least as long as they may take
+			Some(hc) => {
+				let nanos = hc.start_period.unwrap_or(0)
+					+ hc.interval.unwrap_or(30_000_000_000) * (hc.retries.unwrap_or(3) + 1);
+				let needed = (nanos.max(0) as u128 / interval.as_nanos().max(1)) as u32;
+				config.retries.max(needed)
+			}
+			None => config.retries,
+		};
+		deploy_event!(
+			chan,
+			ProvisionerDeployEvent::HealthCheckBegin {
+				method: method.clone()
+			}
+		);
+		for attempt in 1..=retries {
+			let outcome = self.probe_once(container_id, &method, ip, port).await;
+			deploy_event!(
+				chan,
+				ProvisionerDeployEvent::HealthCheckAttempt(HealthCheckAttempt {
+					attempt,
+					retries,
+					healthy: matches!(outcome, Ok(true)),
+					error: outcome.as_ref().err().map(ToString::to_string),
+				})
+			);
+			match outcome {
+				Ok(true) => {
+					deploy_event!(
+						chan,
+						ProvisionerDeployEvent::ContainerHealthy { attempts: attempt }
+					);
+					return Ok(());
+				}
+				// The container is gone, no point in retrying
+				Err(e @ ProvisionerError::Unhealthy(_)) => {
+					deploy_event!(
+						chan,
+						ProvisionerDeployEvent::ContainerUnhealthy { attempts: attempt }
+					);
+					return Err(e);
+				}
+				_ => {}
+			}
+			tokio::time::sleep(interval).await;
+		}
+		deploy_event!(
+			chan,
+			ProvisionerDeployEvent::ContainerUnhealthy { attempts: retries }
+		);
+		Err(ProvisionerError::Unhealthy(format!(
+			"container did not become healthy after {} attempts",
+			retries
+		)))
+	}
+
+	/// Runs a single probe. `Ok(false)` means "not ready yet", while [ProvisionerError::Unhealthy]
+	/// means the container will never become ready.
+	async fn probe_once(
+		&self,
+		container_id: &str,
+		method: &HealthCheckMethod,
+		ip: &str,
+		port: u16,
+	) -> Result<bool> {
+		let info = self.docker.inspect_container(container_id, None).await?;
+		let state = info.state.unwrap_or_default();
+		if state.running != Some(true) {
+			return Err(ProvisionerError::Unhealthy(format!(
+				"container exited with code {}",
+				state.exit_code.unwrap_or(-1)
+			)));
+		}
+		let timeout = Duration::from_millis(self.health_check.timeout_ms);
+		match method {
+			HealthCheckMethod::Docker => match state.health.and_then(|h| h.status) {
+				Some(HealthStatusEnum::HEALTHY) => Ok(true),
+				Some(HealthStatusEnum::UNHEALTHY) => Err(ProvisionerError::Unhealthy(
+					"Docker reported the container as unhealthy".to_owned(),
+				)),
+				_ => Ok(false),
+			},
+			HealthCheckMethod::Tcp | HealthCheckMethod::Http { .. } => {
+				let cmd = probe_command(method, ip, port, timeout).expect("Probe needs a command");
+				// The tools enforce the timeout themselves, this is in case Docker hangs
+				match tokio::time::timeout(timeout * 2, self.run_in_caddy(cmd)).await {
+					Ok(Ok((exit_code, _))) => Ok(exit_code == Some(0)),
+					Ok(Err(e)) => Err(e),
+					Err(_) => Ok(false),
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn healthcheck(test: &[&str]) -> HealthConfig {
+		HealthConfig {
+			test: Some(test.iter().map(|s| s.to_string()).collect()),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn trusts_the_image_healthcheck() {
+		let hc = effective_docker_healthcheck(Some(healthcheck(&["CMD", "true"])));
+		let method = probe_method(hc.as_ref(), Some("/health"), &Default::default());
+		assert!(matches!(method, HealthCheckMethod::Docker));
+		assert!(probe_command(&method, "172.18.0.2", 80, Duration::from_secs(2)).is_none());
+	}
+
+	#[test]
+	fn connects_over_tcp_without_a_healthcheck() {
+		for hc in [None, Some(healthcheck(&["NONE"])), Some(healthcheck(&[]))] {
+			let hc = effective_docker_healthcheck(hc);
+			let method = probe_method(hc.as_ref(), None, &Default::default());
+			assert!(matches!(method, HealthCheckMethod::Tcp));
+		}
+		let method = HealthCheckMethod::Tcp;
+		assert_eq!(
+			probe_command(&method, "172.18.0.2", 3000, Duration::from_millis(2000)).unwrap(),
+			["nc", "-z", "-w", "2", "172.18.0.2", "3000"]
+		);
+	}
+
+	#[test]
+	fn requests_the_http_path_without_a_healthcheck() {
+		let config = HealthCheckConfig {
+			http_path: Some("/healthz".to_owned()),
+			..Default::default()
+		};
+		// The app's own path wins over the provisioner-wide one
+		let method = probe_method(None, Some("/ready"), &config);
+		assert!(matches!(&method, HealthCheckMethod::Http { path } if path == "/ready"));
+		let method = probe_method(None, None, &config);
+		assert!(matches!(&method, HealthCheckMethod::Http { path } if path == "/healthz"));
+		assert_eq!(
+			probe_command(&method, "172.18.0.2", 80, Duration::from_millis(1500)).unwrap(),
+			[
+				"wget",
+				"-q",
+				"-T",
+				"2",
+				"-O",
+				"/dev/null",
+				"http://172.18.0.2:80/healthz"
+			]
+		);
+	}
+}
@@ -1,10 +1,8 @@
 use bollard::Docker;
 use caddy::CaddyClient;
 use diesel::{pg::PgConnection as PgConn, prelude::*};
-use hyper::{Body, Uri};
 use thiserror::Error;
 use tokio::sync::broadcast;
-use tokio_stream::Stream;
 
 #[derive(Error, Debug)]
 pub enum ProvisionerError {
@@ -22,8 +20,28 @@ pub enum ProvisionerError {
 	IO(#[from] std::io::Error),
 	#[error("Git clone failed")]
 	GitCloneFailed,
+	#[error("Invalid git ref {0}")]
+	InvalidGitRef(String),
+	#[error("Invalid haas.toml: {0}")]
+	InvalidRepoConfig(String),
+	#[error("Failed to generate deploy key")]
+	KeyGenerationFailed,
 	#[error("Error while deploying: {0}")]
 	DeployError(String),
+	#[error("Container is unhealthy: {0}")]
+	Unhealthy(String),
+	#[error("Build timed out")]
+	BuildTimeout,
+	#[error("Build was cancelled")]
+	Cancelled,
+	#[error("App is stopped")]
+	AppStopped,
+	#[error("Console session was started detached")]
+	ConsoleDetached,
+	#[error("Task failed: {0}")]
+	TaskError(String),
+	#[error("Can't build the project: {0}")]
+	BuildpackError(String),
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -31,36 +49,102 @@ pub enum ProvisionerError {
 #[serde(tag = "type", content = "event")]
 pub enum ProvisionerEvent {
 	GitClone(String),
+	/// How the image is built, reported before building it
+	Builder(Builder),
 	DockerBuild(bollard::models::BuildInfo),
 	Deploy(String),
+	DeployEvent(ProvisionerDeployEvent),
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "deploy")]
 pub enum ProvisionerDeployEvent {
-	DeployBegin { app_id: i32, image_id: String },
-	CreatingNetwork { network_name: String },
-	CreatedNetwork { network_id: String },
-	UsingExistingNetwork { network_id: String },
+	DeployBegin {
+		app_id: i32,
+		image_id: String,
+	},
+	CreatingNetwork {
+		network_name: String,
+	},
+	CreatedNetwork {
+		network_id: String,
+	},
+	UsingExistingNetwork {
+		network_id: String,
+	},
 	CreatingNewContainer,
-	CreatedNewContainer { container_id: String },
+	CreatedNewContainer {
+		container_id: String,
+	},
 	StartingNewContainer,
 	StartedNewContainer,
 	RetrievingContainerIP,
-	RetrievedContainerIP { container_ip: String },
+	RetrievedContainerIP {
+		container_ip: String,
+	},
 	AddingNewContainerAsUpstream,
-	CreatingNewRoute { route_id: String },
+	CreatingNewRoute {
+		route_id: String,
+	},
 	RemovingOldContainerAsUpstream,
-	StoppingOldContainer { container_id: String },
+	StoppingOldContainer {
+		container_id: String,
+	},
 	DeletingOldContainer,
-	DeployEnd { app_id: i32, app_slug: String },
-	Other { log: String },
+	HealthCheckBegin {
+		method: HealthCheckMethod,
+	},
+	HealthCheckAttempt(HealthCheckAttempt),
+	ContainerHealthy {
+		attempts: u32,
+	},
+	ContainerUnhealthy {
+		attempts: u32,
+	},
+	ReleaseCommandBegin {
+		command: String,
+	},
+	ReleaseCommandEnd {
+		exit_code: Option<i64>,
+		output: String,
+	},
+	RollbackBegin {
+		error: String,
+	},
+	RollbackStep {
+		step: String,
+	},
+	RollbackStepFailed {
+		step: String,
+		error: String,
+	},
+	RollbackEnd,
+	DeployEnd {
+		app_id: i32,
+		app_slug: String,
+	},
+	Other {
+		log: String,
+	},
 }
 
 pub use bollard;
+pub use buildpack::Builder;
 pub use caddy;
+pub use console::Console;
+pub use git::{
+	generate_deploy_key, is_valid_git_ref, CommitInfo, DeployKey, GitCredentials, GitSource,
+	GITHUB_KNOWN_HOST,
+};
+pub use health::{HealthCheckAttempt, HealthCheckConfig, HealthCheckMethod};
 pub use hyper;
+pub use logs::{LogLine, LogOptions, LogStream};
+pub use repo_config::{
+	RepoBuildConfig, RepoConfig, RepoDeployConfig, ResourceRequest, REPO_CONFIG_FILE,
+};
+pub use task::{TaskOptions, TaskOutcome};
+pub use tokio_util::sync::CancellationToken;
 
 pub type Result<T> = std::result::Result<T, ProvisionerError>;
 type DieselResult<T> = std::result::Result<T, diesel::result::Error>;
@@ -69,6 +153,74 @@ fn image_id_from_app_id(app_id: i32) -> String {
 	format!("haas-apps-{}", app_id)
 }
 
+/// Every build's image is tagged separately, so that earlier builds can be deployed again
+fn image_tag(app_id: i32, build_id: i32) -> String {
+	format!("{}:{}", image_id_from_app_id(app_id), build_id)
+}
+
+fn route_id_from_app_id(app_id: i32) -> String {
+	format!("haas_apps_{}_route", app_id)
+}
+
+fn network_name_from_app_id(app_id: i32) -> String {
+	format!("haas_apps_{}", app_id)
+}
+
+fn tls_policy_id_from_app_id(app_id: i32) -> String {
+	format!("haas_apps_{}_tls", app_id)
+}
+
+fn redirect_route_id_from_app_id(app_id: i32) -> String {
+	format!("haas_apps_{}_redirect", app_id)
+}
+
+/// Loads the verified domains of an app, which its Caddy route matches on
+async fn app_hosts(app_id: i32, runner: &mut impl DbRunner) -> Result<Vec<String>> {
+	use db_models::schema::domains::dsl::{app_id as domain_app_id, domain, domains, id, verified};
+	runner
+		.run(Box::new(move |c| {
+			domains
+				.filter(domain_app_id.eq(app_id).and(verified.eq(true)))
+				.order(id.asc())
+				.select(domain)
+				.load::<String>(c)
+		}))
+		.await
+		.map_err(Into::into)
+}
+
+/// Picks the first TCP port exposed by an image or container, or 80 if it exposes none
+fn exposed_port(config: Option<bollard::models::ContainerConfig>) -> u16 {
+	config
+		.and_then(|c| c.exposed_ports)
+		.and_then(|p| p.into_keys().find(|p| p.ends_with("tcp")))
+		.and_then(|p| p.split('/').next().map(|s| s.to_owned()))
+		.and_then(|p| p.parse().ok())
+		.unwrap_or(80u16)
+}
+
+/// Reads the IP of a container on the given network
+fn container_ip(
+	info: &bollard::models::ContainerInspectResponse,
+	network_name: &str,
+) -> Result<String> {
+	let network_settings = info.network_settings.as_ref().ok_or_else(|| {
+		ProvisionerError::DeployError("Failed to get network settings".to_owned())
+	})?;
+	let networks = network_settings
+		.networks
+		.as_ref()
+		.ok_or_else(|| ProvisionerError::DeployError("Failed to get networks".to_owned()))?;
+	let network = networks
+		.get(network_name)
+		.ok_or_else(|| ProvisionerError::DeployError("Failed to get network".to_owned()))?;
+	let ip = network
+		.ip_address
+		.as_ref()
+		.ok_or_else(|| ProvisionerError::DeployError("Failed to get IP address".to_owned()))?;
+	Ok(ip.split('/').next().unwrap().to_string())
+}
+
 #[async_trait::async_trait]
 pub trait DbRunner {
 	async fn run<U: Send + 'static>(
@@ -95,10 +247,148 @@ macro_rules! deploy_log {
 	}
 }
 
+macro_rules! deploy_event {
+	($chan:expr, $ev:expr) => {
+		if let Some(chan) = &$chan {
+			chan.send(ProvisionerEvent::DeployEvent($ev)).unwrap();
+		}
+	};
+}
+
+mod buildpack;
+mod certificate;
+mod console;
+mod git;
+mod health;
+mod logs;
+mod repo_config;
+mod task;
+
+/// Period that [ResourceLimits::cpu_quota] is relative to, in microseconds
+pub const CPU_PERIOD: i64 = 100_000;
+/// Smallest memory limit Docker accepts, in bytes
+pub const MIN_MEMORY_LIMIT: i64 = 6 * 1024 * 1024;
+/// Smallest CPU weight Docker accepts
+pub const MIN_CPU_SHARES: i64 = 2;
+/// Smallest CPU quota Docker accepts, in microseconds
+pub const MIN_CPU_QUOTA: i64 = 1000;
+
+/// Resource limits applied to an app's containers and image builds
+#[derive(Debug, Clone)]
+pub struct ResourceLimits {
+	/// Memory limit in bytes, swap included
+	pub memory: i64,
+	/// Relative CPU weight
+	pub cpu_shares: i64,
+	/// CPU time per [CPU_PERIOD], in microseconds
+	pub cpu_quota: i64,
+	pub pids: i64,
+	pub build_timeout: std::time::Duration,
+}
+
+impl Default for ResourceLimits {
+	fn default() -> Self {
+		Self {
+			memory: 512 * 1024 * 1024,
+			cpu_shares: 1024,
+			cpu_quota: CPU_PERIOD,
+			pids: 256,
+			build_timeout: std::time::Duration::from_secs(900),
+		}
+	}
+}
+
+impl From<&db_models::App> for ResourceLimits {
+	fn from(app: &db_models::App) -> Self {
+		Self {
+			memory: app.memory_limit,
+			cpu_shares: app.cpu_shares,
+			cpu_quota: app.cpu_quota,
+			pids: app.pids_limit,
+			build_timeout: std::time::Duration::from_secs(app.build_timeout.max(0) as u64),
+		}
+	}
+}
+
+/// Settings for a single deploy that are not stored on the app itself
+#[derive(Debug, Clone, Default)]
+pub struct DeployOptions {
+	/// Environment variables for the new container, in `KEY=value` form
+	pub env: Vec<String>,
+	/// Stops the deploy, as long as traffic has not started moving to the new container
+	pub cancel: CancellationToken,
+	/// Build doing the deploy, recorded in the app's releases
+	pub build_id: i32,
+	/// Build whose image is deployed, which is `build_id` itself unless rolling back
+	pub image_build_id: i32,
+	/// Run once in a container from the new image before it takes traffic, see
+	/// [Provisioner::run_release_command]
+	pub release_command: Option<String>,
+	/// Container port traffic is routed to, instead of the first one the image exposes
+	pub port: Option<u16>,
+	/// Path probed over HTTP to check the new container, instead of the configured one
+	pub health_check_path: Option<String>,
+	/// Lowers the app's resource limits for the new container
+	pub resources: ResourceRequest,
+}
+
+/// A completed deploy step, which has to be undone if a later step fails
+enum DeployStep {
+	CreatedNetwork {
+		network_id: String,
+	},
+	ConnectedCaddy {
+		network_id: String,
+	},
+	CreatedContainer {
+		container_id: String,
+	},
+	CreatedRoute {
+		route_id: String,
+	},
+	AddedUpstream {
+		route_id: String,
+		previous: Vec<caddy::types::Upstream>,
+	},
+	SyncedTls {
+		app_id: i32,
+	},
+}
+
+impl std::fmt::Display for DeployStep {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Self::CreatedNetwork { network_id } => write!(f, "delete network {}", network_id),
+			Self::ConnectedCaddy { network_id } => {
+				write!(f, "disconnect caddy from network {}", network_id)
+			}
+			Self::CreatedContainer { container_id } => {
+				write!(f, "stop and delete container {}", container_id)
+			}
+			Self::CreatedRoute { route_id } => write!(f, "delete route {}", route_id),
+			Self::AddedUpstream { route_id, .. } => {
+				write!(f, "restore previous upstreams of route {}", route_id)
+			}
+			Self::SyncedTls { app_id } => {
+				write!(f, "remove TLS policy and HTTPS redirect of app {}", app_id)
+			}
+		}
+	}
+}
+
 pub struct Provisioner {
 	docker: Docker,
 	caddy: CaddyClient,
 	caddy_name: String,
+	/// Name of the Caddy HTTP server app routes are added to
+	caddy_server: String,
+	/// Caddy's data directory, within its container
+	caddy_storage: String,
+	health_check: HealthCheckConfig,
+	/// Host keys trusted when cloning over SSH, in known_hosts format
+	known_hosts: Vec<String>,
+	/// Number of most recent images kept for each app, which can be rolled back to
+	kept_images: usize,
 }
 
 impl Provisioner {
@@ -110,178 +400,806 @@ impl Provisioner {
 			docker: Docker::connect_with_local_defaults()?,
 			caddy: CaddyClient::new(api_base)?,
 			caddy_name,
+			caddy_server: "srv0".to_owned(),
+			caddy_storage: "/data/caddy".to_owned(),
+			health_check: Default::default(),
+			known_hosts: vec![GITHUB_KNOWN_HOST.to_owned()],
+			kept_images: 5,
 		})
 	}
 
-	pub async fn tarball_body_for_git_uri(
-		uri: &Uri,
-		chan: Option<broadcast::Sender<ProvisionerEvent>>,
-	) -> Result<Body> {
-		use mktemp::Temp;
-		use std::process::Stdio;
-		use tokio::{fs, process::Command};
-		use tokio_stream::StreamExt;
-		let clone_dir = Temp::new_path();
-		let mut child = Command::new("git")
-			.args(&["clone", "--depth=1", &uri.to_string()])
-			.arg(clone_dir.as_os_str())
-			.stdout(Stdio::piped())
-			.stderr(Stdio::piped())
-			.spawn()?;
-		let stdout = child.stdout.take().unwrap();
-		let stderr = child.stderr.take().unwrap();
-		let chan2 = chan.clone();
-		tokio::spawn(async move {
-			let chan = match chan2 {
-				Some(c) => c,
-				None => return,
-			};
-			use tokio::io::{AsyncBufReadExt, BufReader};
-			let mut stdout_lines = BufReader::new(stdout).lines();
-			let mut stderr_lines = BufReader::new(stderr).lines();
-			loop {
-				let line = tokio::select! {
-					stdout_line = stdout_lines.next_line() => match stdout_line {
-						Ok(Some(s)) => s,
-						_ => continue,
-					},
-					stderr_line = stderr_lines.next_line() => match stderr_line {
-						Ok(Some(s)) => s,
-						_ => continue,
-					},
-					else => break,
-				};
-				chan.send(ProvisionerEvent::GitClone(line)).unwrap();
+	pub fn with_caddy_server(mut self, caddy_server: String) -> Self {
+		self.caddy_server = caddy_server;
+		self
+	}
+
+	pub fn with_caddy_storage(mut self, caddy_storage: String) -> Self {
+		self.caddy_storage = caddy_storage;
+		self
+	}
+
+	pub fn with_health_check(mut self, health_check: HealthCheckConfig) -> Self {
+		self.health_check = health_check;
+		self
+	}
+
+	pub fn with_known_hosts(mut self, known_hosts: Vec<String>) -> Self {
+		self.known_hosts = known_hosts;
+		self
+	}
+
+	pub fn with_kept_images(mut self, kept_images: usize) -> Self {
+		self.kept_images = kept_images;
+		self
+	}
+
+	/// Lists the builds of the app that still have an image
+	pub async fn app_image_builds(&self, app_id: i32) -> Result<Vec<i32>> {
+		let repository = image_id_from_app_id(app_id);
+		let images = self
+			.docker
+			.list_images(Some(bollard::image::ListImagesOptions::<String> {
+				filters: [("reference".to_owned(), vec![repository.clone()])].into(),
+				..Default::default()
+			}))
+			.await?;
+		let prefix = format!("{}:", repository);
+		Ok(images
+			.iter()
+			.flat_map(|image| image.repo_tags.iter())
+			.filter_map(|tag| tag.strip_prefix(&prefix)?.parse().ok())
+			.collect())
+	}
+
+	/// Removes the app's images except for the most recent ones and the live one. Images still
+	/// used by a container are kept as well.
+	async fn prune_app_images(&self, app_id: i32, live_build_id: i32) -> Result<()> {
+		let mut builds = self.app_image_builds(app_id).await?;
+		builds.sort_unstable_by(|a, b| b.cmp(a));
+		for build_id in builds.into_iter().skip(self.kept_images) {
+			if build_id == live_build_id {
+				continue;
 			}
-		});
-		let status = child.wait().await?;
-		if !status.success() {
-			return Err(ProvisionerError::GitCloneFailed);
-		}
-		let archive_path = Temp::new_path();
-		let mut child = Command::new("git")
-			.args(&["archive", "-o"])
-			.arg(archive_path.as_os_str())
-			.args(&["HEAD"])
-			.current_dir(&clone_dir)
-			.stdout(Stdio::piped())
-			.stderr(Stdio::piped())
-			.spawn()?;
-		let stdout = child.stdout.take().unwrap();
-		let stderr = child.stderr.take().unwrap();
-		let chan2 = chan.clone();
-		tokio::spawn(async move {
-			let chan = match chan2 {
-				Some(c) => c,
-				None => return,
+			let tag = image_tag(app_id, build_id);
+			if let Err(e) = self.docker.remove_image(&tag, None, None).await {
+				log::warn!("Failed to remove image {}: {}", tag, e);
+			}
+		}
+		Ok(())
+	}
+
+	/// Tags the images of apps last deployed before every build got its own tag, which are only
+	/// tagged `latest`, with the build their live release points to. Returns the number of images
+	/// tagged.
+	pub async fn tag_legacy_images(&self, runner: &mut impl DbRunner) -> Result<usize> {
+		use bollard::errors::Error as DockerError;
+		use db_models::schema::releases::dsl::{app_id, id, image_build_id, releases};
+		let live = runner
+			.run(Box::new(|c| {
+				releases
+					.distinct_on(app_id)
+					.order((app_id, id.desc()))
+					.select((app_id, image_build_id))
+					.load::<(i32, i32)>(c)
+			}))
+			.await?;
+		let mut tagged = 0;
+		for (app, build_id) in live {
+			match self.docker.inspect_image(&image_tag(app, build_id)).await {
+				Err(DockerError::DockerResponseNotFoundError { .. }) => {}
+				res => {
+					res?;
+					continue;
+				}
+			}
+			let legacy_image = image_id_from_app_id(app);
+			match self.docker.inspect_image(&legacy_image).await {
+				Err(DockerError::DockerResponseNotFoundError { .. }) => {
+					log::warn!("App {} has no image for its live build {}", app, build_id);
+					continue;
+				}
+				res => res?,
 			};
-			use tokio::io::{AsyncBufReadExt, BufReader};
-			let mut stdout_lines = BufReader::new(stdout).lines();
-			let mut stderr_lines = BufReader::new(stderr).lines();
-			loop {
-				let line = tokio::select! {
-					stdout_line = stdout_lines.next_line() => match stdout_line {
-						Ok(Some(s)) => s,
-						_ => continue,
-					},
-					stderr_line = stderr_lines.next_line() => match stderr_line {
-						Ok(Some(s)) => s,
-						_ => continue,
-					},
-					else => break,
-				};
-				chan.send(ProvisionerEvent::GitClone(line)).unwrap();
+			self.docker
+				.tag_image(
+					&legacy_image,
+					Some(bollard::image::TagImageOptions {
+						repo: legacy_image.clone(),
+						tag: build_id.to_string(),
+					}),
+				)
+				.await?;
+			// Only removes the tag, since the image is now tagged with the build as well
+			self.docker.remove_image(&legacy_image, None, None).await?;
+			log::info!("Tagged the image of app {} with build {}", app, build_id);
+			tagged += 1;
+		}
+		Ok(tagged)
+	}
+
+	/// Stops and removes a container, ignoring it if it was already stopped or removed
+	async fn remove_container(&self, container_id: &str) -> Result<()> {
+		use bollard::errors::Error as DockerError;
+		match self.docker.stop_container(container_id, None).await {
+			Err(
+				DockerError::DockerResponseNotFoundError { .. }
+				| DockerError::DockerResponseNotModifiedError { .. },
+			) => {
+				log::info!(
+					"Container {} did not exist / already stopped, ignoring",
+					container_id
+				);
 			}
-		});
-		let status = child.wait().await?;
-		if !status.success() {
-			return Err(ProvisionerError::GitCloneFailed);
-		}
-		let f = fs::File::open(archive_path).await?;
-		let stream = tokio_util::io::ReaderStream::new(f);
-		let mapped_stream = stream.map(|i| {
-			// Has to be coerced for Into<Body>
-			i.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync + 'static>)
-		});
-		// Type that Into<Body> expects
-		let s2: Box<
-			dyn Stream<
-					Item = std::result::Result<
-						hyper::body::Bytes,
-						Box<dyn std::error::Error + Send + Sync + 'static>,
-					>,
-				> + Send
-				+ 'static,
-		> = Box::new(mapped_stream);
-		Ok(s2.into())
+			e @ Err(_) => return e.map_err(Into::into),
+			_ => {}
+		}
+		match self.docker.remove_container(container_id, None).await {
+			Err(
+				DockerError::DockerResponseNotFoundError { .. }
+				| DockerError::DockerResponseNotModifiedError { .. },
+			) => {
+				log::info!("Container {} did not exist, ignoring", container_id);
+			}
+			e @ Err(_) => return e.map_err(Into::into),
+			_ => {}
+		}
+		Ok(())
 	}
 
+	/// Runs a command in the Caddy container, which is connected to the networks of all apps.
+	/// Returns its exit code, along with what it wrote to stdout.
+	pub(crate) async fn run_in_caddy(&self, cmd: Vec<String>) -> Result<(Option<i64>, Vec<u8>)> {
+		use bollard::{
+			container::LogOutput,
+			exec::{CreateExecOptions, StartExecResults},
+		};
+		use tokio_stream::StreamExt;
+		let exec = self
+			.docker
+			.create_exec(
+				&self.caddy_name,
+				CreateExecOptions {
+					attach_stdout: Some(true),
+					attach_stderr: Some(true),
+					cmd: Some(cmd),
+					..Default::default()
+				},
+			)
+			.await?;
+		let mut stdout = Vec::new();
+		if let StartExecResults::Attached { mut output, .. } =
+			self.docker.start_exec(&exec.id, None).await?
+		{
+			while let Some(output) = output.next().await {
+				if let LogOutput::StdOut { message } = output? {
+					stdout.extend_from_slice(&message);
+				}
+			}
+		}
+		let exit_code = self.docker.inspect_exec(&exec.id).await?.exit_code;
+		Ok((exit_code, stdout))
+	}
+
+	/// Clones and builds the app's image for the given build, giving up once
+	/// `limits.build_timeout` has passed or `cancel` is cancelled. Returns the commit that was
+	/// built, and the settings of its `haas.toml` if it has one.
+	#[allow(clippy::too_many_arguments)]
 	pub async fn build_image_from_github(
 		&self,
 		app_id: i32,
+		build_id: i32,
 		app_slug: &str,
-		uri: &Uri,
+		source: &GitSource,
+		limits: &ResourceLimits,
+		cancel: &CancellationToken,
 		chan: Option<broadcast::Sender<ProvisionerEvent>>,
-	) -> Result<()> {
+	) -> Result<(CommitInfo, Option<RepoConfig>)> {
 		use tokio_stream::StreamExt;
-		let body = Self::tarball_body_for_git_uri(uri, chan.clone()).await?;
-		let mut s = self.docker.build_image(
-			bollard::image::BuildImageOptions {
-				// FIXME: set limits
-				t: image_id_from_app_id(app_id),
-				// Deletes intermediate containers created when building,
-				// which is what we want
-				rm: true,
-				forcerm: true,
-				labels: [("app.hackclub.app_slug".to_owned(), app_slug.to_owned())].into(),
-				..Default::default()
-			},
-			None,
-			Some(body),
-		);
-		while let Some(ev) = s.next().await {
-			let ev = ev?;
-			if let Some(chan) = &chan {
-				chan.send(ProvisionerEvent::DockerBuild(ev)).unwrap();
+		let build = async {
+			let (body, commit, config) = self
+				.tarball_body_for_git_source(source, chan.clone())
+				.await?;
+			let mut s = self.docker.build_image(
+				bollard::image::BuildImageOptions {
+					t: image_tag(app_id, build_id),
+					dockerfile: config
+						.as_ref()
+						.and_then(RepoConfig::dockerfile_path)
+						.unwrap_or_default(),
+					buildargs: config
+						.as_ref()
+						.map(|c| c.build.args.clone().into_iter().collect())
+						.unwrap_or_default(),
+					// Deletes intermediate containers created when building,
+					// which is what we want
+					rm: true,
+					forcerm: true,
+					labels: [("app.hackclub.app_slug".to_owned(), app_slug.to_owned())].into(),
+					memory: Some(limits.memory as u64),
+					memswap: Some(limits.memory),
+					cpushares: Some(limits.cpu_shares as u64),
+					cpuperiod: Some(CPU_PERIOD as u64),
+					cpuquota: Some(limits.cpu_quota as u64),
+					..Default::default()
+				},
+				None,
+				Some(body),
+			);
+			while let Some(ev) = s.next().await {
+				let ev = ev?;
+				if let Some(chan) = &chan {
+					chan.send(ProvisionerEvent::DockerBuild(ev)).unwrap();
+				}
+			}
+			Ok::<_, ProvisionerError>((commit, config))
+		};
+		// Dropping the build kills git or closes the connection to Docker, which stops the build
+		tokio::select! {
+			res = tokio::time::timeout(limits.build_timeout, build) => {
+				res.map_err(|_| ProvisionerError::BuildTimeout)?
 			}
+			_ = cancel.cancelled() => Err(ProvisionerError::Cancelled),
 		}
-		Ok(())
 	}
 
-	/// NB: requires that the app's image has been built using [Self#build_image_from_github].
+	/// NB: requires that the image of `options.image_build_id` has been built using
+	/// [Self#build_image_from_github], and not pruned since.
 	/// !!! This does not do any privilege checks
+	///
+	/// If any step fails, the steps completed so far are undone so that the app is left in its
+	/// last good state, with the old container still serving traffic. Once the deploy succeeds,
+	/// it is recorded as the app's latest release, and old images are pruned.
 	pub async fn deploy_app(
 		&self,
 		app_id: i32,
 		runner: &mut impl DbRunner,
+		options: &DeployOptions,
 		chan: Option<broadcast::Sender<ProvisionerEvent>>,
+	) -> Result<()> {
+		let mut steps = Vec::new();
+		let res = self
+			.try_deploy_app(app_id, runner, options, &chan, &mut steps)
+			.await;
+		if let Err(e) = &res {
+			deploy_event!(
+				chan,
+				ProvisionerDeployEvent::RollbackBegin {
+					error: e.to_string()
+				}
+			);
+			self.rollback_deploy(steps, &chan).await;
+		} else if let Err(e) = self.prune_app_images(app_id, options.image_build_id).await {
+			log::warn!("Failed to prune images of app {}: {}", app_id, e);
+		}
+		res
+	}
+
+	/// Undoes the given deploy steps in reverse order. Failures are reported but do not stop the
+	/// rollback, since every step left in place is one more leaked resource.
+	async fn rollback_deploy(
+		&self,
+		steps: Vec<DeployStep>,
+		chan: &Option<broadcast::Sender<ProvisionerEvent>>,
+	) {
+		use caddy::types::Upstream;
+		for step in steps.into_iter().rev() {
+			deploy_event!(
+				chan,
+				ProvisionerDeployEvent::RollbackStep {
+					step: step.to_string()
+				}
+			);
+			let res: Result<()> = match &step {
+				DeployStep::CreatedNetwork { network_id } => self
+					.docker
+					.remove_network(network_id)
+					.await
+					.map_err(Into::into),
+				DeployStep::ConnectedCaddy { network_id } => self
+					.docker
+					.disconnect_network(
+						network_id,
+						bollard::network::DisconnectNetworkOptions::<&str> {
+							container: &self.caddy_name,
+							force: true,
+						},
+					)
+					.await
+					.map_err(Into::into),
+				DeployStep::CreatedContainer { container_id } => {
+					self.remove_container(container_id).await
+				}
+				DeployStep::CreatedRoute { route_id } => self
+					.caddy
+					.config_by_id(route_id)
+					.delete()
+					.await
+					.map(|_| ())
+					.map_err(Into::into),
+				DeployStep::AddedUpstream { route_id, previous } => self
+					.caddy
+					.config_by_id(route_id)
+					.appending_path(&["handle", "0", "upstreams"])
+					.patch::<Vec<Upstream>>(previous)
+					.await
+					.map(|_| ())
+					.map_err(Into::into),
+				DeployStep::SyncedTls { app_id } => {
+					self.remove_app_tls(*app_id).await;
+					Ok(())
+				}
+			};
+			if let Err(e) = res {
+				log::error!("Failed to {} during rollback: {}", step, e);
+				deploy_event!(
+					chan,
+					ProvisionerDeployEvent::RollbackStepFailed {
+						step: step.to_string(),
+						error: e.to_string(),
+					}
+				);
+			}
+		}
+		deploy_event!(chan, ProvisionerDeployEvent::RollbackEnd);
+	}
+
+	/// Points the app's Caddy route at its current verified domains. Should be called whenever
+	/// one of its domains is verified, unverified or deleted.
+	///
+	/// Apps that were never deployed have no route yet, which is then created with the right
+	/// domains on their first deploy.
+	/// !!! This does not do any privilege checks
+	pub async fn update_app_hosts(&self, app_id: i32, runner: &mut impl DbRunner) -> Result<()> {
+		if self.deployed_container(app_id, runner).await?.is_none() {
+			return Ok(());
+		}
+		let hosts = app_hosts(app_id, runner).await?;
+		self.caddy
+			.config_by_id(&route_id_from_app_id(app_id))
+			.appending_path(&["match", "0", "host"])
+			.patch(&hosts)
+			.await?;
+		self.sync_app_tls(app_id, &hosts).await
+	}
+
+	/// Makes Caddy obtain certificates for the app's domains, and redirect them from HTTP to HTTPS
+	async fn sync_app_tls(&self, app_id: i32, hosts: &[String]) -> Result<()> {
+		let policy_id = tls_policy_id_from_app_id(app_id);
+		let redirect_id = redirect_route_id_from_app_id(app_id);
+		// A policy without subjects would apply to every domain, and a route without hosts would
+		// redirect every request
+		if hosts.is_empty() {
+			self.remove_app_tls(app_id).await;
+			return Ok(());
+		}
+		let policy = serde_json::json!({
+			"@id": policy_id,
+			"subjects": hosts,
+		});
+		// FIXME: more exact error checking
+		if self
+			.caddy
+			.config_by_id(&policy_id)
+			.patch(&policy)
+			.await
+			.is_err()
+		{
+			// Policies are tried in order, ours has to come before any catch-all policy
+			self.put_creating_parents(&["apps", "tls", "automation", "policies", "0"], policy)
+				.await?;
+		}
+		let redirect = serde_json::json!({
+			"@id": redirect_id,
+			"match": [{ "host": hosts, "protocol": "http" }],
+			"handle": [{
+				"handler": "static_response",
+				"status_code": 308,
+				"headers": { "Location": ["https://{http.request.host}{http.request.uri}"] },
+			}],
+			"terminal": true,
+		});
+		if self
+			.caddy
+			.config_by_id(&redirect_id)
+			.patch(&redirect)
+			.await
+			.is_err()
+		{
+			// Has to come before the app's route, which would serve the request over HTTP
+			self.put_creating_parents(
+				&[
+					"apps",
+					"http",
+					"servers",
+					self.caddy_server.as_str(),
+					"routes",
+					"0",
+				],
+				redirect,
+			)
+			.await?;
+		}
+		Ok(())
+	}
+
+	/// Removes the app's TLS policy and HTTPS redirect, skipping the ones that don't exist
+	async fn remove_app_tls(&self, app_id: i32) {
+		for id in [
+			tls_policy_id_from_app_id(app_id),
+			redirect_route_id_from_app_id(app_id),
+		] {
+			// FIXME: more exact error checking
+			if let Err(e) = self.caddy.config_by_id(&id).delete().await {
+				log::info!("Could not delete {}, ignoring: {}", id, e);
+			}
+		}
+	}
+
+	/// Inserts `value` at `path` in Caddy's config, where numeric segments are array indices.
+	/// Caddy refuses to insert into objects or arrays that don't exist, e.g. the TLS automation
+	/// policies of a fresh install, so these are created along the way.
+	async fn put_creating_parents(
+		&self,
+		path: &[&str],
+		mut value: serde_json::Value,
+	) -> Result<()> {
+		let mut first_error = None;
+		for len in (1..=path.len()).rev() {
+			match self.caddy.config_by_path(&path[..len]).put(&value).await {
+				Ok(_) => return Ok(()),
+				// Either the parent is missing, or this already exists and the error from the
+				// full path is the one worth reporting
+				Err(e) => {
+					first_error.get_or_insert(e);
+				}
+			}
+			value = match path[len - 1].parse::<usize>() {
+				Ok(_) => serde_json::json!([value]),
+				Err(_) => serde_json::json!({ path[len - 1]: value }),
+			};
+		}
+		Err(first_error.expect("Path is not empty").into())
+	}
+
+	/// Removes everything deploys created for the app: its Caddy routes and TLS policy, its
+	/// container, the containers of its runs, its network and images. Then deletes the app, along
+	/// with its domains, builds and runs.
+	///
+	/// Resources that are already gone are skipped, so that a teardown that failed halfway can
+	/// simply be retried.
+	/// !!! This does not do any privilege checks
+	pub async fn destroy_app(&self, app_id: i32, runner: &mut impl DbRunner) -> Result<()> {
+		use bollard::errors::Error as DockerError;
+		use db_models::schema::apps::dsl::apps;
+		use db_models::App;
+		let app = runner
+			.run(Box::new(move |c| {
+				apps.find(app_id).first::<App>(c).optional()
+			}))
+			.await?;
+		// 1. Stop routing traffic to the app
+		for id in [
+			redirect_route_id_from_app_id(app_id),
+			route_id_from_app_id(app_id),
+			tls_policy_id_from_app_id(app_id),
+		] {
+			// FIXME: more exact error checking
+			if let Err(e) = self.caddy.config_by_id(&id).delete().await {
+				log::info!("Could not delete {}, ignoring: {}", id, e);
+			}
+		}
+		// 2. Remove the container
+		if let Some(container_id) = app.as_ref().and_then(|a| a.container_id.as_deref()) {
+			self.remove_container(container_id).await?;
+		}
+		let network = app
+			.as_ref()
+			.and_then(|a| a.network_id.clone())
+			.unwrap_or_else(|| network_name_from_app_id(app_id));
+		// 3. Fail the app's queued runs, and remove the containers of the runs still going, which
+		// would keep the network from being removed
+		runner
+			.run(Box::new(move |c| {
+				use db_models::schema::runs::dsl::{
+					app_id as run_app_id, ended_at, logs, runs, status,
+				};
+				use db_models::RunStatus;
+
+				diesel::update(runs)
+					.filter(run_app_id.eq(app_id).and(status.eq(RunStatus::Queued)))
+					.set((
+						status.eq(RunStatus::Failed),
+						ended_at.eq(diesel::dsl::now),
+						logs.eq("The app was deleted"),
+					))
+					.execute(c)
+			}))
+			.await?;
+		let tasks = self
+			.docker
+			.list_containers(Some(bollard::container::ListContainersOptions::<&str> {
+				all: true,
+				filters: [
+					("label", vec!["app.hackclub.task=true"]),
+					("network", vec![network.as_str()]),
+				]
+				.into(),
+				..Default::default()
+			}))
+			.await?;
+		for task_id in tasks.into_iter().filter_map(|task| task.id) {
+			match self
+				.docker
+				.remove_container(
+					&task_id,
+					Some(bollard::container::RemoveContainerOptions {
+						force: true,
+						..Default::default()
+					}),
+				)
+				.await
+			{
+				Err(DockerError::DockerResponseNotFoundError { .. }) => {
+					log::info!("Task container {} did not exist, ignoring", task_id);
+				}
+				Err(e) => return Err(e.into()),
+				Ok(_) => {}
+			}
+		}
+		// 4. Disconnect caddy from the network and remove it
+		match self.docker.inspect_network::<&str>(&network, None).await {
+			Ok(info) => {
+				let caddy_connected = info
+					.containers
+					.unwrap_or_default()
+					.values()
+					.any(|c| c.name.as_deref() == Some(self.caddy_name.as_str()));
+				if caddy_connected {
+					self.docker
+						.disconnect_network(
+							&network,
+							bollard::network::DisconnectNetworkOptions::<&str> {
+								container: &self.caddy_name,
+								force: true,
+							},
+						)
+						.await?;
+				}
+				self.docker.remove_network(&network).await?;
+			}
+			Err(DockerError::DockerResponseNotFoundError { .. }) => {
+				log::info!("Network {} did not exist, ignoring", network);
+			}
+			Err(e) => return Err(e.into()),
+		}
+		// 5. Remove the images of every build
+		let mut tags = self
+			.app_image_builds(app_id)
+			.await?
+			.into_iter()
+			.map(|build_id| image_tag(app_id, build_id))
+			.collect::<Vec<_>>();
+		// Images used to be tagged `latest`, before every build got its own tag
+		tags.push(image_id_from_app_id(app_id));
+		for tag in tags {
+			match self
+				.docker
+				.remove_image(
+					&tag,
+					Some(bollard::image::RemoveImageOptions {
+						force: true,
+						..Default::default()
+					}),
+					None,
+				)
+				.await
+			{
+				Err(DockerError::DockerResponseNotFoundError { .. }) => {
+					log::info!("Image {} did not exist, ignoring", tag);
+				}
+				Err(e) => return Err(e.into()),
+				Ok(_) => {}
+			}
+		}
+		// 6. Delete the app, which cascades to its domains, builds and runs
+		if let Some(app) = app {
+			runner
+				.run(Box::new(move |c| diesel::delete(&app).execute(c)))
+				.await?;
+		}
+		Ok(())
+	}
+
+	/// Stops the app's container, and makes its route answer with a static "disabled" response
+	/// until it is started again. Deploys fail while the app is stopped.
+	/// !!! This does not do any privilege checks
+	pub async fn stop_app(&self, app_id: i32, runner: &mut impl DbRunner) -> Result<()> {
+		use bollard::errors::Error as DockerError;
+		use db_models::schema::apps::dsl::{apps, enabled};
+		let container_id = self.deployed_container(app_id, runner).await?;
+		// Recorded first so that no deploy starts serving traffic again in the meantime
+		runner
+			.run(Box::new(move |c| {
+				diesel::update(apps.find(app_id))
+					.set(enabled.eq(false))
+					.execute(c)
+			}))
+			.await?;
+		let container_id = match container_id {
+			Some(container_id) => container_id,
+			// Never deployed, so there is nothing to stop
+			None => return Ok(()),
+		};
+		self.caddy
+			.config_by_id(&route_id_from_app_id(app_id))
+			.appending_path(&["handle"])
+			.patch(&serde_json::json!([{
+				"handler": "static_response",
+				"status_code": 503,
+				"headers": { "Content-Type": ["text/plain; charset=utf-8"] },
+				"body": "This app is disabled",
+			}]))
+			.await?;
+		match self.docker.stop_container(&container_id, None).await {
+			Err(
+				DockerError::DockerResponseNotFoundError { .. }
+				| DockerError::DockerResponseNotModifiedError { .. },
+			) => {
+				log::info!(
+					"Container {} did not exist / already stopped, ignoring",
+					container_id
+				);
+			}
+			e @ Err(_) => return e.map_err(Into::into),
+			_ => {}
+		}
+		Ok(())
+	}
+
+	/// Starts the app's container again, and routes traffic back to it once it is healthy
+	/// !!! This does not do any privilege checks
+	pub async fn start_app(&self, app_id: i32, runner: &mut impl DbRunner) -> Result<()> {
+		use bollard::errors::Error as DockerError;
+		use db_models::schema::apps::dsl::{apps, enabled};
+		if let Some(container_id) = self.deployed_container(app_id, runner).await? {
+			match self
+				.docker
+				.start_container::<&str>(&container_id, None)
+				.await
+			{
+				Err(DockerError::DockerResponseNotModifiedError { .. }) => {
+					log::info!("Container {} already started, ignoring", container_id);
+				}
+				e @ Err(_) => return e.map_err(Into::into),
+				_ => {}
+			}
+			self.route_to_container(app_id, &container_id).await?;
+		}
+		runner
+			.run(Box::new(move |c| {
+				diesel::update(apps.find(app_id))
+					.set(enabled.eq(true))
+					.execute(c)
+			}))
+			.await?;
+		Ok(())
+	}
+
+	/// Restarts the app's container, and routes traffic back to it once it is healthy
+	/// !!! This does not do any privilege checks
+	pub async fn restart_app(&self, app_id: i32, runner: &mut impl DbRunner) -> Result<()> {
+		let container_id = match self.deployed_container(app_id, runner).await? {
+			Some(container_id) => container_id,
+			None => return Ok(()),
+		};
+		self.docker.restart_container(&container_id, None).await?;
+		self.route_to_container(app_id, &container_id).await
+	}
+
+	async fn deployed_container(
+		&self,
+		app_id: i32,
+		runner: &mut impl DbRunner,
+	) -> Result<Option<String>> {
+		use db_models::schema::apps::dsl::{apps, container_id};
+		runner
+			.run(Box::new(move |c| {
+				apps.find(app_id)
+					.select(container_id)
+					.first::<Option<String>>(c)
+			}))
+			.await
+			.map_err(Into::into)
+	}
+
+	/// Waits for a started container to be healthy, then points the app's route at it. Its IP
+	/// may have changed since it was last started.
+	async fn route_to_container(&self, app_id: i32, container_id: &str) -> Result<()> {
+		let info = self.docker.inspect_container(container_id, None).await?;
+		let ip = container_ip(&info, &network_name_from_app_id(app_id))?;
+		let docker_healthcheck = health::effective_docker_healthcheck(
+			info.config.as_ref().and_then(|c| c.healthcheck.clone()),
+		);
+		// Set by the deploy that created the container, containers from older deploys lack them
+		let label = |key: &str| {
+			info.config
+				.as_ref()
+				.and_then(|c| c.labels.as_ref())
+				.and_then(|labels| labels.get(key).cloned())
+		};
+		let http_path = label("app.hackclub.health_check_path");
+		let port = match label("app.hackclub.port").and_then(|p| p.parse().ok()) {
+			Some(port) => port,
+			None => exposed_port(info.config),
+		};
+		self.wait_until_healthy(
+			container_id,
+			docker_healthcheck.as_ref(),
+			&ip,
+			port,
+			http_path.as_deref(),
+			&None,
+		)
+		.await?;
+		self.caddy
+			.config_by_id(&route_id_from_app_id(app_id))
+			.appending_path(&["handle"])
+			.patch(&serde_json::json!([{
+				"handler": "reverse_proxy",
+				"upstreams": [{ "dial": format!("{}:{}", ip, port) }],
+			}]))
+			.await?;
+		Ok(())
+	}
+
+	async fn try_deploy_app(
+		&self,
+		app_id: i32,
+		runner: &mut impl DbRunner,
+		options: &DeployOptions,
+		chan: &Option<broadcast::Sender<ProvisionerEvent>>,
+		steps: &mut Vec<DeployStep>,
 	) -> Result<()> {
 		use db_models::schema::apps::dsl::{self as apps_dsl, apps, id};
 		use db_models::App;
-		let image_id = image_id_from_app_id(app_id);
+		let image_id = image_tag(app_id, options.image_build_id);
 		deploy_log!(
 			chan,
 			"Deploy begin with app id {}, image id {}",
 			app_id,
 			image_id
 		);
-		// 0. Inspect image for exposed port
-		let image_metadata = self.docker.inspect_image(&image_id).await?;
-		let port = image_metadata
-			.config
-			.and_then(|c| c.exposed_ports)
-			.and_then(|p| p.into_iter().map(|(p, _)| p).find(|p| p.ends_with("tcp")))
-			.and_then(|p| p.split('/').next().map(|s| s.to_owned()))
-			.and_then(|p| p.parse().ok())
-			.unwrap_or(80u16);
+		// 0. Inspect image for exposed port and health check
+		let image_metadata = match self.docker.inspect_image(&image_id).await {
+			Err(bollard::errors::Error::DockerResponseNotFoundError { .. }) => {
+				return Err(ProvisionerError::DeployError(format!(
+					"The image of build {} was pruned, it has to be built again",
+					options.image_build_id
+				)));
+			}
+			res => res?,
+		};
+		let docker_healthcheck = health::effective_docker_healthcheck(
+			image_metadata
+				.config
+				.as_ref()
+				.and_then(|c| c.healthcheck.clone()),
+		);
+		let port = options
+			.port
+			.unwrap_or_else(|| exposed_port(image_metadata.config));
 		deploy_log!(chan, "Will route traffic to container port {}", port);
 		let mut app = runner
 			.run(Box::new(move |c| {
 				apps.filter(id.eq(app_id)).first::<App>(c)
 			}))
 			.await?;
+		// Stopped apps are only deployed again once started, see [Self::start_app]
+		if !app.enabled {
+			return Err(ProvisionerError::AppStopped);
+		}
 		// 1. Get or create the app network
-		let network_name = format!("haas_apps_{}", app_id);
+		let network_name = network_name_from_app_id(app_id);
 		if app.network_id.is_none() {
 			deploy_log!(chan, "Creating network with name {}", network_name);
 			app.network_id = Some(
@@ -295,6 +1213,9 @@ impl Provisioner {
 					.id
 					.expect("Network create returns id"),
 			);
+			steps.push(DeployStep::CreatedNetwork {
+				network_id: app.network_id.clone().unwrap(),
+			});
 			deploy_log!(
 				chan,
 				"Created network with id {}",
@@ -310,6 +1231,9 @@ impl Provisioner {
 					},
 				)
 				.await?;
+			steps.push(DeployStep::ConnectedCaddy {
+				network_id: app.network_id.clone().unwrap(),
+			});
 			deploy_log!(chan, "Added caddy to the new network");
 		} else {
 			deploy_log!(
@@ -318,8 +1242,23 @@ impl Provisioner {
 				app.network_id.as_ref().unwrap()
 			);
 		}
+		// 1.b. Run the release command, before anything serves the new image
+		if let Some(command) = &options.release_command {
+			self.run_release_command(&app, command, options, chan)
+				.await?;
+		}
 		// Safe to unwrap: checked None case above
 		let network_id = app.network_id.as_deref().unwrap();
+		let limits = ResourceLimits::from(&app).requesting(&options.resources);
+		// Remembered for when the container is started again, see [Self::route_to_container]
+		let port_label = port.to_string();
+		let mut labels = vec![
+			("app.hackclub.app_slug", app.slug.as_str()),
+			("app.hackclub.port", port_label.as_str()),
+		];
+		if let Some(path) = &options.health_check_path {
+			labels.push(("app.hackclub.health_check_path", path.as_str()));
+		}
 		deploy_log!(chan, "Creating new container");
 		// 2. Create the new container, attached to the new network
 		let new_container = self
@@ -332,16 +1271,26 @@ impl Provisioner {
 				None,
 				bollard::container::Config {
 					image: Some(image_id.as_str()),
+					env: Some(options.env.iter().map(String::as_str).collect()),
 					host_config: Some(bollard::service::HostConfig {
 						network_mode: Some(network_id.to_owned()),
+						memory: Some(limits.memory),
+						memory_swap: Some(limits.memory),
+						cpu_shares: Some(limits.cpu_shares),
+						cpu_period: Some(CPU_PERIOD),
+						cpu_quota: Some(limits.cpu_quota),
+						pids_limit: Some(limits.pids),
 						..Default::default()
 					}),
-					labels: Some([("app.hackclub.app_slug", app.slug.as_str())].into()),
+					labels: Some(labels.into_iter().collect()),
 					..Default::default()
 				},
 			)
 			.await?
 			.id;
+		steps.push(DeployStep::CreatedContainer {
+			container_id: new_container.clone(),
+		});
 		deploy_log!(chan, "Created new container with id {}", new_container);
 		deploy_log!(chan, "Starting new container");
 		// 2.b. Start the new container
@@ -352,48 +1301,64 @@ impl Provisioner {
 		deploy_log!(chan, "Retrieving container IP...");
 		// 2.c. Get the container's IP
 		let new_container_info = self.docker.inspect_container(&new_container, None).await?;
-		let new_container_ip = {
-			let network_settings =
-				new_container_info
-					.network_settings
-					.as_ref()
-					.ok_or_else(|| {
-						ProvisionerError::DeployError("Failed to get network settings".to_owned())
-					})?;
-			let networks = network_settings.networks.as_ref().ok_or_else(|| {
-				ProvisionerError::DeployError("Failed to get networks".to_owned())
-			})?;
-			let network = networks
-				.get(&network_name)
-				.ok_or_else(|| ProvisionerError::DeployError("Failed to get network".to_owned()))?;
-			let ip = network.ip_address.as_ref().ok_or_else(|| {
-				ProvisionerError::DeployError("Failed to get IP address".to_owned())
-			})?;
-			Ok::<_, ProvisionerError>(ip.split('/').next().unwrap().to_string())
-		}?;
+		let new_container_ip = container_ip(&new_container_info, &network_name)?;
 		deploy_log!(chan, "Retrieved container IP: {}", new_container_ip);
+		// 2.d. Wait for the new container to be ready before sending traffic its way
+		tokio::select! {
+			res = self.wait_until_healthy(
+				&new_container,
+				docker_healthcheck.as_ref(),
+				&new_container_ip,
+				port,
+				options.health_check_path.as_deref(),
+				chan,
+			) => res?,
+			_ = options.cancel.cancelled() => return Err(ProvisionerError::Cancelled),
+		}
+		// Last chance to back out, the new container starts receiving traffic below
+		if options.cancel.is_cancelled() {
+			return Err(ProvisionerError::Cancelled);
+		}
 		deploy_log!(chan, "Adding new container as upstream...");
 		let upstream = format!("{}:{}", new_container_ip, port);
 		//let upstreams_id = format!("haas_apps_{}_upstreams", app_slug);
-		let route_id = format!("haas_apps_{}_route", app_id);
+		let route_id = route_id_from_app_id(app_id);
+		let hosts = app_hosts(app_id, runner).await?;
 		// 3. Update the Caddy upstreams to include the new container upstream
-		match {
-			let handle = self.caddy.config_by_id(&route_id).appending_path(&[
-				"handle",
-				"0",
-				"upstreams",
-				"0",
-			]);
-			handle
-				.put(&caddy::types::Upstream {
-					dial: Some(upstream.clone()),
-					..Default::default()
-				})
-				.await
-		} {
-			// Config updated, good
-			Ok(_) => {
+		match self
+			.caddy
+			.config_by_id(&route_id)
+			.appending_path(&["handle", "0", "upstreams"])
+			.get::<Vec<caddy::types::Upstream>>()
+			.await
+		{
+			// Route exists, remember its upstreams in case we have to roll back
+			Ok(previous) => {
+				let handle = self.caddy.config_by_id(&route_id).appending_path(&[
+					"handle",
+					"0",
+					"upstreams",
+					"0",
+				]);
+				handle
+					.put(&caddy::types::Upstream {
+						dial: Some(upstream.clone()),
+						..Default::default()
+					})
+					.await?;
+				steps.push(DeployStep::AddedUpstream {
+					route_id: route_id.clone(),
+					previous,
+				});
 				deploy_log!(chan, "Updated upstreams");
+				// Domains verified since the route was created are normally added already, this
+				// catches up on any update that failed
+				self.caddy
+					.config_by_id(&route_id)
+					.appending_path(&["match", "0", "host"])
+					.patch(&hosts)
+					.await?;
+				deploy_log!(chan, "Updated hosts: {}", hosts.join(", "));
 			}
 			// FIXME: more exact error checking
 			// Upstream does not seem to exist... add a new one
@@ -405,16 +1370,18 @@ impl Provisioner {
 					e,
 					route_id
 				);
-				// FIXME: using a static path to routes
-				let handle = self
-					.caddy
-					.config_by_path(&["apps", "http", "servers", "srv0", "routes"]);
+				let handle = self.caddy.config_by_path(&[
+					"apps",
+					"http",
+					"servers",
+					self.caddy_server.as_str(),
+					"routes",
+				]);
 				let route = Identified {
 					id: Some(route_id.clone()),
 					value: Route {
 						r#match: Some(vec![HttpMatchersMap {
-							// FIXME: domains support
-							host: Some(vec![format!("{}.hackclub.app", &app.slug)]),
+							host: Some(hosts.clone()),
 							..Default::default()
 						}
 						.into()]),
@@ -435,14 +1402,24 @@ impl Provisioner {
 				};
 				//info!("Route: {:?}", route);
 				handle.post(&route).await?;
+				steps.push(DeployStep::CreatedRoute {
+					route_id: route_id.clone(),
+				});
 				deploy_log!(chan, "Created new route with upstreams");
 			}
 		}
-		deploy_log!(chan, "FIXME: Waiting 5 seconds for container to be up");
-		// 4. Wait 5 seconds or something idk for the new container to be up
-		// FIXME
-		tokio::time::sleep(tokio::time::Duration::from_millis(5000)).await;
-		// 5. Replace the upstreams list with only the new container ID
+		// 3.b. Serve the app's domains over HTTPS. When the route already existed, this only
+		// brings the TLS policy and redirect in line with the app's verified domains, the same way
+		// update_app_hosts does, so only the ones of a new route have to be undone.
+		if steps
+			.iter()
+			.any(|step| matches!(step, DeployStep::CreatedRoute { .. }))
+		{
+			steps.push(DeployStep::SyncedTls { app_id });
+		}
+		self.sync_app_tls(app_id, &hosts).await?;
+		deploy_log!(chan, "Updated TLS policy and HTTPS redirect");
+		// 4. Replace the upstreams list with only the new container ID
 		deploy_log!(chan, "Removing old container as upstream...");
 		let handle =
 			self.caddy
@@ -455,53 +1432,63 @@ impl Provisioner {
 			}])
 			.await?;
 		deploy_log!(chan, "Updated upstreams");
-		if let Some(old_container_id) = &app.container_id {
-			use bollard::errors::Error as DockerError;
-			deploy_log!(chan, "Stopping old container with id {}", old_container_id);
-			match self.docker.stop_container(old_container_id, None).await {
-				Err(
-					DockerError::DockerResponseNotFoundError { .. }
-					| DockerError::DockerResponseNotModifiedError { .. },
-				) => {
-					log::info!("Old container did not exist / already stopped, ignoring");
-				}
-				e @ Err(_) => return e.map_err(Into::into),
-				_ => {}
-			}
-			deploy_log!(chan, "Deleting old container");
-			match self.docker.remove_container(old_container_id, None).await {
-				Err(
-					DockerError::DockerResponseNotFoundError { .. }
-					| DockerError::DockerResponseNotModifiedError { .. },
-				) => {
-					log::info!("Old container did not exist, ignoring");
-				}
-				e @ Err(_) => return e.map_err(Into::into),
-				_ => {}
-			}
-		} else {
-			deploy_log!(chan, "No old container found to remove");
-		}
-		// 6. Done! Save the new container id / network id back to the db and return success
+		// 5. Save the new container id / network id back to the db, along with the release
 		deploy_log!(
 			chan,
 			"Updating database with new container and network ID..."
 		);
-		app.container_id = Some(new_container);
+		let old_container_id = app.container_id.replace(new_container);
+		let release = db_models::NewRelease {
+			app_id,
+			build_id: options.build_id,
+			image_build_id: options.image_build_id,
+		};
 		runner
 			.run(Box::new({
 				let app = app.clone();
 				move |c| {
-					diesel::update(&app)
-						.set((
-							apps_dsl::container_id.eq(&app.container_id),
-							apps_dsl::network_id.eq(&app.network_id),
-						))
-						.execute(c)
+					c.transaction(|| {
+						diesel::update(&app)
+							.set((
+								apps_dsl::container_id.eq(&app.container_id),
+								apps_dsl::network_id.eq(&app.network_id),
+							))
+							.execute(c)?;
+						diesel::insert_into(db_models::schema::releases::table)
+							.values(&release)
+							.execute(c)
+					})
 				}
 			}))
 			.await?;
 		deploy_log!(chan, "Updated database with new container and network ID");
+		// The new container is now live and recorded, so there is nothing left to roll back
+		steps.clear();
+		// 6. Done! Clean up the old container and return success. The deploy is live and recorded
+		// by now, so a container left behind doesn't fail it.
+		if let Some(old_container_id) = &old_container_id {
+			deploy_log!(
+				chan,
+				"Stopping and deleting old container with id {}",
+				old_container_id
+			);
+			if let Err(e) = self.remove_container(old_container_id).await {
+				log::warn!(
+					"Failed to remove old container {} of app {}: {}",
+					old_container_id,
+					app_id,
+					e
+				);
+				deploy_log!(
+					chan,
+					"Failed to remove old container {}, leaving it behind: {}",
+					old_container_id,
+					e
+				);
+			}
+		} else {
+			deploy_log!(chan, "No old container found to remove");
+		}
 		deploy_log!(
 			chan,
 			"Successful deploy for app with id {}, slug {}",
@@ -0,0 +1,80 @@
+use bollard::container::{LogOutput, LogsOptions};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{Provisioner, Result};
+
+/// Which lines of a container's logs to read
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+	/// Number of lines to return from the end of the logs, all of them if unset
+	pub tail: Option<u64>,
+	/// Only return lines logged after this UNIX timestamp
+	pub since: Option<i64>,
+	/// Keep streaming new lines until the container stops
+	pub follow: bool,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStream {
+	Stdout,
+	Stderr,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LogLine {
+	pub stream: LogStream,
+	/// When the line was logged, as an RFC 3339 timestamp with nanoseconds
+	pub ts: String,
+	pub message: String,
+}
+
+impl LogLine {
+	/// Parses a line logged with `timestamps` enabled, which prefixes it with its timestamp
+	fn parse(stream: LogStream, line: &[u8]) -> Self {
+		let line = String::from_utf8_lossy(line);
+		let line = line.trim_end_matches(&['\r', '\n'][..]);
+		let (ts, message) = line.split_once(' ').unwrap_or((line, ""));
+		Self {
+			stream,
+			ts: ts.to_owned(),
+			message: message.to_owned(),
+		}
+	}
+}
+
+impl Provisioner {
+	/// Reads a container's logs, with stdout and stderr told apart.
+	/// !!! This does not do any privilege checks
+	pub fn container_logs(
+		&self,
+		container_id: &str,
+		options: &LogOptions,
+	) -> impl Stream<Item = Result<LogLine>> {
+		self.docker
+			.logs(
+				container_id,
+				Some(LogsOptions::<String> {
+					follow: options.follow,
+					stdout: true,
+					stderr: true,
+					since: options.since.unwrap_or(0),
+					timestamps: true,
+					tail: options
+						.tail
+						.map_or_else(|| "all".to_owned(), |tail| tail.to_string()),
+					..Default::default()
+				}),
+			)
+			.filter_map(|output| match output {
+				Ok(LogOutput::StdOut { message }) | Ok(LogOutput::Console { message }) => {
+					Some(Ok(LogLine::parse(LogStream::Stdout, &message)))
+				}
+				Ok(LogOutput::StdErr { message }) => {
+					Some(Ok(LogLine::parse(LogStream::Stderr, &message)))
+				}
+				Ok(LogOutput::StdIn { .. }) => None,
+				Err(e) => Some(Err(e.into())),
+			})
+	}
+}
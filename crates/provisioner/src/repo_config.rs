@@ -0,0 +1,355 @@
+use std::collections::BTreeMap;
+use std::path::{Component, Path};
+
+use crate::{DeployOptions, ResourceLimits};
+
+/// Name of the file read from the root of an app's repository
+pub const REPO_CONFIG_FILE: &str = "haas.toml";
+
+/// Build and deploy settings that an app's repository can set in its `haas.toml`. Every setting
+/// is optional:
+///
+/// ```toml
+/// [build]
+/// context = "web"
+/// dockerfile = "docker/Dockerfile.prod"
+/// args = { NODE_ENV = "production" }
+///
+/// [deploy]
+/// port = 3000
+/// health_check_path = "/healthz"
+/// release_command = "npm run migrate"
+///
+/// [env]
+/// LOG_LEVEL = "info"
+///
+/// [resources]
+/// memory = 268435456
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoConfig {
+	pub build: RepoBuildConfig,
+	pub deploy: RepoDeployConfig,
+	/// Defaults for environment variables, which the app's own variables override
+	pub env: BTreeMap<String, String>,
+	pub resources: ResourceRequest,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoBuildConfig {
+	/// Subdirectory of the repository sent to Docker as the build context
+	pub context: Option<String>,
+	/// Path of the Dockerfile, relative to the build context
+	pub dockerfile: Option<String>,
+	/// Passed to Docker as build arguments
+	pub args: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoDeployConfig {
+	/// Container port traffic is routed to, instead of the first one the image exposes
+	pub port: Option<u16>,
+	/// Path probed over HTTP to check new containers, instead of the server's default
+	pub health_check_path: Option<String>,
+	/// Used unless the app sets its own, see [DeployOptions::release_command]
+	pub release_command: Option<String>,
+}
+
+/// Resources an app asks for. They can only lower the limits set on the app, never raise them.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceRequest {
+	/// Memory in bytes, swap included
+	pub memory: Option<i64>,
+	pub cpu_shares: Option<i64>,
+	/// CPU time per [crate::CPU_PERIOD], in microseconds
+	pub cpu_quota: Option<i64>,
+	pub pids: Option<i64>,
+}
+
+/// Normalizes a path relative to the root of the repository, or returns `None` if it could point
+/// outside of it. The root itself is the empty string.
+fn normalize_repo_path(path: &str) -> Option<String> {
+	Path::new(path)
+		.components()
+		.filter(|c| *c != Component::CurDir)
+		.map(|c| match c {
+			Component::Normal(s) => s.to_str(),
+			_ => None,
+		})
+		.collect::<Option<Vec<_>>>()
+		.map(|parts| parts.join("/"))
+}
+
+/// Joins a normalized path in the repository with a name relative to it
+pub(crate) fn join_repo_path(dir: &str, name: &str) -> String {
+	if dir.is_empty() {
+		name.to_owned()
+	} else {
+		format!("{}/{}", dir, name)
+	}
+}
+
+/// Same rules as the keys of the app's environment variables
+fn is_valid_env_key(key: &str) -> bool {
+	let mut chars = key.chars();
+	matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl RepoConfig {
+	/// Parses and validates the contents of a `haas.toml`. Every problem found is reported at
+	/// once.
+	pub fn parse(s: &str) -> Result<Self, String> {
+		let config = toml::from_str::<Self>(s).map_err(|e| e.to_string())?;
+		let problems = config.problems();
+		if problems.is_empty() {
+			Ok(config)
+		} else {
+			Err(problems.join("; "))
+		}
+	}
+
+	fn problems(&self) -> Vec<String> {
+		let mut problems = Vec::new();
+
+		if let Some(context) = &self.build.context {
+			if normalize_repo_path(context).is_none() {
+				problems.push("build.context must be a path inside the repository".to_owned());
+			}
+		}
+		if let Some(dockerfile) = &self.build.dockerfile {
+			if normalize_repo_path(dockerfile).map_or(true, |p| p.is_empty()) {
+				problems.push(
+					"build.dockerfile must be the path of a file inside the build context"
+						.to_owned(),
+				);
+			}
+		}
+		for key in self.build.args.keys().filter(|k| !is_valid_env_key(k)) {
+			problems.push(format!("build.args: invalid argument name {:?}", key));
+		}
+
+		if self.deploy.port == Some(0) {
+			problems.push("deploy.port must be between 1 and 65535".to_owned());
+		}
+		if let Some(path) = &self.deploy.health_check_path {
+			if !path.starts_with('/') || path.chars().any(|c| c.is_whitespace() || c.is_control()) {
+				problems.push(
+					"deploy.health_check_path must start with / and contain no whitespace"
+						.to_owned(),
+				);
+			}
+		}
+		if let Some(command) = &self.deploy.release_command {
+			if command.trim().is_empty() {
+				problems.push("deploy.release_command must not be empty".to_owned());
+			}
+		}
+
+		for key in self.env.keys().filter(|k| !is_valid_env_key(k)) {
+			problems.push(format!("env: invalid variable name {:?}", key));
+		}
+
+		let resources = &self.resources;
+		for (name, value) in [
+			("memory", resources.memory),
+			("cpu_shares", resources.cpu_shares),
+			("cpu_quota", resources.cpu_quota),
+			("pids", resources.pids),
+		] {
+			if matches!(value, Some(v) if v <= 0) {
+				problems.push(format!("resources.{} must be positive", name));
+			}
+		}
+
+		problems
+	}
+
+	/// Path of the build context in the repository, the empty string for its root
+	pub fn context_path(&self) -> String {
+		self.build
+			.context
+			.as_deref()
+			.and_then(normalize_repo_path)
+			.unwrap_or_default()
+	}
+
+	/// Path of the Dockerfile relative to the build context, if it is not the default one
+	pub fn dockerfile_path(&self) -> Option<String> {
+		self.build
+			.dockerfile
+			.as_deref()
+			.and_then(normalize_repo_path)
+	}
+}
+
+impl ResourceLimits {
+	/// Lowers the limits to the requested resources
+	pub fn requesting(self, request: &ResourceRequest) -> Self {
+		let lower = |limit: i64, requested: Option<i64>| requested.map_or(limit, |r| r.min(limit));
+		Self {
+			memory: lower(self.memory, request.memory),
+			cpu_shares: lower(self.cpu_shares, request.cpu_shares),
+			cpu_quota: lower(self.cpu_quota, request.cpu_quota),
+			pids: lower(self.pids, request.pids),
+			..self
+		}
+	}
+}
+
+impl DeployOptions {
+	/// Applies the settings of the deployed build's `haas.toml`. The app's own environment
+	/// variables and release command take precedence over the file's.
+	pub fn with_repo_config(mut self, config: RepoConfig) -> Self {
+		for (key, value) in config.env {
+			let prefix = format!("{}=", key);
+			if !self.env.iter().any(|var| var.starts_with(&prefix)) {
+				self.env.push(format!("{}={}", key, value));
+			}
+		}
+		self.release_command = self.release_command.or(config.deploy.release_command);
+		self.port = config.deploy.port;
+		self.health_check_path = config.deploy.health_check_path;
+		self.resources = config.resources;
+		self
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn normalizes_paths_inside_the_repo() {
+		assert_eq!(normalize_repo_path("web").as_deref(), Some("web"));
+		assert_eq!(normalize_repo_path("./web/").as_deref(), Some("web"));
+		assert_eq!(normalize_repo_path("web/./api").as_deref(), Some("web/api"));
+		assert_eq!(normalize_repo_path("web//api").as_deref(), Some("web/api"));
+	}
+
+	#[test]
+	fn normalizes_the_root_to_the_empty_string() {
+		assert_eq!(normalize_repo_path("").as_deref(), Some(""));
+		assert_eq!(normalize_repo_path(".").as_deref(), Some(""));
+		assert_eq!(normalize_repo_path("./").as_deref(), Some(""));
+	}
+
+	#[test]
+	fn rejects_paths_that_may_leave_the_repo() {
+		assert_eq!(normalize_repo_path(".."), None);
+		assert_eq!(normalize_repo_path("../web"), None);
+		// Even when it would stay inside
+		assert_eq!(normalize_repo_path("web/../api"), None);
+		assert_eq!(normalize_repo_path("/etc"), None);
+		assert_eq!(normalize_repo_path("/"), None);
+	}
+
+	#[test]
+	fn parses_every_setting() {
+		let config = RepoConfig::parse(
+			r#"
+			[build]
+			context = "./web"
+			dockerfile = "docker/Dockerfile.prod"
+			args = { NODE_ENV = "production" }
+
+			[deploy]
+			port = 3000
+			health_check_path = "/healthz"
+			release_command = "npm run migrate"
+
+			[env]
+			LOG_LEVEL = "info"
+
+			[resources]
+			memory = 268435456
+			"#,
+		)
+		.unwrap();
+
+		assert_eq!(config.context_path(), "web");
+		assert_eq!(
+			config.dockerfile_path().as_deref(),
+			Some("docker/Dockerfile.prod")
+		);
+		assert_eq!(config.build.args["NODE_ENV"], "production");
+		assert_eq!(config.deploy.port, Some(3000));
+		assert_eq!(config.deploy.health_check_path.as_deref(), Some("/healthz"));
+		assert_eq!(
+			config.deploy.release_command.as_deref(),
+			Some("npm run migrate")
+		);
+		assert_eq!(config.env["LOG_LEVEL"], "info");
+		assert_eq!(config.resources.memory, Some(268435456));
+		assert_eq!(config.resources.cpu_shares, None);
+	}
+
+	#[test]
+	fn parses_an_empty_file_to_the_defaults() {
+		let config = RepoConfig::parse("").unwrap();
+		assert_eq!(config, RepoConfig::default());
+		assert_eq!(config.context_path(), "");
+		assert_eq!(config.dockerfile_path(), None);
+	}
+
+	#[test]
+	fn rejects_unknown_settings() {
+		assert!(RepoConfig::parse("[bulid]\ncontext = \"web\"").is_err());
+		assert!(RepoConfig::parse("[deploy]\nhealthcheck = \"/\"").is_err());
+	}
+
+	#[test]
+	fn rejects_out_of_range_port() {
+		assert!(RepoConfig::parse("[deploy]\nport = 70000").is_err());
+		assert!(RepoConfig::parse("[deploy]\nport = 0").is_err());
+	}
+
+	#[test]
+	fn rejects_paths_outside_the_repo() {
+		assert!(RepoConfig::parse("[build]\ncontext = \"../web\"").is_err());
+		assert!(RepoConfig::parse("[build]\ncontext = \"/etc\"").is_err());
+		assert!(RepoConfig::parse("[build]\ndockerfile = \"../Dockerfile\"").is_err());
+		// The Dockerfile must be a file, not the context itself
+		assert!(RepoConfig::parse("[build]\ndockerfile = \".\"").is_err());
+		assert!(RepoConfig::parse("[build]\ndockerfile = \"\"").is_err());
+	}
+
+	#[test]
+	fn reports_every_problem_at_once() {
+		let err = RepoConfig::parse(
+			r#"
+			[build]
+			context = "../x"
+			args = { "1A" = "b" }
+
+			[deploy]
+			health_check_path = "x y"
+			release_command = " "
+
+			[env]
+			"a-b" = "c"
+
+			[resources]
+			memory = -1
+			pids = 0
+			"#,
+		)
+		.unwrap_err();
+
+		for problem in [
+			"build.context",
+			"build.args",
+			"deploy.health_check_path",
+			"deploy.release_command",
+			"env:",
+			"resources.memory",
+			"resources.pids",
+		] {
+			assert!(err.contains(problem), "{:?} not in {:?}", problem, err);
+		}
+	}
+}
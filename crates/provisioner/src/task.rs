@@ -0,0 +1,226 @@
+use std::time::Duration;
+
+use bollard::container::{LogOutput, LogsOptions, RemoveContainerOptions};
+use tokio::sync::broadcast;
+use tokio_stream::StreamExt;
+
+use crate::{
+	image_tag, DeployOptions, Provisioner, ProvisionerDeployEvent, ProvisionerError,
+	ProvisionerEvent, ResourceLimits, Result, CPU_PERIOD,
+};
+
+/// Only the end of longer logs is kept
+const MAX_TASK_LOG_BYTES: usize = 64 * 1024;
+
+/// A command to run once in a short-lived container
+#[derive(Debug, Clone, Default)]
+pub struct TaskOptions {
+	/// Run with `sh -c`, in place of the image's entrypoint
+	pub command: String,
+	/// Environment variables for the container, in `KEY=value` form
+	pub env: Vec<String>,
+	/// Build whose image the command runs in
+	pub image_build_id: i32,
+	/// The container is killed once this has passed
+	pub timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskOutcome {
+	/// Unset if the command was killed for timing out
+	pub exit_code: Option<i64>,
+	pub timed_out: bool,
+	/// Output of the command, stdout and stderr interleaved
+	pub logs: String,
+}
+
+impl Provisioner {
+	/// Runs a command in a new container from one of the app's images, with the app's resource
+	/// limits and on its network, so that it can reach the same services. The container is removed
+	/// once the command exits.
+	/// !!! This does not do any privilege checks
+	pub async fn run_task(
+		&self,
+		app: &db_models::App,
+		options: &TaskOptions,
+	) -> Result<TaskOutcome> {
+		let image_id = image_tag(app.id, options.image_build_id);
+		if let Err(bollard::errors::Error::DockerResponseNotFoundError { .. }) =
+			self.docker.inspect_image(&image_id).await
+		{
+			return Err(ProvisionerError::TaskError(format!(
+				"The image of build {} was pruned, the app has to be deployed again",
+				options.image_build_id
+			)));
+		}
+		let network_id = app.network_id.as_deref().ok_or_else(|| {
+			ProvisionerError::TaskError("The app has not been deployed".to_owned())
+		})?;
+
+		let limits = ResourceLimits::from(app);
+		let container_id = self
+			.docker
+			.create_container::<&str, _>(
+				None,
+				bollard::container::Config {
+					image: Some(image_id.as_str()),
+					entrypoint: Some(vec!["/bin/sh", "-c"]),
+					cmd: Some(vec![options.command.as_str()]),
+					env: Some(options.env.iter().map(String::as_str).collect()),
+					host_config: Some(bollard::service::HostConfig {
+						network_mode: Some(network_id.to_owned()),
+						memory: Some(limits.memory),
+						memory_swap: Some(limits.memory),
+						cpu_shares: Some(limits.cpu_shares),
+						cpu_period: Some(CPU_PERIOD),
+						cpu_quota: Some(limits.cpu_quota),
+						pids_limit: Some(limits.pids),
+						..Default::default()
+					}),
+					labels: Some(
+						[
+							("app.hackclub.app_slug", app.slug.as_str()),
+							("app.hackclub.task", "true"),
+						]
+						.into(),
+					),
+					..Default::default()
+				},
+			)
+			.await?
+			.id;
+
+		let outcome = self.wait_for_task(&container_id, options.timeout).await;
+		if let Err(e) = self
+			.docker
+			.remove_container(
+				&container_id,
+				Some(RemoveContainerOptions {
+					force: true,
+					..Default::default()
+				}),
+			)
+			.await
+		{
+			log::warn!("Failed to remove task container {}: {}", container_id, e);
+		}
+		outcome
+	}
+
+	/// Runs the release command of a deploy, e.g. to migrate the app's database, in a container
+	/// from the new image. It gets the environment and network of the new container, and the app's
+	/// build timeout. Fails the deploy unless it exits with 0, while the old container still serves.
+	/// Cancellations wait for the command, which is not interrupted halfway.
+	pub(crate) async fn run_release_command(
+		&self,
+		app: &db_models::App,
+		command: &str,
+		options: &DeployOptions,
+		chan: &Option<broadcast::Sender<ProvisionerEvent>>,
+	) -> Result<()> {
+		if options.cancel.is_cancelled() {
+			return Err(ProvisionerError::Cancelled);
+		}
+		deploy_event!(
+			chan,
+			ProvisionerDeployEvent::ReleaseCommandBegin {
+				command: command.to_owned(),
+			}
+		);
+		let outcome = self
+			.run_task(
+				app,
+				&TaskOptions {
+					command: command.to_owned(),
+					env: options.env.clone(),
+					image_build_id: options.image_build_id,
+					timeout: ResourceLimits::from(app).build_timeout,
+				},
+			)
+			.await?;
+		deploy_event!(
+			chan,
+			ProvisionerDeployEvent::ReleaseCommandEnd {
+				exit_code: outcome.exit_code,
+				output: outcome.logs,
+			}
+		);
+
+		match outcome.exit_code {
+			Some(0) => Ok(()),
+			Some(code) => Err(ProvisionerError::DeployError(format!(
+				"Release command exited with code {}",
+				code
+			))),
+			None => Err(ProvisionerError::DeployError(
+				"Release command timed out".to_owned(),
+			)),
+		}
+	}
+
+	async fn wait_for_task(&self, container_id: &str, timeout: Duration) -> Result<TaskOutcome> {
+		self.docker
+			.start_container::<&str>(container_id, None)
+			.await?;
+
+		let timed_out = tokio::time::timeout(
+			timeout,
+			self.docker
+				.wait_container::<&str>(container_id, None)
+				.next(),
+		)
+		.await
+		.is_err();
+		// Some versions of bollard report non-zero exit codes as wait errors, so the exit code is
+		// read back instead
+		let state = self
+			.docker
+			.inspect_container(container_id, None)
+			.await?
+			.state
+			.unwrap_or_default();
+		let exit_code = if state.running == Some(true) {
+			self.docker
+				.kill_container::<&str>(container_id, None)
+				.await?;
+			None
+		} else {
+			state.exit_code
+		};
+
+		Ok(TaskOutcome {
+			exit_code,
+			timed_out,
+			logs: self.task_logs(container_id).await?,
+		})
+	}
+
+	/// Reads the end of the logs of a stopped container
+	async fn task_logs(&self, container_id: &str) -> Result<String> {
+		let mut s = self.docker.logs(
+			container_id,
+			Some(LogsOptions::<String> {
+				stdout: true,
+				stderr: true,
+				..Default::default()
+			}),
+		);
+		let mut logs = Vec::new();
+		while let Some(output) = s.next().await {
+			match output? {
+				LogOutput::StdOut { message }
+				| LogOutput::StdErr { message }
+				| LogOutput::Console { message } => logs.extend_from_slice(&message),
+				LogOutput::StdIn { .. } => {}
+			}
+			if logs.len() > 2 * MAX_TASK_LOG_BYTES {
+				logs.drain(..logs.len() - MAX_TASK_LOG_BYTES);
+			}
+		}
+		if logs.len() > MAX_TASK_LOG_BYTES {
+			logs.drain(..logs.len() - MAX_TASK_LOG_BYTES);
+		}
+
+		Ok(String::from_utf8_lossy(&logs).into_owned())
+	}
+}
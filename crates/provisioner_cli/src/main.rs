@@ -14,14 +14,57 @@ struct Opts {
 #[derive(Parser)]
 enum Subcommand {
 	Build {
+		/// Build the image is tagged for
+		#[clap(long)]
+		build_id: i32,
 		#[clap(long)]
 		github_uri: String,
 		#[clap(long)]
 		slug: String,
+		/// Branch, tag or commit SHA to build, defaults to the default branch
+		#[clap(long = "ref")]
+		git_ref: Option<String>,
+		/// Private SSH key to clone an ssh:// repository with, takes precedence over --https-token
+		#[clap(long)]
+		ssh_key: Option<std::path::PathBuf>,
+		/// Token to clone an https:// repository with
+		#[clap(long)]
+		https_token: Option<String>,
 	},
 	Deploy {
 		#[clap(long)]
 		database_url: String,
+		/// Environment variable for the new container, in KEY=value form
+		#[clap(long)]
+		env: Vec<String>,
+		/// Build recorded as deploying the release, which has to exist in the database
+		#[clap(long)]
+		build_id: i32,
+		/// Build whose image is deployed, defaults to --build-id
+		#[clap(long)]
+		image_build_id: Option<i32>,
+		/// Command run in a container from the image before it takes traffic
+		#[clap(long)]
+		release_command: Option<String>,
+	},
+	/// Removes the app's route, container, network and image, then deletes it
+	Destroy {
+		#[clap(long)]
+		database_url: String,
+	},
+	/// Stops the app's container and serves a "disabled" page instead
+	Stop {
+		#[clap(long)]
+		database_url: String,
+	},
+	/// Starts a stopped app's container and routes traffic back to it
+	Start {
+		#[clap(long)]
+		database_url: String,
+	},
+	Restart {
+		#[clap(long)]
+		database_url: String,
 	},
 }
 
@@ -36,12 +79,38 @@ async fn main() -> anyhow::Result<()> {
 	)?;
 	match &opts.subcmd {
 		Subcommand::Build {
-			github_uri, slug, ..
+			build_id,
+			github_uri,
+			slug,
+			git_ref,
+			ssh_key,
+			https_token,
 		} => {
 			let (tx, mut rx) = broadcast::channel(10);
-			let parsed_uri = github_uri.parse()?;
-			let mut build_finish =
-				Box::pin(provisioner.build_image_from_github(opts.id, slug, &parsed_uri, Some(tx)));
+			let source = provisioner::GitSource {
+				uri: github_uri.parse()?,
+				git_ref: git_ref.clone(),
+				credentials: match (ssh_key, https_token) {
+					(Some(path), _) => Some(provisioner::GitCredentials::SshKey(
+						std::fs::read_to_string(path)?,
+					)),
+					(_, Some(token)) => {
+						Some(provisioner::GitCredentials::HttpsToken(token.clone()))
+					}
+					_ => None,
+				},
+			};
+			let limits = Default::default();
+			let cancel = Default::default();
+			let mut build_finish = Box::pin(provisioner.build_image_from_github(
+				opts.id,
+				*build_id,
+				slug,
+				&source,
+				&limits,
+				&cancel,
+				Some(tx),
+			));
 			loop {
 				tokio::select! {
 					ev = rx.recv() => {
@@ -53,10 +122,24 @@ async fn main() -> anyhow::Result<()> {
 			}
 			log::info!("Build done!");
 		}
-		Subcommand::Deploy { database_url, .. } => {
+		Subcommand::Deploy {
+			database_url,
+			env,
+			build_id,
+			image_build_id,
+			release_command,
+		} => {
 			let mut conn = diesel::PgConnection::establish(database_url)?;
 			let (tx, mut rx) = broadcast::channel(10);
-			let mut build_finish = Box::pin(provisioner.deploy_app(opts.id, &mut conn, Some(tx)));
+			let options = provisioner::DeployOptions {
+				env: env.clone(),
+				build_id: *build_id,
+				image_build_id: image_build_id.unwrap_or(*build_id),
+				release_command: release_command.clone(),
+				..Default::default()
+			};
+			let mut build_finish =
+				Box::pin(provisioner.deploy_app(opts.id, &mut conn, &options, Some(tx)));
 			loop {
 				tokio::select! {
 					ev = rx.recv() => {
@@ -68,6 +151,26 @@ async fn main() -> anyhow::Result<()> {
 			}
 			log::info!("Deploy done!");
 		}
+		Subcommand::Destroy { database_url } => {
+			let mut conn = diesel::PgConnection::establish(database_url)?;
+			provisioner.destroy_app(opts.id, &mut conn).await?;
+			log::info!("Destroy done!");
+		}
+		Subcommand::Stop { database_url } => {
+			let mut conn = diesel::PgConnection::establish(database_url)?;
+			provisioner.stop_app(opts.id, &mut conn).await?;
+			log::info!("Stop done!");
+		}
+		Subcommand::Start { database_url } => {
+			let mut conn = diesel::PgConnection::establish(database_url)?;
+			provisioner.start_app(opts.id, &mut conn).await?;
+			log::info!("Start done!");
+		}
+		Subcommand::Restart { database_url } => {
+			let mut conn = diesel::PgConnection::establish(database_url)?;
+			provisioner.restart_app(opts.id, &mut conn).await?;
+			log::info!("Restart done!");
+		}
 	}
 	Ok(())
 }
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE app_env_vars (
+	id SERIAL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+	app_id INTEGER NOT NULL REFERENCES apps (id) ON DELETE CASCADE,
+	key TEXT NOT NULL,
+	encrypted_value TEXT NOT NULL,
+	secret BOOLEAN NOT NULL DEFAULT false,
+	UNIQUE (app_id, key)
+)
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE apps
+DROP COLUMN memory_limit,
+DROP COLUMN cpu_shares,
+DROP COLUMN cpu_quota,
+DROP COLUMN pids_limit,
+DROP COLUMN build_timeout
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE apps
+ADD COLUMN memory_limit BIGINT NOT NULL DEFAULT 536870912,
+ADD COLUMN cpu_shares BIGINT NOT NULL DEFAULT 1024,
+ADD COLUMN cpu_quota BIGINT NOT NULL DEFAULT 100000,
+ADD COLUMN pids_limit BIGINT NOT NULL DEFAULT 256,
+ADD COLUMN build_timeout INTEGER NOT NULL DEFAULT 900
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX builds_pending_idx;
+ALTER TABLE builds
+DROP COLUMN status,
+DROP COLUMN git_repository,
+DROP COLUMN heartbeat_at
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+ALTER TABLE builds
+ADD COLUMN status TEXT NOT NULL DEFAULT 'queued' CHECK (
+	status IN ('queued', 'running', 'succeeded', 'failed', 'cancelled')
+),
+ADD COLUMN git_repository TEXT,
+ADD COLUMN heartbeat_at TIMESTAMP;
+-- Builds that never ended were orphaned by an API restart
+UPDATE builds
+SET status = CASE
+		WHEN ended_at IS NULL
+		OR EXISTS (
+			SELECT 1
+			FROM unnest(events) AS e
+			WHERE e::jsonb ? 'Err'
+		) THEN 'failed'
+		ELSE 'succeeded'
+	END,
+	ended_at = COALESCE(ended_at, NOW());
+CREATE INDEX builds_pending_idx ON builds (id)
+WHERE status IN ('queued', 'running')
@@ -0,0 +1,11 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE builds
+ADD COLUMN events TEXT[] NOT NULL DEFAULT ARRAY[]::TEXT[];
+UPDATE builds AS b
+SET events = ARRAY(
+		SELECT (jsonb_build_object('ts', e.ts) || e.payload)::text
+		FROM build_events AS e
+		WHERE e.build_id = b.id
+		ORDER BY e.seq
+	);
+DROP TABLE build_events
@@ -0,0 +1,26 @@
+-- Your SQL goes here
+CREATE TABLE build_events (
+	build_id INTEGER NOT NULL REFERENCES builds (id) ON DELETE CASCADE,
+	seq INTEGER NOT NULL,
+	ts TIMESTAMP NOT NULL,
+	kind TEXT NOT NULL CHECK (
+		kind IN ('git_clone', 'docker_build', 'deploy', 'error')
+	),
+	payload JSONB NOT NULL,
+	PRIMARY KEY (build_id, seq)
+);
+CREATE INDEX build_events_kind_idx ON build_events (build_id, kind, seq);
+-- Events were stored as {"ts": ..., "Ok": {"type": ..., ...}} or {"ts": ..., "Err": ...}
+INSERT INTO build_events (build_id, seq, ts, kind, payload)
+SELECT b.id,
+	e.seq,
+	COALESCE((e.event->>'ts')::timestamp, b.started_at),
+	CASE
+		WHEN e.event ? 'Err' THEN 'error'
+		WHEN e.event->'Ok'->>'type' IN ('git_clone', 'docker_build') THEN e.event->'Ok'->>'type'
+		ELSE 'deploy'
+	END,
+	e.event - 'ts'
+FROM builds AS b,
+	unnest(b.events::jsonb[]) WITH ORDINALITY AS e (event, seq);
+ALTER TABLE builds DROP COLUMN events;
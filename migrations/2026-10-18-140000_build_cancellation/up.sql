@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE builds
+ADD COLUMN cancel_requested BOOLEAN NOT NULL DEFAULT FALSE
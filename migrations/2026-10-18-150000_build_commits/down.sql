@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE builds
+DROP COLUMN git_ref,
+DROP COLUMN commit_sha,
+DROP COLUMN commit_author,
+DROP COLUMN commit_message
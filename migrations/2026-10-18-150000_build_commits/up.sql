@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE builds
+ADD COLUMN git_ref TEXT,
+ADD COLUMN commit_sha TEXT,
+ADD COLUMN commit_author TEXT,
+ADD COLUMN commit_message TEXT
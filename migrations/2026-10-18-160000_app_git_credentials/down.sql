@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE apps
+DROP COLUMN deploy_key_public,
+DROP COLUMN deploy_key_private,
+DROP COLUMN git_https_token
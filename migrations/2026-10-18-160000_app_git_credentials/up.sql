@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE apps
+ADD COLUMN deploy_key_public TEXT,
+ADD COLUMN deploy_key_private TEXT,
+ADD COLUMN git_https_token TEXT
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE apps
+DROP COLUMN git_repository,
+DROP COLUMN git_branch,
+DROP COLUMN webhook_secret
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE apps
+ADD COLUMN git_repository TEXT,
+ADD COLUMN git_branch TEXT,
+ADD COLUMN webhook_secret TEXT
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE domains
+DROP COLUMN last_checked_at,
+DROP COLUMN last_verified_at
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE domains
+ADD COLUMN last_checked_at TIMESTAMP,
+ADD COLUMN last_verified_at TIMESTAMP;
+
+UPDATE domains SET last_checked_at = NOW(), last_verified_at = NOW() WHERE verified
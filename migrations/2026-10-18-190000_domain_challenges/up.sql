@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- Evaluated for each existing domain
+ALTER TABLE domains
+ADD COLUMN challenge_token TEXT NOT NULL DEFAULT md5(random()::text);
+
+ALTER TABLE domains
+ALTER COLUMN challenge_token DROP DEFAULT
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE builds DROP CONSTRAINT builds_app_id_fkey;
+ALTER TABLE builds
+ADD CONSTRAINT builds_app_id_fkey FOREIGN KEY (app_id) REFERENCES apps (id);
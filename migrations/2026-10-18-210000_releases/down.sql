@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE releases;
+ALTER TABLE builds DROP COLUMN image_build_id;
@@ -0,0 +1,23 @@
+-- Your SQL goes here
+ALTER TABLE builds
+ADD COLUMN image_build_id INTEGER REFERENCES builds (id) ON DELETE CASCADE;
+CREATE TABLE releases (
+	id SERIAL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+	app_id INTEGER NOT NULL REFERENCES apps (id) ON DELETE CASCADE,
+	build_id INTEGER NOT NULL REFERENCES builds (id) ON DELETE CASCADE,
+	image_build_id INTEGER NOT NULL REFERENCES builds (id) ON DELETE CASCADE
+);
+CREATE INDEX releases_app_id_idx ON releases (app_id, id);
+-- The last successful build of every deployed app is live
+INSERT INTO releases (created_at, app_id, build_id, image_build_id)
+SELECT DISTINCT ON (b.app_id) COALESCE(b.ended_at, b.started_at),
+	b.app_id,
+	b.id,
+	b.id
+FROM builds AS b
+	INNER JOIN apps AS a ON a.id = b.app_id
+WHERE b.status = 'succeeded'
+	AND a.container_id IS NOT NULL
+ORDER BY b.app_id,
+	b.id DESC;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE console_sessions (
+	id SERIAL PRIMARY KEY,
+	app_id INTEGER NOT NULL REFERENCES apps (id) ON DELETE CASCADE,
+	user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	started_at TIMESTAMP NOT NULL DEFAULT NOW(),
+	ended_at TIMESTAMP
+);
+CREATE INDEX console_sessions_app_id_idx ON console_sessions (app_id, id);
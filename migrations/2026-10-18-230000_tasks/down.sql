@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE runs;
+DROP TABLE task_schedules;
@@ -0,0 +1,28 @@
+-- Your SQL goes here
+CREATE TABLE task_schedules (
+	id SERIAL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+	app_id INTEGER NOT NULL REFERENCES apps (id) ON DELETE CASCADE,
+	name TEXT NOT NULL,
+	command TEXT NOT NULL,
+	cron TEXT NOT NULL,
+	concurrency_policy TEXT NOT NULL DEFAULT 'skip',
+	next_run_at TIMESTAMP,
+	UNIQUE (app_id, name)
+);
+CREATE INDEX task_schedules_next_run_at_idx ON task_schedules (next_run_at);
+CREATE TABLE runs (
+	id SERIAL PRIMARY KEY,
+	created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+	app_id INTEGER NOT NULL REFERENCES apps (id) ON DELETE CASCADE,
+	schedule_id INTEGER REFERENCES task_schedules (id) ON DELETE SET NULL,
+	command TEXT NOT NULL,
+	status TEXT NOT NULL DEFAULT 'queued',
+	started_at TIMESTAMP,
+	ended_at TIMESTAMP,
+	heartbeat_at TIMESTAMP,
+	exit_code INTEGER,
+	logs TEXT NOT NULL DEFAULT ''
+);
+CREATE INDEX runs_app_id_idx ON runs (app_id, id);
+CREATE INDEX runs_status_idx ON runs (status);
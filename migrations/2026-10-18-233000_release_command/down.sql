@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE apps DROP COLUMN release_command;
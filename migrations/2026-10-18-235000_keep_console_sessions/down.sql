@@ -0,0 +1,15 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM console_sessions WHERE app_id IS NULL OR user_id IS NULL;
+
+ALTER TABLE console_sessions DROP CONSTRAINT console_sessions_user_id_fkey;
+ALTER TABLE console_sessions
+ADD CONSTRAINT console_sessions_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+ALTER TABLE console_sessions DROP CONSTRAINT console_sessions_app_id_fkey;
+ALTER TABLE console_sessions
+ADD CONSTRAINT console_sessions_app_id_fkey FOREIGN KEY (app_id) REFERENCES apps (id) ON DELETE CASCADE;
+
+ALTER TABLE console_sessions
+	ALTER COLUMN app_id SET NOT NULL,
+	ALTER COLUMN user_id SET NOT NULL,
+	DROP COLUMN app_slug,
+	DROP COLUMN user_name;
@@ -0,0 +1,19 @@
+-- Your SQL goes here
+-- Sessions outlive their app and user for auditing, along with what they were called
+ALTER TABLE console_sessions
+	ADD COLUMN app_slug TEXT,
+	ADD COLUMN user_name TEXT;
+UPDATE console_sessions SET app_slug = apps.slug FROM apps WHERE apps.id = console_sessions.app_id;
+UPDATE console_sessions SET user_name = users.name FROM users WHERE users.id = console_sessions.user_id;
+ALTER TABLE console_sessions
+	ALTER COLUMN app_slug SET NOT NULL,
+	ALTER COLUMN user_name SET NOT NULL,
+	ALTER COLUMN app_id DROP NOT NULL,
+	ALTER COLUMN user_id DROP NOT NULL;
+
+ALTER TABLE console_sessions DROP CONSTRAINT console_sessions_app_id_fkey;
+ALTER TABLE console_sessions
+ADD CONSTRAINT console_sessions_app_id_fkey FOREIGN KEY (app_id) REFERENCES apps (id) ON DELETE SET NULL;
+ALTER TABLE console_sessions DROP CONSTRAINT console_sessions_user_id_fkey;
+ALTER TABLE console_sessions
+ADD CONSTRAINT console_sessions_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE SET NULL;
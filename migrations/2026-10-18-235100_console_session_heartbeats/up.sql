@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE console_sessions ADD COLUMN heartbeat_at TIMESTAMP;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE builds
+ADD COLUMN from_webhook BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE domains
+DROP COLUMN certificate_state,
+DROP COLUMN certificate_expires_at,
+DROP COLUMN certificate_error,
+DROP COLUMN certificate_checked_at;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE domains
+ADD COLUMN certificate_state TEXT,
+ADD COLUMN certificate_expires_at TIMESTAMP,
+ADD COLUMN certificate_error TEXT,
+ADD COLUMN certificate_checked_at TIMESTAMP;
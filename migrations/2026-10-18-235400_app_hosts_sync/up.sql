@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE apps
+ADD COLUMN hosts_sync_requested_at TIMESTAMP;
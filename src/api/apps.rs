@@ -1,19 +1,58 @@
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDateTime;
 use diesel::{
 	connection::Connection,
 	prelude::*,
 	result::{
 		DatabaseErrorKind::UniqueViolation,
-		Error::{self, DatabaseError, NotFound},
+		Error::{self, DatabaseError, NotFound, QueryBuilderError},
+	},
+};
+use provisioner::{LogLine, LogOptions};
+use rocket::{
+	futures::{
+		stream::{self, BoxStream},
+		StreamExt, TryStreamExt,
+	},
+	http::Status,
+	response::{
+		self,
+		status::NoContent,
+		stream::{Event, EventStream},
+		Responder,
 	},
+	serde::json::Json,
+	tokio::sync::RwLock,
+	Request, State,
 };
-use rocket::{http::Status, serde::json::Json, tokio::sync::RwLock, State};
 
-use db_models::{App, Build, Domain, NewApp, NewDomain, Team, TeamUser};
+use db_models::{
+	App, AppEnvVar, Build, BuildStatus, ConsoleSession, Domain, NewApp, NewAppEnvVar, NewDomain,
+	Release, Team, TeamUser, UpdatedApp, UpdatedAppEnvVar, User,
+};
+
+use crate::{
+	api::domains::DomainWithCertificate,
+	auth::AuthUser,
+	provision::{self, ProvisionerManager, ResourceLimitsConfig},
+	utils::{
+		crypto::{Encryptor, SecretColumn},
+		domain::AppsDomain,
+		env_var::validate_env_key,
+		slug::validate_slug,
+		token::generate_token,
+	},
+	DbConn,
+};
 
-use crate::{auth::AuthUser, provision::ProvisionerManager, utils::slug::validate_slug, DbConn};
+/// Number of log lines returned when `tail` isn't given
+const DEFAULT_LOG_TAIL: u64 = 100;
+/// How often deleting an app checks whether its cancelled builds have stopped
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Fetches an app by the `slug`, which can either be an App.slug or a numeric App.id
-fn fetch_app(app_slug: String, user_id: i32, c: &diesel::PgConnection) -> QueryResult<App> {
+pub fn fetch_app(app_slug: String, user_id: i32, c: &diesel::PgConnection) -> QueryResult<App> {
 	use db_models::schema::apps::dsl::*;
 	use db_models::schema::{team_users, teams};
 
@@ -34,6 +73,35 @@ fn fetch_app(app_slug: String, user_id: i32, c: &diesel::PgConnection) -> QueryR
 	Ok(app)
 }
 
+/// Whether a build of the app is queued or running, and so about to deploy it
+fn has_pending_build(app_id: i32, c: &diesel::PgConnection) -> QueryResult<bool> {
+	use db_models::schema::builds::dsl::{app_id as build_app_id, builds, status};
+
+	diesel::select(diesel::dsl::exists(
+		builds
+			.filter(build_app_id.eq(app_id))
+			.filter(status.eq_any(vec![BuildStatus::Queued, BuildStatus::Running])),
+	))
+	.get_result(c)
+}
+
+/// Fetches an app whose container can be stopped or started. The container is replaced by the
+/// deploy of a pending build, so changing it in the meantime would race the build.
+fn fetch_idle_app(app_slug: String, user_id: i32, c: &diesel::PgConnection) -> Result<App, Status> {
+	let app = fetch_app(app_slug, user_id, c).map_err(|e| {
+		if e == NotFound {
+			Status::NotFound
+		} else {
+			Status::InternalServerError
+		}
+	})?;
+	if has_pending_build(app.id, c).map_err(|_| Status::InternalServerError)? {
+		return Err(Status::Conflict);
+	}
+
+	Ok(app)
+}
+
 #[get("/apps/<app_slug>")]
 pub async fn app(app_slug: String, user: AuthUser, conn: DbConn) -> Result<Json<App>, Status> {
 	conn.run(move |c| {
@@ -50,17 +118,228 @@ pub async fn app(app_slug: String, user: AuthUser, conn: DbConn) -> Result<Json<
 	.await
 }
 
+#[patch("/apps/<app_slug>", data = "<app>")]
+pub async fn update(
+	app_slug: String,
+	user: AuthUser,
+	app: Json<UpdatedApp>,
+	conn: DbConn,
+	limits: &State<ResourceLimitsConfig>,
+) -> Result<Json<App>, Status> {
+	if !limits.allows(&app) {
+		return Err(Status::UnprocessableEntity);
+	}
+	if let Some(Some(git_repository)) = &app.git_repository {
+		if git_repository.parse::<provisioner::hyper::Uri>().is_err() {
+			return Err(Status::UnprocessableEntity);
+		}
+	}
+	if let Some(Some(git_branch)) = &app.git_branch {
+		if !provisioner::is_valid_git_ref(git_branch) {
+			return Err(Status::UnprocessableEntity);
+		}
+	}
+	if let Some(Some(release_command)) = &app.release_command {
+		if release_command.trim().is_empty() {
+			return Err(Status::UnprocessableEntity);
+		}
+	}
+
+	conn.run(move |c| {
+		let existing_app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		match diesel::update(&existing_app)
+			.set(&app.into_inner())
+			.get_result::<App>(c)
+		{
+			Ok(updated_app) => Ok(Json(updated_app)),
+			// Nothing to change
+			Err(QueryBuilderError(_)) => Ok(Json(existing_app)),
+			Err(_) => Err(Status::InternalServerError),
+		}
+	})
+	.await
+}
+
+/// Deletes an app, and everything its deploys created. Its unfinished builds are cancelled first,
+/// and the app is only deleted once none of them is running anymore, so that none of them deploys
+/// the app again while it is being torn down.
+#[delete("/apps/<app_slug>")]
+pub async fn delete(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<NoContent, Status> {
+	let (app, mut running_builds) = conn
+		.run(move |c| {
+			use db_models::schema::builds::dsl::{app_id, builds, id, status};
+
+			let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+				if e == NotFound {
+					Status::NotFound
+				} else {
+					Status::InternalServerError
+				}
+			})?;
+
+			let unfinished = builds
+				.filter(app_id.eq(app.id))
+				.filter(status.eq_any(vec![BuildStatus::Queued, BuildStatus::Running]))
+				.select(id)
+				.load::<i32>(c)
+				.map_err(|_| Status::InternalServerError)?;
+			let mut running_builds = Vec::new();
+			for build_id in unfinished {
+				let build = provision::request_build_cancellation(build_id, c)
+					.map_err(|_| Status::InternalServerError)?;
+				if build.status == BuildStatus::Running {
+					running_builds.push(build_id);
+				}
+			}
+
+			Ok((app, running_builds))
+		})
+		.await?;
+
+	let provisioner_manager = provisioner_manager.read().await;
+	for &build_id in &running_builds {
+		provisioner_manager.cancel_local_build(build_id);
+	}
+	let deadline = Instant::now() + provision::BUILD_CANCEL_TIMEOUT;
+	while !running_builds.is_empty() {
+		// Deleting can be retried once the builds have stopped
+		if Instant::now() >= deadline {
+			return Err(Status::Conflict);
+		}
+		rocket::tokio::time::sleep(CANCEL_POLL_INTERVAL).await;
+
+		let ids = running_builds.clone();
+		running_builds = conn
+			.run(move |c| {
+				use db_models::schema::builds::dsl::{builds, id, status};
+
+				builds
+					.filter(id.eq_any(ids))
+					.filter(status.eq(BuildStatus::Running))
+					.select(id)
+					.load::<i32>(c)
+			})
+			.await
+			.map_err(|_| Status::InternalServerError)?;
+	}
+
+	provisioner_manager
+		.destroy_app(&conn, app.id)
+		.await
+		.map_err(|e| {
+			log::error!("Failed to delete app {}: {}", app.id, e);
+			Status::InternalServerError
+		})?;
+
+	Ok(NoContent)
+}
+
+/// Stops the app's container, and serves a "disabled" page instead until it is started again
+#[post("/apps/<app_slug>/stop")]
+pub async fn stop(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<Json<App>, Status> {
+	let mut app = conn
+		.run(move |c| fetch_idle_app(app_slug, user.id, c))
+		.await?;
+
+	provisioner_manager
+		.read()
+		.await
+		.stop_app(&conn, app.id)
+		.await
+		.map_err(|e| {
+			log::error!("Failed to stop app {}: {}", app.id, e);
+			Status::InternalServerError
+		})?;
+	app.enabled = false;
+
+	Ok(Json(app))
+}
+
+/// Starts a stopped app's container, and routes traffic back to it once it is healthy
+#[post("/apps/<app_slug>/start")]
+pub async fn start(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<Json<App>, Status> {
+	let mut app = conn
+		.run(move |c| fetch_idle_app(app_slug, user.id, c))
+		.await?;
+
+	provisioner_manager
+		.read()
+		.await
+		.start_app(&conn, app.id)
+		.await
+		.map_err(|e| {
+			log::error!("Failed to start app {}: {}", app.id, e);
+			Status::InternalServerError
+		})?;
+	app.enabled = true;
+
+	Ok(Json(app))
+}
+
+#[post("/apps/<app_slug>/restart")]
+pub async fn restart(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<Json<App>, Status> {
+	let app = conn
+		.run(move |c| fetch_idle_app(app_slug, user.id, c))
+		.await?;
+	// Stopped apps have to be started instead
+	if !app.enabled {
+		return Err(Status::Conflict);
+	}
+
+	provisioner_manager
+		.read()
+		.await
+		.restart_app(&conn, app.id)
+		.await
+		.map_err(|e| {
+			log::error!("Failed to restart app {}: {}", app.id, e);
+			Status::InternalServerError
+		})?;
+
+	Ok(Json(app))
+}
+
 #[post("/teams/<team_slug>/apps", data = "<app>")]
 pub async fn create(
 	user: AuthUser,
 	team_slug: String,
 	app: Json<NewApp>,
 	conn: DbConn,
+	apps_domain: &State<AppsDomain>,
 ) -> Result<Json<App>, Status> {
 	if !validate_slug(&app.slug) {
 		return Err(Status::UnprocessableEntity);
 	}
 
+	let apps_domain = apps_domain.inner().clone();
+
 	conn.run(move |c| {
 		use db_models::schema::apps::dsl::apps;
 		use db_models::schema::domains::dsl::domains;
@@ -93,8 +372,9 @@ pub async fn create(
 			diesel::insert_into(domains)
 				.values(NewDomain {
 					verified: true,
-					domain: format!("{}.hackclub.app", app.slug),
+					domain: apps_domain.builtin_domain(&app.slug),
 					app_id: app.id,
+					challenge_token: generate_token(),
 				})
 				.execute(c)?;
 
@@ -118,7 +398,7 @@ pub async fn domains(
 	app_slug: String,
 	user: AuthUser,
 	conn: DbConn,
-) -> Result<Json<Vec<Domain>>, Status> {
+) -> Result<Json<Vec<DomainWithCertificate>>, Status> {
 	conn.run(move |c| {
 		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
 			if e == NotFound {
@@ -128,19 +408,23 @@ pub async fn domains(
 			}
 		})?;
 
-		let domains = Domain::belonging_to(&app)
+		Domain::belonging_to(&app)
 			.load::<Domain>(c)
-			.map_err(|_| Status::InternalServerError)?;
-
-		Ok(Json(domains))
+			.map(|domains| Json(domains.into_iter().map(Into::into).collect()))
+			.map_err(|_| Status::InternalServerError)
 	})
 	.await
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct NewDeploy {
-	#[serde(with = "crate::utils::uri_serializer")]
-	git_repository: provisioner::hyper::Uri,
+	/// Defaults to the app's repository
+	#[serde(default)]
+	git_repository: Option<String>,
+	/// Branch, tag or commit SHA to deploy, defaults to the app's branch, then to the repository's
+	/// default branch
+	#[serde(rename = "ref", default)]
+	git_ref: Option<String>,
 }
 
 #[post("/apps/<app_slug>/deploy", data = "<deploy>")]
@@ -151,6 +435,12 @@ pub async fn deploy(
 	conn: DbConn,
 	provisioner_manager: &State<RwLock<ProvisionerManager>>,
 ) -> Result<(Status, Json<Build>), Status> {
+	if let Some(git_ref) = &deploy.git_ref {
+		if !provisioner::is_valid_git_ref(git_ref) {
+			return Err(Status::UnprocessableEntity);
+		}
+	}
+
 	let app = conn
 		.run(move |c| {
 			let app = fetch_app(app_slug, user.id, c).map_err(|e| {
@@ -164,15 +454,23 @@ pub async fn deploy(
 			Ok(app)
 		})
 		.await?;
+	// Stopped apps are only deployed again once started
+	if !app.enabled {
+		return Err(Status::Conflict);
+	}
 	// FIXME: Change to a 303 /builds/{id} once we have that route set up
 	let existing_build = conn
 		.run({
 			let app = app.clone();
 			move |c| {
-				use db_models::schema::builds::dsl::{app_id, builds, ended_at};
+				use db_models::schema::builds::dsl::{app_id, builds, id, status};
 
 				let build = builds
-					.filter(app_id.eq(app.id).and(ended_at.is_null()))
+					.filter(
+						app_id
+							.eq(app.id)
+							.and(status.eq_any(vec![BuildStatus::Queued, BuildStatus::Running])),
+					)
 					.first::<Build>(c)
 					.optional()
 					.map_err(|_| Status::InternalServerError)?;
@@ -184,10 +482,627 @@ pub async fn deploy(
 		// Somehow make this a 200?
 		return Ok((Status::Ok, Json(existing_build)));
 	}
-	let mut provisioner_manager = provisioner_manager.write().await;
+	let deploy = deploy.into_inner();
+	let git_repository = deploy
+		.git_repository
+		.or_else(|| app.git_repository.clone())
+		.ok_or(Status::UnprocessableEntity)?
+		.parse::<provisioner::hyper::Uri>()
+		.map_err(|_| Status::UnprocessableEntity)?;
+	let git_ref = deploy.git_ref.or_else(|| app.git_branch.clone());
+	let new_build = provisioner_manager
+		.read()
+		.await
+		.create_build(conn, git_repository, git_ref, false, &app)
+		.await
+		.map_err(|_| Status::InternalServerError)?;
+	Ok((Status::Accepted, Json(new_build)))
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct NewRollback {
+	build_id: i32,
+}
+
+/// Deploys the image of an earlier build again, without rebuilding it. Runs as a build of its own,
+/// so that it shows up in the build queue with its events.
+#[post("/apps/<app_slug>/rollback", data = "<rollback>")]
+pub async fn rollback(
+	app_slug: String,
+	user: AuthUser,
+	rollback: Json<NewRollback>,
+	conn: DbConn,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<(Status, Json<Build>), Status> {
+	let target = conn
+		.run(move |c| {
+			use db_models::schema::builds::dsl::{id, status};
+
+			let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+				if e == NotFound {
+					Status::NotFound
+				} else {
+					Status::InternalServerError
+				}
+			})?;
+			// Stopped apps are only deployed again once started
+			if !app.enabled {
+				return Err(Status::Conflict);
+			}
+
+			// Rolling back while a deploy is pending would race it
+			if has_pending_build(app.id, c).map_err(|_| Status::InternalServerError)? {
+				return Err(Status::Conflict);
+			}
+
+			// Only successful builds are known to have a working image
+			Build::belonging_to(&app)
+				.filter(id.eq(rollback.build_id))
+				.filter(status.eq(BuildStatus::Succeeded))
+				.first::<Build>(c)
+				.map_err(|e| {
+					if e == NotFound {
+						Status::UnprocessableEntity
+					} else {
+						Status::InternalServerError
+					}
+				})
+		})
+		.await?;
+
+	let provisioner_manager = provisioner_manager.read().await;
+	// Images of older builds are pruned
+	let images = provisioner_manager
+		.app_image_builds(target.app_id)
+		.await
+		.map_err(|_| Status::InternalServerError)?;
+	if !images.contains(&target.image_build_id()) {
+		return Err(Status::UnprocessableEntity);
+	}
+
 	let new_build = provisioner_manager
-		.create_build(conn, deploy.git_repository.clone(), app.id, &app.slug)
+		.create_rollback(conn, &target)
 		.await
 		.map_err(|_| Status::InternalServerError)?;
 	Ok((Status::Accepted, Json(new_build)))
 }
+
+/// Lists the app's releases, newest first. The first one is live.
+#[get("/apps/<app_slug>/releases")]
+pub async fn releases(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+) -> Result<Json<Vec<Release>>, Status> {
+	conn.run(move |c| {
+		use db_models::schema::releases::dsl::id;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		let releases = Release::belonging_to(&app)
+			.order(id.desc())
+			.load::<Release>(c)
+			.map_err(|_| Status::InternalServerError)?;
+
+		Ok(Json(releases))
+	})
+	.await
+}
+
+pub enum LogsResponse {
+	Lines(Json<Vec<LogLine>>),
+	Stream(EventStream<BoxStream<'static, Event>>),
+}
+
+impl<'r> Responder<'r, 'r> for LogsResponse {
+	fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+		match self {
+			Self::Lines(lines) => lines.respond_to(req),
+			Self::Stream(events) => events.respond_to(req),
+		}
+	}
+}
+
+/// Reads the logs of the app's container. With `follow`, new lines are streamed as server-sent
+/// events until the container stops.
+#[get("/apps/<app_slug>/logs?<tail>&<since>&<follow>")]
+pub async fn logs(
+	app_slug: String,
+	tail: Option<u64>,
+	since: Option<i64>,
+	follow: Option<bool>,
+	user: AuthUser,
+	conn: DbConn,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<LogsResponse, Status> {
+	let app = conn
+		.run(move |c| fetch_app(app_slug, user.id, c))
+		.await
+		.map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+	// Never deployed
+	let container_id = app.container_id.ok_or(Status::NotFound)?;
+	let app_id = app.id;
+
+	let options = LogOptions {
+		tail: Some(tail.unwrap_or(DEFAULT_LOG_TAIL)),
+		since,
+		follow: follow.unwrap_or(false),
+	};
+	let lines = provisioner_manager
+		.read()
+		.await
+		.container_logs(&container_id, &options);
+
+	if options.follow {
+		let events = lines
+			.map(|line| match line {
+				Ok(line) => Event::json(&line),
+				Err(e) => Event::data(e.to_string()).event("error"),
+			})
+			// Lets clients tell the container stopping apart from a dropped connection
+			.chain(stream::once(async { Event::data("").event("end") }));
+		Ok(LogsResponse::Stream(EventStream::from(events.boxed())))
+	} else {
+		lines
+			.try_collect()
+			.await
+			.map(|lines| LogsResponse::Lines(Json(lines)))
+			.map_err(|e| {
+				log::error!("Failed to read logs of app {}: {}", app_id, e);
+				Status::InternalServerError
+			})
+	}
+}
+
+/// An app's environment variable as returned by the API. The values of secrets are masked.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct EnvVar {
+	key: String,
+	value: Option<String>,
+	secret: bool,
+	created_at: NaiveDateTime,
+}
+
+impl EnvVar {
+	fn from_model(var: AppEnvVar, encryptor: &Encryptor) -> Result<Self, Status> {
+		let value = if var.secret {
+			None
+		} else {
+			Some(
+				encryptor
+					.decrypt(&var.encrypted_value, SecretColumn::EnvVarValue, var.app_id)
+					.map_err(|_| Status::InternalServerError)?,
+			)
+		};
+
+		Ok(Self {
+			key: var.key,
+			value,
+			secret: var.secret,
+			created_at: var.created_at,
+		})
+	}
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct NewEnvVar {
+	key: String,
+	value: String,
+	#[serde(default)]
+	secret: bool,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct UpdatedEnvVar {
+	value: Option<String>,
+	secret: Option<bool>,
+}
+
+#[get("/apps/<app_slug>/env")]
+pub async fn env(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+	encryptor: &State<Encryptor>,
+) -> Result<Json<Vec<EnvVar>>, Status> {
+	let encryptor = encryptor.inner().clone();
+
+	conn.run(move |c| {
+		use db_models::schema::app_env_vars::dsl::key;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		let vars = AppEnvVar::belonging_to(&app)
+			.order(key.asc())
+			.load::<AppEnvVar>(c)
+			.map_err(|_| Status::InternalServerError)?;
+
+		vars.into_iter()
+			.map(|v| EnvVar::from_model(v, &encryptor))
+			.collect::<Result<_, _>>()
+			.map(Json)
+	})
+	.await
+}
+
+#[post("/apps/<app_slug>/env", data = "<var>")]
+pub async fn create_env_var(
+	app_slug: String,
+	user: AuthUser,
+	var: Json<NewEnvVar>,
+	conn: DbConn,
+	encryptor: &State<Encryptor>,
+) -> Result<Json<EnvVar>, Status> {
+	if !validate_env_key(&var.key) {
+		return Err(Status::UnprocessableEntity);
+	}
+
+	let encryptor = encryptor.inner().clone();
+
+	conn.run(move |c| {
+		use db_models::schema::app_env_vars::dsl::app_env_vars;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		let created_var = diesel::insert_into(app_env_vars)
+			.values(NewAppEnvVar {
+				app_id: app.id,
+				encrypted_value: encryptor.encrypt(&var.value, SecretColumn::EnvVarValue, app.id),
+				key: var.0.key,
+				secret: var.0.secret,
+			})
+			.get_result::<AppEnvVar>(c)
+			.map_err(|e| {
+				if let DatabaseError(UniqueViolation, _) = e {
+					Status::Conflict
+				} else {
+					Status::InternalServerError
+				}
+			})?;
+
+		EnvVar::from_model(created_var, &encryptor).map(Json)
+	})
+	.await
+}
+
+#[patch("/apps/<app_slug>/env/<var_key>", data = "<var>")]
+pub async fn update_env_var(
+	app_slug: String,
+	var_key: String,
+	user: AuthUser,
+	var: Json<UpdatedEnvVar>,
+	conn: DbConn,
+	encryptor: &State<Encryptor>,
+) -> Result<Json<EnvVar>, Status> {
+	let encryptor = encryptor.inner().clone();
+
+	conn.run(move |c| {
+		use db_models::schema::app_env_vars::dsl::key;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		let existing_var = AppEnvVar::belonging_to(&app)
+			.filter(key.eq(var_key))
+			.first::<AppEnvVar>(c)
+			.map_err(|e| {
+				if e == NotFound {
+					Status::NotFound
+				} else {
+					Status::InternalServerError
+				}
+			})?;
+
+		let changes = UpdatedAppEnvVar {
+			encrypted_value: var
+				.value
+				.as_deref()
+				.map(|v| encryptor.encrypt(v, SecretColumn::EnvVarValue, app.id)),
+			secret: var.secret,
+		};
+
+		// Diesel refuses to run an update without any changes
+		let updated_var = if changes.encrypted_value.is_none() && changes.secret.is_none() {
+			existing_var
+		} else {
+			diesel::update(&existing_var)
+				.set(&changes)
+				.get_result::<AppEnvVar>(c)
+				.map_err(|_| Status::InternalServerError)?
+		};
+
+		EnvVar::from_model(updated_var, &encryptor).map(Json)
+	})
+	.await
+}
+
+#[delete("/apps/<app_slug>/env/<var_key>")]
+pub async fn delete_env_var(
+	app_slug: String,
+	var_key: String,
+	user: AuthUser,
+	conn: DbConn,
+) -> Result<NoContent, Status> {
+	conn.run(move |c| {
+		use db_models::schema::app_env_vars::dsl::key;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		let deleted = diesel::delete(AppEnvVar::belonging_to(&app).filter(key.eq(var_key)))
+			.execute(c)
+			.map_err(|_| Status::InternalServerError)?;
+
+		if deleted == 0 {
+			return Err(Status::NotFound);
+		}
+
+		Ok(NoContent)
+	})
+	.await
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct DeployKey {
+	public_key: String,
+}
+
+/// Returns the public half of the app's SSH deploy key, generating the key on first use
+#[get("/apps/<app_slug>/deploy_key")]
+pub async fn deploy_key(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+	encryptor: &State<Encryptor>,
+) -> Result<Json<DeployKey>, Status> {
+	let app = conn
+		.run(move |c| {
+			fetch_app(app_slug, user.id, c).map_err(|e| {
+				if e == NotFound {
+					Status::NotFound
+				} else {
+					Status::InternalServerError
+				}
+			})
+		})
+		.await?;
+
+	if let Some(public_key) = app.deploy_key_public {
+		return Ok(Json(DeployKey { public_key }));
+	}
+
+	let key = provisioner::generate_deploy_key(&format!("haas-{}", app.slug))
+		.await
+		.map_err(|_| Status::InternalServerError)?;
+	let encrypted_private_key =
+		encryptor.encrypt(&key.private_key, SecretColumn::DeployKey, app.id);
+
+	conn.run(move |c| {
+		use db_models::schema::apps::dsl::{apps, deploy_key_private, deploy_key_public, id};
+
+		// A concurrent request may have generated a key already, in which case it wins
+		diesel::update(apps.filter(id.eq(app.id).and(deploy_key_public.is_null())))
+			.set((
+				deploy_key_public.eq(key.public_key),
+				deploy_key_private.eq(encrypted_private_key),
+			))
+			.execute(c)?;
+
+		apps.find(app.id)
+			.select(deploy_key_public)
+			.first::<Option<String>>(c)
+	})
+	.await
+	.ok()
+	.flatten()
+	.map(|public_key| Json(DeployKey { public_key }))
+	.ok_or(Status::InternalServerError)
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct GitToken {
+	token: String,
+}
+
+/// Sets the token used to clone the app's repository over HTTPS
+#[put("/apps/<app_slug>/git_token", data = "<git_token>")]
+pub async fn set_git_token(
+	app_slug: String,
+	user: AuthUser,
+	git_token: Json<GitToken>,
+	conn: DbConn,
+	encryptor: &State<Encryptor>,
+) -> Result<NoContent, Status> {
+	if git_token.token.is_empty() {
+		return Err(Status::UnprocessableEntity);
+	}
+
+	let encryptor = encryptor.inner().clone();
+	conn.run(move |c| {
+		use db_models::schema::apps::dsl::git_https_token;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		let encrypted_token =
+			encryptor.encrypt(&git_token.token, SecretColumn::GitHttpsToken, app.id);
+		diesel::update(&app)
+			.set(git_https_token.eq(encrypted_token))
+			.execute(c)
+			.map_err(|_| Status::InternalServerError)?;
+
+		Ok(NoContent)
+	})
+	.await
+}
+
+#[delete("/apps/<app_slug>/git_token")]
+pub async fn delete_git_token(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+) -> Result<NoContent, Status> {
+	conn.run(move |c| {
+		use db_models::schema::apps::dsl::git_https_token;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		diesel::update(&app)
+			.set(git_https_token.eq(None::<String>))
+			.execute(c)
+			.map_err(|_| Status::InternalServerError)?;
+
+		Ok(NoContent)
+	})
+	.await
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct WebhookSecret {
+	secret: String,
+}
+
+/// Returns the secret Git hosts must sign webhook deliveries with, generating it on first use
+#[get("/apps/<app_slug>/webhook_secret")]
+pub async fn webhook_secret(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+	encryptor: &State<Encryptor>,
+) -> Result<Json<WebhookSecret>, Status> {
+	let encryptor = encryptor.inner().clone();
+
+	conn.run(move |c| {
+		use db_models::schema::apps::dsl::{apps, id, webhook_secret};
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		// A concurrent request may have generated a secret already, in which case it wins
+		if app.webhook_secret.is_none() {
+			diesel::update(apps.filter(id.eq(app.id).and(webhook_secret.is_null())))
+				.set(webhook_secret.eq(encryptor.encrypt(
+					&generate_token(),
+					SecretColumn::WebhookSecret,
+					app.id,
+				)))
+				.execute(c)
+				.map_err(|_| Status::InternalServerError)?;
+		}
+
+		let encrypted_secret = apps
+			.find(app.id)
+			.select(webhook_secret)
+			.first::<Option<String>>(c)
+			.ok()
+			.flatten()
+			.ok_or(Status::InternalServerError)?;
+
+		encryptor
+			.decrypt(&encrypted_secret, SecretColumn::WebhookSecret, app.id)
+			.map(|secret| Json(WebhookSecret { secret }))
+			.map_err(|_| Status::InternalServerError)
+	})
+	.await
+}
+
+#[derive(serde::Serialize)]
+pub struct ConsoleSessionWithUser {
+	#[serde(flatten)]
+	session: ConsoleSession,
+	/// Unset if the user was deleted since
+	user: Option<User>,
+	/// How long the session lasted, in seconds. Unset while it is open.
+	duration_secs: Option<i64>,
+}
+
+/// Lists the console sessions opened into the app, newest first
+#[get("/apps/<app_slug>/console_sessions")]
+pub async fn console_sessions(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+) -> Result<Json<Vec<ConsoleSessionWithUser>>, Status> {
+	conn.run(move |c| {
+		use db_models::schema::console_sessions::dsl::id;
+		use db_models::schema::users;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		let sessions = ConsoleSession::belonging_to(&app)
+			.left_join(users::table)
+			.order(id.desc())
+			.load::<(ConsoleSession, Option<User>)>(c)
+			.map_err(|_| Status::InternalServerError)?;
+
+		Ok(Json(
+			sessions
+				.into_iter()
+				.map(|(session, user)| ConsoleSessionWithUser {
+					duration_secs: session
+						.ended_at
+						.map(|ended_at| (ended_at - session.started_at).num_seconds()),
+					session,
+					user,
+				})
+				.collect(),
+		))
+	})
+	.await
+}
@@ -1,31 +1,230 @@
+use std::time::Duration;
+
 use diesel::{prelude::*, result::Error::NotFound};
-use rocket::{http::Status, serde::json::Json};
+use rocket::{
+	futures::Stream,
+	http::Status,
+	request::{self, FromRequest, Outcome, Request},
+	response::stream::{Event, EventStream},
+	serde::json::Json,
+	tokio::sync::{broadcast::error::RecvError, RwLock},
+	State,
+};
+
+use db_models::{App, Build, BuildEvent, BuildEventKind, BuildStatus, Team, TeamUser};
+
+use crate::{
+	auth::AuthUser,
+	provision::{self, ProvisionerManager},
+	utils::db_pool::DbPool,
+	DbConn,
+};
 
-use db_models::{App, Build, Team, TeamUser};
+/// How often the events of builds that aren't running on this instance are polled from the db
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum number of events returned by a single query
+const MAX_PAGE_SIZE: i64 = 1000;
 
-use crate::{auth::AuthUser, DbConn};
+fn fetch_build(build_id: i32, user_id: i32, c: &diesel::PgConnection) -> Result<Build, Status> {
+	use db_models::schema::apps::dsl::apps;
+	use db_models::schema::builds::dsl::{builds, id};
+	use db_models::schema::team_users::dsl::{team_users, user_id as team_user_id};
+	use db_models::schema::teams::dsl::teams;
+
+	builds
+		.inner_join(apps.inner_join(teams.inner_join(team_users)))
+		.filter(team_user_id.eq(user_id).and(id.eq(build_id)))
+		.first::<(Build, (App, (Team, TeamUser)))>(c)
+		.map(|build| build.0)
+		.map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})
+}
 
 #[get("/builds/<build_id>")]
 pub async fn build(build_id: i32, user: AuthUser, conn: DbConn) -> Result<Json<Build>, Status> {
+	conn.run(move |c| fetch_build(build_id, user.id, c).map(Json))
+		.await
+}
+
+/// Cancels a build. Queued builds are cancelled right away, while running builds are stopped
+/// asynchronously, as soon as the worker running them notices.
+#[post("/builds/<build_id>/cancel")]
+pub async fn cancel(
+	build_id: i32,
+	user: AuthUser,
+	conn: DbConn,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<(Status, Json<Build>), Status> {
+	let build = conn
+		.run(move |c| {
+			fetch_build(build_id, user.id, c)?;
+
+			provision::request_build_cancellation(build_id, c)
+				.map_err(|_| Status::InternalServerError)
+		})
+		.await?;
+
+	match build.status {
+		BuildStatus::Cancelled => Ok((Status::Ok, Json(build))),
+		BuildStatus::Running => {
+			provisioner_manager
+				.read()
+				.await
+				.cancel_local_build(build_id);
+			Ok((Status::Accepted, Json(build)))
+		}
+		_ => Err(Status::Conflict),
+	}
+}
+
+/// The sequence number of the last event received by an SSE client, sent when it reconnects
+pub struct LastEventId(Option<i32>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+	type Error = ();
+
+	async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+		match req.headers().get_one("Last-Event-ID") {
+			Some(id) => match id.trim().parse() {
+				Ok(id) => Outcome::Success(LastEventId(Some(id))),
+				Err(_) => Outcome::Failure((Status::BadRequest, ())),
+			},
+			None => Outcome::Success(LastEventId(None)),
+		}
+	}
+}
+
+fn load_events(
+	build_id: i32,
+	after_seq: i32,
+	kind: Option<BuildEventKind>,
+	limit: i64,
+	c: &diesel::PgConnection,
+) -> QueryResult<Vec<BuildEvent>> {
+	use db_models::schema::build_events::dsl;
+
+	let mut query = dsl::build_events
+		.filter(dsl::build_id.eq(build_id).and(dsl::seq.gt(after_seq)))
+		.into_boxed();
+	if let Some(kind) = kind {
+		query = query.filter(dsl::kind.eq(kind));
+	}
+
+	query.order(dsl::seq.asc()).limit(limit).load(c)
+}
+
+/// Pages through a build's events, optionally only returning those of one kind
+#[get("/builds/<build_id>/events/page?<after_seq>&<kind>&<limit>")]
+pub async fn event_page(
+	build_id: i32,
+	after_seq: Option<i32>,
+	kind: Option<String>,
+	limit: Option<i64>,
+	user: AuthUser,
+	conn: DbConn,
+) -> Result<Json<Vec<BuildEvent>>, Status> {
+	let kind = match kind {
+		Some(kind) => Some(
+			kind.parse::<BuildEventKind>()
+				.map_err(|_| Status::UnprocessableEntity)?,
+		),
+		None => None,
+	};
+	let limit = limit.unwrap_or(MAX_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
 	conn.run(move |c| {
-		use db_models::schema::apps::dsl::apps;
-		use db_models::schema::builds::dsl::{builds, id};
-		use db_models::schema::team_users::dsl::{team_users, user_id};
-		use db_models::schema::teams::dsl::teams;
-
-		let build = builds
-			.inner_join(apps.inner_join(teams.inner_join(team_users)))
-			.filter(user_id.eq(user.id).and(id.eq(build_id)))
-			.first::<(Build, (App, (Team, TeamUser)))>(c)
-			.map_err(|e| {
-				if e == NotFound {
-					Status::NotFound
-				} else {
-					Status::InternalServerError
-				}
-			})?;
+		fetch_build(build_id, user.id, c)?;
 
-		Ok(Json(build.0))
+		load_events(build_id, after_seq.unwrap_or(0), kind, limit, c)
+			.map(Json)
+			.map_err(|_| Status::InternalServerError)
 	})
 	.await
 }
+
+#[get("/builds/<build_id>/events")]
+pub async fn events(
+	build_id: i32,
+	user: AuthUser,
+	last_event_id: LastEventId,
+	conn: DbConn,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<EventStream<impl Stream<Item = Event> + '_>, Status> {
+	conn.run(move |c| fetch_build(build_id, user.id, c)).await?;
+	// Streams can last for as long as builds do, so they only take connections from the pool to
+	// replay events, while live events come from the worker running the build
+	let pool = DbPool::from(&conn);
+	drop(conn);
+
+	Ok(EventStream! {
+		let mut next_seq = last_event_id.0.unwrap_or(0) + 1;
+
+		'replay: loop {
+			// Subscribe before reading the persisted events, so that none are missed in between
+			let rx = provisioner_manager.read().await.receiver_for_build(build_id);
+			let status = match pool
+				.run(move |c| {
+					use db_models::schema::builds::dsl::{builds, status};
+
+					builds.find(build_id).select(status).first::<BuildStatus>(c)
+				})
+				.await
+			{
+				Ok(status) => status,
+				Err(_) => break,
+			};
+
+			loop {
+				let after_seq = next_seq - 1;
+				let page = match pool
+					.run(move |c| load_events(build_id, after_seq, None, MAX_PAGE_SIZE, c))
+					.await
+				{
+					Ok(page) => page,
+					Err(_) => break 'replay,
+				};
+				let page_len = page.len() as i64;
+
+				for ev in page {
+					next_seq = ev.seq + 1;
+					yield Event::json(&ev).id(ev.seq.to_string());
+				}
+				if page_len < MAX_PAGE_SIZE {
+					break;
+				}
+			}
+
+			match rx {
+				Some(mut rx) => loop {
+					match rx.recv().await {
+						Ok(ev) => {
+							if ev.seq >= next_seq {
+								next_seq = ev.seq + 1;
+								yield Event::json(&ev).id(ev.seq.to_string());
+							}
+						}
+						// Catch up on the missed events from the db
+						Err(RecvError::Lagged(_)) => continue 'replay,
+						// The build ended, make sure no event was missed before it did
+						Err(RecvError::Closed) => continue 'replay,
+					}
+				},
+				// The build is waiting for a worker or running on another instance
+				None if matches!(status, BuildStatus::Queued | BuildStatus::Running) => {
+					rocket::tokio::time::sleep(POLL_INTERVAL).await;
+				}
+				None => {
+					// Lets clients tell the end of the build apart from a dropped connection
+					yield Event::json(&status).event("end");
+					break;
+				}
+			}
+		}
+	})
+}
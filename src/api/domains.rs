@@ -1,3 +1,4 @@
+use chrono::NaiveDateTime;
 use diesel::{
 	prelude::*,
 	result::{
@@ -5,85 +6,64 @@ use diesel::{
 		Error::{DatabaseError, NotFound},
 	},
 };
-use rocket::{http::Status, serde::json::Json, tokio::sync::RwLock, State};
+use rocket::{
+	http::Status, response::status::NoContent, serde::json::Json, tokio::sync::RwLock, State,
+};
 
-use db_models::{App, Domain, NewDomain, Team, TeamUser};
-use trust_dns_client::client::AsyncClient;
+use db_models::{App, CertificateState, Domain, NewDomain, Team, TeamUser};
 
 use crate::{
 	auth::AuthUser,
-	utils::domain::{validate_domain, verify_domain},
+	provision::{request_hosts_sync, ProvisionerManager},
+	utils::{
+		certificate::CertificateStatus,
+		dns::DnsClient,
+		domain::{validate_domain, verify_domain, AppsDomain, DomainTargets},
+		token::generate_token,
+	},
 	DbConn,
 };
 
+/// A domain, along with the certificate Caddy had obtained for it when the domain checker last
+/// looked
+#[derive(serde::Serialize, Debug)]
+pub struct DomainWithCertificate {
+	#[serde(flatten)]
+	domain: Domain,
+	certificate: Option<CertificateStatus>,
+}
+
+impl From<Domain> for DomainWithCertificate {
+	fn from(domain: Domain) -> Self {
+		Self {
+			certificate: CertificateStatus::of(&domain),
+			domain,
+		}
+	}
+}
+
 #[post("/apps/<app_slug>/domains", data = "<domain>")]
 pub async fn create(
 	app_slug: String,
 	domain: Json<NewDomain>,
 	user: AuthUser,
 	conn: DbConn,
-) -> Result<Json<Domain>, Status> {
+) -> Result<Json<DomainWithCertificate>, Status> {
 	if !validate_domain(&domain.domain) {
 		return Err(Status::UnprocessableEntity);
 	}
 
-	conn.run(move |c| {
-		use db_models::schema::apps::dsl::{apps, slug};
-		use db_models::schema::domains::dsl::domains;
-		use db_models::schema::team_users::dsl::{team_users, user_id};
-		use db_models::schema::teams::dsl::teams;
-
-		let (app, _) = apps
-			.inner_join(teams.inner_join(team_users))
-			.filter(user_id.eq(user.id).and(slug.eq(app_slug)))
-			.first::<(App, (Team, TeamUser))>(c)
-			.map_err(|e| {
-				if e == NotFound {
-					Status::NotFound
-				} else {
-					Status::InternalServerError
-				}
-			})?;
-
-		let created_domain = diesel::insert_into(domains)
-			.values(&NewDomain {
-				app_id: app.id,
-				verified: false,
-				..domain.0
-			})
-			.get_result::<Domain>(c)
-			.map_err(|e| {
-				if let DatabaseError(UniqueViolation, _) = e {
-					Status::Conflict
-				} else {
-					Status::InternalServerError
-				}
-			})?;
-
-		Ok(Json(created_domain))
-	})
-	.await
-}
-
-#[post("/domains/<id>/verify")]
-pub async fn verify(
-	id: i32,
-	user: AuthUser,
-	dns: &State<RwLock<AsyncClient>>,
-	conn: DbConn,
-) -> Result<Json<Domain>, Status> {
-	// Fetch the domain
-	let mut domain = conn
+	let created_domain = conn
 		.run(move |c| {
-			use db_models::schema::apps::dsl::apps;
-			use db_models::schema::domains::dsl::{domains, id as domain_id};
+			use db_models::schema::apps::dsl::{apps, slug};
+			use db_models::schema::domains::dsl::domains;
 			use db_models::schema::team_users::dsl::{team_users, user_id};
 			use db_models::schema::teams::dsl::teams;
 
-			let domain = domains
-				.inner_join(apps.inner_join(teams.inner_join(team_users)))
-				.filter(domain_id.eq(id).and(user_id.eq(user.id)))
-				.first::<(Domain, (App, (Team, TeamUser)))>(c)
+			let (app, _) = apps
+				.inner_join(teams.inner_join(team_users))
+				.filter(user_id.eq(user.id).and(slug.eq(app_slug)))
+				.first::<(App, (Team, TeamUser))>(c)
 				.map_err(|e| {
 					if e == NotFound {
 						Status::NotFound
@@ -92,32 +72,186 @@ pub async fn verify(
 					}
 				})?;
 
-			Ok(domain.0)
+			let created_domain = diesel::insert_into(domains)
+				.values(&NewDomain {
+					app_id: app.id,
+					verified: false,
+					challenge_token: generate_token(),
+					..domain.0
+				})
+				.get_result::<Domain>(c)
+				.map_err(|e| {
+					if let DatabaseError(UniqueViolation, _) = e {
+						Status::Conflict
+					} else {
+						Status::InternalServerError
+					}
+				})?;
+
+			Ok(created_domain)
 		})
 		.await?;
 
-	// Check its DNS config
-	let is_verified = verify_domain(&mut *dns.write().await, &domain.domain)
+	Ok(Json(created_domain.into()))
+}
+
+/// Updates the app's Caddy route after its domains changed. The change is already committed, so
+/// failing to do so doesn't fail the request: the domain checker tries again shortly.
+async fn sync_app_hosts(
+	provisioner_manager: &RwLock<ProvisionerManager>,
+	conn: &DbConn,
+	app_id: i32,
+) {
+	if let Err(e) = provisioner_manager
+		.read()
+		.await
+		.update_app_hosts(conn, app_id)
+		.await
+	{
+		log::error!("Failed to update the domains of app {}: {}", app_id, e);
+	}
+}
+
+/// Fetches a domain along with its app, if the user is a member of the app's team
+fn fetch_domain(id: i32, user_id: i32, c: &diesel::PgConnection) -> Result<(Domain, App), Status> {
+	use db_models::schema::apps::dsl::apps;
+	use db_models::schema::domains::dsl::{domains, id as domain_id};
+	use db_models::schema::team_users::dsl::{team_users, user_id as team_user_id};
+	use db_models::schema::teams::dsl::teams;
+
+	domains
+		.inner_join(apps.inner_join(teams.inner_join(team_users)))
+		.filter(domain_id.eq(id).and(team_user_id.eq(user_id)))
+		.first::<(Domain, (App, (Team, TeamUser)))>(c)
+		.map(|(domain, (app, _))| (domain, app))
+		.map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})
+}
+
+#[post("/domains/<id>/verify")]
+pub async fn verify(
+	id: i32,
+	user: AuthUser,
+	dns: &State<DnsClient>,
+	apps_domain: &State<AppsDomain>,
+	targets: &State<DomainTargets>,
+	conn: DbConn,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<Json<DomainWithCertificate>, Status> {
+	// Fetch the domain
+	let (mut domain, app) = conn.run(move |c| fetch_domain(id, user.id, c)).await?;
+
+	// Built-in domains are verified from the start, and have no challenge to check
+	if domain.domain == apps_domain.builtin_domain(&app.slug) {
+		return Ok(Json(domain.into()));
+	}
+
+	// Check ownership and DNS config
+	let is_verified = verify_domain(dns, targets, &domain.domain, &domain.challenge_token)
 		.await
 		.map_err(|_| Status::InternalServerError)?;
 
 	// Update accordingly
+	let was_verified = domain.verified;
+	let now = chrono::Utc::now().naive_utc();
 	domain = conn
 		.run(move |c| {
-			use db_models::schema::domains::dsl::{domains, id as domain_id, verified};
+			use db_models::schema::domains::dsl::{
+				certificate_checked_at, certificate_error, certificate_expires_at,
+				certificate_state, domains, id as domain_id, last_checked_at, last_verified_at,
+				verified,
+			};
 
-			diesel::update(domains.filter(domain_id.eq(domain.id)))
-				.set(verified.eq(is_verified))
-				.get_result::<Domain>(c)
-				.map_err(|e| {
-					if let DatabaseError(UniqueViolation, _) = e {
-						Status::Conflict
-					} else {
-						Status::InternalServerError
-					}
-				})
+			c.transaction(|| {
+				let updated_domain = diesel::update(domains.filter(domain_id.eq(domain.id)))
+					.set((
+						verified.eq(is_verified),
+						last_checked_at.eq(now),
+						last_verified_at.eq(if is_verified {
+							Some(now)
+						} else {
+							domain.last_verified_at
+						}),
+					))
+					.get_result::<Domain>(c)?;
+				// Start or stop routing the domain to the app. Also done when it stays verified, so
+				// that verifying again fixes routing if updating it failed the first time.
+				if is_verified || was_verified {
+					request_hosts_sync(updated_domain.app_id, c)?;
+				}
+				if !is_verified || was_verified {
+					return Ok(updated_domain);
+				}
+
+				// Whatever was served before the domain was last unverified is stale, the domain
+				// checker probes it again shortly
+				diesel::update(&updated_domain)
+					.set((
+						certificate_state.eq(None::<CertificateState>),
+						certificate_expires_at.eq(None::<NaiveDateTime>),
+						certificate_error.eq(None::<String>),
+						certificate_checked_at.eq(None::<NaiveDateTime>),
+					))
+					.get_result::<Domain>(c)
+			})
+			.map_err(|e| {
+				if let DatabaseError(UniqueViolation, _) = e {
+					Status::Conflict
+				} else {
+					Status::InternalServerError
+				}
+			})
+		})
+		.await?;
+
+	if domain.verified || was_verified {
+		sync_app_hosts(provisioner_manager, &conn, domain.app_id).await;
+	}
+
+	Ok(Json(domain.into()))
+}
+
+#[delete("/domains/<id>")]
+pub async fn delete(
+	id: i32,
+	user: AuthUser,
+	conn: DbConn,
+	apps_domain: &State<AppsDomain>,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<NoContent, Status> {
+	let apps_domain = apps_domain.inner().clone();
+
+	let domain = conn
+		.run(move |c| {
+			let (domain, app) = fetch_domain(id, user.id, c)?;
+
+			// Apps must keep the domain they were created with
+			if domain.domain == apps_domain.builtin_domain(&app.slug) {
+				return Err(Status::Forbidden);
+			}
+
+			c.transaction(|| {
+				diesel::delete(&domain).execute(c)?;
+				// Stop routing the domain to the app
+				if domain.verified {
+					request_hosts_sync(domain.app_id, c)?;
+				}
+				Ok::<_, diesel::result::Error>(())
+			})
+			.map_err(|_| Status::InternalServerError)?;
+
+			Ok(domain)
 		})
 		.await?;
 
-	Ok(Json(domain))
+	if domain.verified {
+		sync_app_hosts(provisioner_manager, &conn, domain.app_id).await;
+	}
+
+	Ok(NoContent)
 }
@@ -0,0 +1,256 @@
+use diesel::{prelude::*, result::Error::NotFound};
+use rocket::{
+	data::{Data, ToByteUnit},
+	http::{HeaderMap, Status},
+	request::{self, FromRequest, Outcome, Request},
+	serde::json::Json,
+	tokio::sync::RwLock,
+	State,
+};
+use serde::Deserialize;
+
+use db_models::{App, Build, BuildStatus};
+
+use crate::{
+	provision::{ProvisionerManager, Uri},
+	utils::{
+		crypto::{Encryptor, SecretColumn},
+		webhook::{verify_signature, verify_token},
+	},
+	DbConn,
+};
+
+/// `after` of pushes deleting a branch
+const NULL_SHA: &str = "0000000000000000000000000000000000000000";
+
+/// The headers of a webhook delivery, which carry its event type and signature
+pub struct HookHeaders<'r>(&'r HeaderMap<'r>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for HookHeaders<'r> {
+	type Error = ();
+
+	async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+		Outcome::Success(HookHeaders(req.headers()))
+	}
+}
+
+/// The fields of a push event shared by GitHub, Gitea and GitLab
+#[derive(Deserialize, Debug, Clone)]
+struct PushEvent {
+	#[serde(rename = "ref")]
+	git_ref: String,
+	after: String,
+	/// Only sent by GitLab, `after` may not be the commit to deploy there
+	#[serde(default)]
+	checkout_sha: Option<String>,
+}
+
+#[derive(Responder)]
+#[allow(clippy::large_enum_variant)]
+pub enum HookResponse {
+	#[response(status = 202)]
+	Deploying(Json<Build>),
+	/// Deliveries that don't call for a deploy, like pings or pushes to other branches
+	#[response(status = 204)]
+	Ignored(()),
+}
+
+async fn read_body(data: Data<'_>) -> Result<Vec<u8>, Status> {
+	// GitHub caps payloads at 25MB
+	let body = data
+		.open(25.mebibytes())
+		.into_bytes()
+		.await
+		.map_err(|_| Status::BadRequest)?;
+	if !body.is_complete() {
+		return Err(Status::PayloadTooLarge);
+	}
+
+	Ok(body.into_inner())
+}
+
+/// Checks a delivery with `verify` against the app's webhook secret, then queues a build if it
+/// is a push to the app's branch
+async fn handle_delivery(
+	app_id: i32,
+	body: Vec<u8>,
+	verify: impl FnOnce(&str, &[u8]) -> bool,
+	is_push: bool,
+	conn: DbConn,
+	encryptor: &Encryptor,
+	provisioner_manager: &RwLock<ProvisionerManager>,
+) -> Result<HookResponse, Status> {
+	let app = conn
+		.run(move |c| {
+			use db_models::schema::apps::dsl::apps;
+
+			apps.find(app_id).first::<App>(c).map_err(|e| {
+				if e == NotFound {
+					Status::NotFound
+				} else {
+					Status::InternalServerError
+				}
+			})
+		})
+		.await?;
+	let secret = encryptor
+		.decrypt(
+			app.webhook_secret.as_deref().ok_or(Status::NotFound)?,
+			SecretColumn::WebhookSecret,
+			app.id,
+		)
+		.map_err(|_| Status::InternalServerError)?;
+	if !verify(&secret, &body) {
+		return Err(Status::Unauthorized);
+	}
+
+	// Stopped apps are only deployed again once started
+	if !is_push || !app.enabled {
+		return Ok(HookResponse::Ignored(()));
+	}
+	let push =
+		serde_json::from_slice::<PushEvent>(&body).map_err(|_| Status::UnprocessableEntity)?;
+	let (git_repository, git_branch) = match (&app.git_repository, &app.git_branch) {
+		(Some(repository), Some(branch)) => (repository.clone(), branch),
+		_ => return Ok(HookResponse::Ignored(())),
+	};
+	if push.git_ref != format!("refs/heads/{}", git_branch) || push.after == NULL_SHA {
+		return Ok(HookResponse::Ignored(()));
+	}
+	let commit = push.checkout_sha.unwrap_or(push.after);
+	if !provisioner::is_valid_git_ref(&commit) {
+		return Err(Status::UnprocessableEntity);
+	}
+	let git_uri = git_repository
+		.parse::<Uri>()
+		.map_err(|_| Status::InternalServerError)?;
+
+	// Pushes in quick succession only need the latest one to be deployed. Builds queued by users
+	// are left alone, since they may be of another repository or ref.
+	let queued_build = conn
+		.run({
+			let git_repository = git_repository.clone();
+			let commit = commit.clone();
+			move |c| {
+				use db_models::schema::builds::dsl;
+
+				diesel::update(
+					dsl::builds.filter(
+						dsl::app_id
+							.eq(app_id)
+							.and(dsl::status.eq(BuildStatus::Queued))
+							.and(dsl::from_webhook.eq(true)),
+					),
+				)
+				.set((
+					dsl::git_repository.eq(git_repository),
+					dsl::git_ref.eq(commit),
+				))
+				.get_result::<Build>(c)
+				.optional()
+				.map_err(|_| Status::InternalServerError)
+			}
+		})
+		.await?;
+	if let Some(build) = queued_build {
+		return Ok(HookResponse::Deploying(Json(build)));
+	}
+
+	provisioner_manager
+		.read()
+		.await
+		.create_build(conn, git_uri, Some(commit), true, &app)
+		.await
+		.map(|build| HookResponse::Deploying(Json(build)))
+		.map_err(|_| Status::InternalServerError)
+}
+
+/// Strips the `sha256=` prefix GitHub puts before signatures
+fn github_signature<'a>(headers: &'a HeaderMap<'_>) -> Option<&'a str> {
+	headers
+		.get_one("X-Hub-Signature-256")
+		.and_then(|s| s.strip_prefix("sha256="))
+}
+
+#[post("/hooks/github/<app_id>", data = "<data>")]
+pub async fn github(
+	app_id: i32,
+	headers: HookHeaders<'_>,
+	data: Data<'_>,
+	conn: DbConn,
+	encryptor: &State<Encryptor>,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<HookResponse, Status> {
+	let signature = github_signature(headers.0).ok_or(Status::Unauthorized)?;
+	let is_push = headers.0.get_one("X-GitHub-Event") == Some("push");
+	let body = read_body(data).await?;
+
+	handle_delivery(
+		app_id,
+		body,
+		|secret, body| verify_signature(secret, body, signature),
+		is_push,
+		conn,
+		encryptor,
+		provisioner_manager,
+	)
+	.await
+}
+
+#[post("/hooks/gitea/<app_id>", data = "<data>")]
+pub async fn gitea(
+	app_id: i32,
+	headers: HookHeaders<'_>,
+	data: Data<'_>,
+	conn: DbConn,
+	encryptor: &State<Encryptor>,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<HookResponse, Status> {
+	let signature = headers
+		.0
+		.get_one("X-Gitea-Signature")
+		.ok_or(Status::Unauthorized)?;
+	let is_push = headers.0.get_one("X-Gitea-Event") == Some("push");
+	let body = read_body(data).await?;
+
+	handle_delivery(
+		app_id,
+		body,
+		|secret, body| verify_signature(secret, body, signature),
+		is_push,
+		conn,
+		encryptor,
+		provisioner_manager,
+	)
+	.await
+}
+
+/// GitLab doesn't sign deliveries, and sends the secret itself instead
+#[post("/hooks/gitlab/<app_id>", data = "<data>")]
+pub async fn gitlab(
+	app_id: i32,
+	headers: HookHeaders<'_>,
+	data: Data<'_>,
+	conn: DbConn,
+	encryptor: &State<Encryptor>,
+	provisioner_manager: &State<RwLock<ProvisionerManager>>,
+) -> Result<HookResponse, Status> {
+	let token = headers
+		.0
+		.get_one("X-Gitlab-Token")
+		.ok_or(Status::Unauthorized)?;
+	let is_push = headers.0.get_one("X-Gitlab-Event") == Some("Push Hook");
+	let body = read_body(data).await?;
+
+	handle_delivery(
+		app_id,
+		body,
+		|secret, _| verify_token(secret, token),
+		is_push,
+		conn,
+		encryptor,
+		provisioner_manager,
+	)
+	.await
+}
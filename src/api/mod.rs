@@ -3,7 +3,9 @@ pub mod auth;
 pub mod builds;
 pub mod dev;
 pub mod domains;
+pub mod hooks;
 pub mod invites;
 pub mod oauth;
+pub mod tasks;
 pub mod teams;
 pub mod users;
@@ -0,0 +1,270 @@
+use chrono::Utc;
+use diesel::{
+	prelude::*,
+	result::{
+		DatabaseErrorKind::UniqueViolation,
+		Error::{DatabaseError, NotFound},
+	},
+};
+use rocket::{http::Status, response::status::NoContent, serde::json::Json, State};
+
+use db_models::{ConcurrencyPolicy, NewTaskSchedule, Run, TaskSchedule};
+
+use crate::{
+	api::apps::fetch_app,
+	auth::AuthUser,
+	tasks::{next_run_at, parse_cron, TaskQueue},
+	DbConn,
+};
+
+/// Maximum number of runs returned when listing an app's runs
+const MAX_RUNS: i64 = 100;
+
+#[derive(serde::Serialize)]
+pub struct RunResponse {
+	#[serde(flatten)]
+	run: Run,
+	/// How long the run took, in seconds, once it ended
+	duration_secs: Option<i64>,
+	/// Left out of listings, since logs can be long
+	#[serde(skip_serializing_if = "Option::is_none")]
+	logs: Option<String>,
+}
+
+impl RunResponse {
+	fn new(run: Run, with_logs: bool) -> Self {
+		Self {
+			duration_secs: run.duration_secs(),
+			logs: if with_logs {
+				Some(run.logs.clone())
+			} else {
+				None
+			},
+			run,
+		}
+	}
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct NewRunRequest {
+	command: String,
+}
+
+/// Queues a one-off run of a command, in a container from the image the app is running
+#[post("/apps/<app_slug>/runs", data = "<run>")]
+pub async fn create_run(
+	app_slug: String,
+	user: AuthUser,
+	run: Json<NewRunRequest>,
+	conn: DbConn,
+	queue: &State<TaskQueue>,
+) -> Result<(Status, Json<RunResponse>), Status> {
+	if run.command.trim().is_empty() {
+		return Err(Status::UnprocessableEntity);
+	}
+
+	let app = conn
+		.run(move |c| {
+			fetch_app(app_slug, user.id, c).map_err(|e| {
+				if e == NotFound {
+					Status::NotFound
+				} else {
+					Status::InternalServerError
+				}
+			})
+		})
+		.await?;
+	// Runs need the image and network of a running app
+	if !app.enabled || app.container_id.is_none() {
+		return Err(Status::Conflict);
+	}
+
+	let run = queue
+		.create_run(&conn, app.id, run.0.command)
+		.await
+		.map_err(|_| Status::InternalServerError)?;
+	Ok((Status::Accepted, Json(RunResponse::new(run, false))))
+}
+
+/// Lists the app's latest runs, newest first
+#[get("/apps/<app_slug>/runs")]
+pub async fn runs(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+) -> Result<Json<Vec<RunResponse>>, Status> {
+	conn.run(move |c| {
+		use db_models::schema::runs::dsl::id;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		let runs = Run::belonging_to(&app)
+			.order(id.desc())
+			.limit(MAX_RUNS)
+			.load::<Run>(c)
+			.map_err(|_| Status::InternalServerError)?;
+
+		Ok(Json(
+			runs.into_iter()
+				.map(|run| RunResponse::new(run, false))
+				.collect(),
+		))
+	})
+	.await
+}
+
+#[get("/apps/<app_slug>/runs/<run_id>")]
+pub async fn run(
+	app_slug: String,
+	run_id: i32,
+	user: AuthUser,
+	conn: DbConn,
+) -> Result<Json<RunResponse>, Status> {
+	conn.run(move |c| {
+		use db_models::schema::runs::dsl::id;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		Run::belonging_to(&app)
+			.filter(id.eq(run_id))
+			.first::<Run>(c)
+			.map(|run| Json(RunResponse::new(run, true)))
+			.map_err(|e| {
+				if e == NotFound {
+					Status::NotFound
+				} else {
+					Status::InternalServerError
+				}
+			})
+	})
+	.await
+}
+
+#[get("/apps/<app_slug>/schedules")]
+pub async fn schedules(
+	app_slug: String,
+	user: AuthUser,
+	conn: DbConn,
+) -> Result<Json<Vec<TaskSchedule>>, Status> {
+	conn.run(move |c| {
+		use db_models::schema::task_schedules::dsl::name;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		TaskSchedule::belonging_to(&app)
+			.order(name.asc())
+			.load::<TaskSchedule>(c)
+			.map(Json)
+			.map_err(|_| Status::InternalServerError)
+	})
+	.await
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct NewSchedule {
+	name: String,
+	command: String,
+	cron: String,
+	#[serde(default)]
+	concurrency_policy: ConcurrencyPolicy,
+}
+
+#[post("/apps/<app_slug>/schedules", data = "<schedule>")]
+pub async fn create_schedule(
+	app_slug: String,
+	user: AuthUser,
+	schedule: Json<NewSchedule>,
+	conn: DbConn,
+) -> Result<Json<TaskSchedule>, Status> {
+	let schedule = schedule.0;
+	if schedule.name.trim().is_empty() || schedule.command.trim().is_empty() {
+		return Err(Status::UnprocessableEntity);
+	}
+	// Expressions that never match again are rejected as well
+	let next = parse_cron(&schedule.cron)
+		.ok()
+		.and_then(|cron| next_run_at(&cron, Utc::now().naive_utc()))
+		.ok_or(Status::UnprocessableEntity)?;
+
+	conn.run(move |c| {
+		use db_models::schema::task_schedules::dsl::task_schedules;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		diesel::insert_into(task_schedules)
+			.values(NewTaskSchedule {
+				app_id: app.id,
+				name: schedule.name,
+				command: schedule.command,
+				cron: schedule.cron,
+				concurrency_policy: schedule.concurrency_policy,
+				next_run_at: Some(next),
+			})
+			.get_result::<TaskSchedule>(c)
+			.map(Json)
+			.map_err(|e| {
+				if let DatabaseError(UniqueViolation, _) = e {
+					Status::Conflict
+				} else {
+					Status::InternalServerError
+				}
+			})
+	})
+	.await
+}
+
+/// Deletes a schedule. Its past runs are kept.
+#[delete("/apps/<app_slug>/schedules/<schedule_id>")]
+pub async fn delete_schedule(
+	app_slug: String,
+	schedule_id: i32,
+	user: AuthUser,
+	conn: DbConn,
+) -> Result<NoContent, Status> {
+	conn.run(move |c| {
+		use db_models::schema::task_schedules::dsl::id;
+
+		let app = fetch_app(app_slug, user.id, c).map_err(|e| {
+			if e == NotFound {
+				Status::NotFound
+			} else {
+				Status::InternalServerError
+			}
+		})?;
+
+		let deleted = diesel::delete(TaskSchedule::belonging_to(&app).filter(id.eq(schedule_id)))
+			.execute(c)
+			.map_err(|_| Status::InternalServerError)?;
+
+		if deleted == 0 {
+			return Err(Status::NotFound);
+		}
+
+		Ok(NoContent)
+	})
+	.await
+}
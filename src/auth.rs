@@ -30,6 +30,18 @@ impl AuthUser {
 	}
 }
 
+/// Loads the user an unexpired token belongs to
+pub fn user_from_token(t: String, c: &PgConnection) -> QueryResult<User> {
+	use db_models::schema::tokens::dsl::*;
+	use db_models::schema::users::dsl::*;
+
+	tokens
+		.filter(token.eq(t).and(expires_at.gt(diesel::dsl::now)))
+		.inner_join(users)
+		.first::<(Token, User)>(c)
+		.map(|e| e.1)
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for AuthUser {
 	type Error = ();
@@ -50,18 +62,7 @@ impl<'r> FromRequest<'r> for AuthUser {
 				Some(_t) => {
 					let t = _t.to_owned();
 
-					let user = conn
-						.run(|c| {
-							use db_models::schema::tokens::dsl::*;
-							use db_models::schema::users::dsl::*;
-
-							tokens
-								.filter(token.eq(t).and(expires_at.gt(diesel::dsl::now)))
-								.inner_join(users)
-								.first::<(Token, User)>(c)
-								.map(|e| e.1)
-						})
-						.await;
+					let user = conn.run(|c| user_from_token(t, c)).await;
 
 					match user {
 						Ok(u) => Outcome::Success(AuthUser::new(u)),
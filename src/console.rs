@@ -0,0 +1,419 @@
+use std::{borrow::Cow, sync::Arc, time::Duration};
+
+use db_models::{App, ConsoleSession, NewConsoleSession, User};
+use diesel::{prelude::*, result::Error::NotFound};
+use provisioner::Console;
+use rocket::futures::{SinkExt, StreamExt};
+use tokio::{
+	io::AsyncWriteExt,
+	net::{TcpListener, TcpStream},
+};
+use tokio_tungstenite::{
+	accept_hdr_async,
+	tungstenite::{
+		handshake::server::{ErrorResponse, Request, Response},
+		http::StatusCode,
+		protocol::{frame::coding::CloseCode, CloseFrame},
+		Message,
+	},
+	WebSocketStream,
+};
+
+use crate::{
+	api::apps::fetch_app, auth::user_from_token, provision::ProvisionerManager,
+	utils::db_pool::DbPool,
+};
+
+/// How often open sessions are marked as alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// Open sessions without a heartbeat for this long were cut off, e.g. by an API restart
+const HEARTBEAT_TIMEOUT_SECS: i64 = 60;
+
+/// Rocket can't upgrade connections to WebSockets, so consoles are served on their own port. Only
+/// `/api/apps/<slug>/console` is served there, which the reverse proxy should route upgrades of.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ConsoleConfig {
+	port: u16,
+	/// Sessions without any input or output for this long are closed
+	idle_timeout_secs: u64,
+	/// Origins, besides the API's own, that browsers may open consoles from, e.g.
+	/// `https://dashboard.example.com`
+	allowed_origins: Vec<String>,
+}
+
+impl Default for ConsoleConfig {
+	fn default() -> Self {
+		Self {
+			port: 5001,
+			idle_timeout_secs: 15 * 60,
+			allowed_origins: Vec::new(),
+		}
+	}
+}
+
+impl ConsoleConfig {
+	pub fn from_figment(f: &rocket::figment::Figment) -> Self {
+		f.focus("console")
+			.extract::<Self>()
+			.expect("Failed to extract console config from figment")
+	}
+}
+
+/// Control messages, sent by clients as text frames. Binary frames are typed into the shell as is.
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ConsoleMessage {
+	Input { data: String },
+	Resize { cols: u16, rows: u16 },
+}
+
+/// Browsers don't expose the status of failed handshakes, so sessions that can't be opened are
+/// closed right after the upgrade instead, with 4000 + the HTTP status that would apply
+fn close_frame(code: u16, reason: &'static str) -> Message {
+	Message::Close(Some(CloseFrame {
+		code: CloseCode::Library(code),
+		reason: Cow::Borrowed(reason),
+	}))
+}
+
+/// Checks the `Origin` browsers send along with WebSocket handshakes. Browsers also send cookies
+/// along with handshakes started by any other site, so only the API's own origin and the allowed
+/// ones may use them. Clients that aren't browsers send no origin.
+fn is_allowed_origin(req: &Request, allowed_origins: &[String]) -> bool {
+	let origin = match req.headers().get("Origin").map(|h| h.to_str()) {
+		None => return true,
+		Some(Ok(origin)) => origin,
+		Some(Err(_)) => return false,
+	};
+	if allowed_origins.iter().any(|allowed| allowed == origin) {
+		return true;
+	}
+
+	let origin_host = origin
+		.strip_prefix("https://")
+		.or_else(|| origin.strip_prefix("http://"));
+	let host = req.headers().get("Host").and_then(|h| h.to_str().ok());
+	matches!((origin_host, host), (Some(o), Some(h)) if o.eq_ignore_ascii_case(h))
+}
+
+/// Ends the sessions whose server stopped sending heartbeats as of their last heartbeat, so that
+/// they don't stay open forever in the audit trail
+fn end_orphaned_sessions(c: &PgConnection) -> QueryResult<usize> {
+	use db_models::schema::console_sessions::dsl::{console_sessions, ended_at, heartbeat_at};
+	use diesel::sql_types::{Nullable, Timestamp};
+
+	let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::seconds(HEARTBEAT_TIMEOUT_SECS);
+
+	diesel::update(console_sessions)
+		.filter(
+			ended_at
+				.is_null()
+				.and(heartbeat_at.is_null().or(heartbeat_at.lt(cutoff))),
+		)
+		.set(ended_at.eq(diesel::dsl::sql::<Nullable<Timestamp>>(
+			"COALESCE(heartbeat_at, started_at)",
+		)))
+		.execute(c)
+}
+
+/// Reads the token of the `haas_token` cookie
+fn token_from_cookies(cookies: &str) -> Option<&str> {
+	cookies
+		.split(';')
+		.find_map(|cookie| cookie.trim().strip_prefix("haas_token="))
+}
+
+pub struct ConsoleServer {
+	provisioner_manager: ProvisionerManager,
+	config: ConsoleConfig,
+	pool: DbPool,
+}
+
+impl ConsoleServer {
+	pub fn new(
+		provisioner_manager: ProvisionerManager,
+		config: ConsoleConfig,
+		pool: DbPool,
+	) -> Self {
+		Self {
+			provisioner_manager,
+			config,
+			pool,
+		}
+	}
+
+	pub async fn run(self, address: std::net::IpAddr) {
+		match self.pool.run(end_orphaned_sessions).await {
+			Ok(0) => {}
+			Ok(n) => log::warn!("Ended {} orphaned console session(s)", n),
+			Err(e) => log::error!("Failed to end orphaned console sessions: {}", e),
+		}
+
+		let listener = TcpListener::bind((address, self.config.port))
+			.await
+			.expect("Failed to bind the console listener");
+		log::info!("Serving consoles on {}:{}", address, self.config.port);
+
+		let server = Arc::new(self);
+		loop {
+			match listener.accept().await {
+				Ok((stream, _)) => {
+					tokio::spawn(Arc::clone(&server).handle(stream));
+				}
+				Err(e) => log::error!("Failed to accept a console connection: {}", e),
+			}
+		}
+	}
+
+	async fn handle(self: Arc<Self>, stream: TcpStream) {
+		let mut slug = None;
+		let mut token = None;
+		let ws = accept_hdr_async(stream, |req: &Request, res: Response| {
+			let path = req.uri().path();
+			match path
+				.strip_prefix("/api/apps/")
+				.and_then(|p| p.strip_suffix("/console"))
+				.filter(|s| !s.is_empty() && !s.contains('/'))
+			{
+				Some(s) => slug = Some(s.to_owned()),
+				None => {
+					let mut res = ErrorResponse::new(Some("Not found".to_owned()));
+					*res.status_mut() = StatusCode::NOT_FOUND;
+					return Err(res);
+				}
+			}
+
+			if !is_allowed_origin(req, &self.config.allowed_origins) {
+				let mut res = ErrorResponse::new(Some("Origin not allowed".to_owned()));
+				*res.status_mut() = StatusCode::FORBIDDEN;
+				return Err(res);
+			}
+
+			// Same as AuthUser, browsers send the cookie along with WebSocket handshakes
+			let headers = req.headers();
+			token = headers
+				.get("Authorization")
+				.and_then(|h| h.to_str().ok())
+				.and_then(|h| h.strip_prefix("Bearer "))
+				.or_else(|| {
+					headers
+						.get_all("Cookie")
+						.iter()
+						.filter_map(|h| h.to_str().ok())
+						.find_map(token_from_cookies)
+				})
+				.map(|t| t.to_owned());
+
+			Ok(res)
+		})
+		.await;
+
+		let mut ws = match ws {
+			Ok(ws) => ws,
+			Err(e) => {
+				log::debug!("Console handshake failed: {}", e);
+				return;
+			}
+		};
+		let slug = slug.expect("Handshake succeeded without a slug");
+
+		let (user, app) = match self.authorize(slug, token).await {
+			Ok(x) => x,
+			Err(close) => {
+				let _ = ws.send(close).await;
+				return;
+			}
+		};
+		let container_id = match (&app.container_id, app.enabled) {
+			(Some(container_id), true) => container_id.clone(),
+			_ => {
+				let _ = ws.send(close_frame(4409, "App is not running")).await;
+				return;
+			}
+		};
+
+		let console = match self.provisioner_manager.open_console(&container_id).await {
+			Ok(console) => console,
+			Err(e) => {
+				log::error!("Failed to open a console into app {}: {}", app.id, e);
+				let _ = ws.send(close_frame(4500, "Failed to open console")).await;
+				return;
+			}
+		};
+
+		let (app_id, user_id) = (app.id, user.id);
+		let new_session = NewConsoleSession {
+			app_id,
+			user_id,
+			app_slug: app.slug.clone(),
+			user_name: user.name.clone(),
+		};
+		let session = self
+			.pool
+			.run(move |c| {
+				use db_models::schema::console_sessions::dsl::{console_sessions, heartbeat_at};
+				diesel::insert_into(console_sessions)
+					.values((new_session, heartbeat_at.eq(diesel::dsl::now)))
+					.get_result::<ConsoleSession>(c)
+			})
+			.await;
+		let session = match session {
+			Ok(session) => session,
+			Err(e) => {
+				// Sessions must not go unaudited
+				log::error!(
+					"Failed to record a console session into app {}: {}",
+					app_id,
+					e
+				);
+				let _ = ws.send(close_frame(4500, "Failed to open console")).await;
+				return;
+			}
+		};
+		log::info!(
+			"User {} opened console session {} into app {}",
+			user_id,
+			session.id,
+			app_id
+		);
+
+		// Keep the session from being ended as orphaned while it is open
+		let session_id = session.id;
+		let pool = self.pool.clone();
+		let heartbeat = tokio::spawn(async move {
+			loop {
+				tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+				let res = pool
+					.run(move |c| {
+						use db_models::schema::console_sessions::dsl::{
+							console_sessions, heartbeat_at,
+						};
+						diesel::update(console_sessions.find(session_id))
+							.set(heartbeat_at.eq(diesel::dsl::now))
+							.execute(c)
+					})
+					.await;
+				if let Err(e) = res {
+					log::error!(
+						"Failed to record heartbeat for console session {}: {}",
+						session_id,
+						e
+					);
+				}
+			}
+		});
+
+		self.attach(ws, console).await;
+		heartbeat.abort();
+
+		let ended = self
+			.pool
+			.run(move |c| {
+				use db_models::schema::console_sessions::dsl::{console_sessions, ended_at};
+				diesel::update(console_sessions.find(session_id))
+					.set(ended_at.eq(diesel::dsl::now))
+					.execute(c)
+			})
+			.await;
+		if let Err(e) = ended {
+			log::error!(
+				"Failed to record the end of console session {}: {}",
+				session_id,
+				e
+			);
+		}
+	}
+
+	/// Checks that the token is valid, and that its user is a member of the app's team
+	async fn authorize(&self, slug: String, token: Option<String>) -> Result<(User, App), Message> {
+		let token = token.ok_or_else(|| close_frame(4401, "Unauthorized"))?;
+
+		self.pool
+			.run(move |c| {
+				let user = match user_from_token(token, c) {
+					Ok(user) => user,
+					Err(NotFound) => return Ok(Err(close_frame(4401, "Unauthorized"))),
+					Err(e) => return Err(e),
+				};
+				let app = match fetch_app(slug, user.id, c) {
+					Ok(app) => app,
+					Err(NotFound) => return Ok(Err(close_frame(4404, "App not found"))),
+					Err(e) => return Err(e),
+				};
+
+				Ok(Ok((user, app)))
+			})
+			.await
+			.unwrap_or_else(|e| {
+				log::error!("Failed to authorize a console session: {}", e);
+				Err(close_frame(4500, "Internal server error"))
+			})
+	}
+
+	/// Relays the WebSocket to the shell until either side closes or the session goes idle
+	async fn attach(&self, ws: WebSocketStream<TcpStream>, console: Console) {
+		let Console {
+			exec_id,
+			mut output,
+			mut input,
+		} = console;
+		let (mut ws_tx, mut ws_rx) = ws.split();
+		let idle_timeout = Duration::from_secs(self.config.idle_timeout_secs);
+
+		let close = loop {
+			tokio::select! {
+				msg = ws_rx.next() => {
+					let res = match msg {
+						Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break None,
+						Some(Ok(Message::Binary(data))) => input.write_all(&data).await.map_err(|e| e.to_string()),
+						Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+							Ok(ConsoleMessage::Input { data }) => {
+								input.write_all(data.as_bytes()).await.map_err(|e| e.to_string())
+							}
+							Ok(ConsoleMessage::Resize { cols, rows }) => self
+								.provisioner_manager
+								.resize_console(&exec_id, cols, rows)
+								.await
+								.map_err(|e| e.to_string()),
+							Err(e) => {
+								log::debug!("Ignoring invalid console message: {}", e);
+								Ok(())
+							}
+						},
+						// Pings are answered by tungstenite
+						Some(Ok(_)) => Ok(()),
+					};
+					if let Err(e) = res {
+						log::error!("Failed to relay console input: {}", e);
+						break Some(close_frame(4500, "Failed to relay input"));
+					}
+				}
+				out = output.next() => match out {
+					Some(Ok(data)) => {
+						if ws_tx.send(Message::Binary(data)).await.is_err() {
+							break None;
+						}
+					}
+					Some(Err(e)) => {
+						log::error!("Failed to read console output: {}", e);
+						break Some(close_frame(4500, "Failed to read output"));
+					}
+					None => break Some(Message::Close(Some(CloseFrame {
+						code: CloseCode::Normal,
+						reason: Cow::Borrowed("Shell exited"),
+					}))),
+				},
+				_ = tokio::time::sleep(idle_timeout) => {
+					break Some(close_frame(4408, "Session timed out"));
+				}
+			}
+		};
+
+		if let Some(close) = close {
+			let _ = ws_tx.send(close).await;
+		}
+		// Hangs up the shell, in case it is still running
+		let _ = input.shutdown().await;
+	}
+}
@@ -0,0 +1,311 @@
+use std::time::Duration;
+
+use db_models::{CertificateState, Domain};
+use diesel::prelude::*;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+	provision::{request_hosts_sync, ProvisionerManager},
+	utils::{
+		certificate::certificate_expiry,
+		db_pool::DbPool,
+		dns::DnsClient,
+		domain::{points_to_us, AppsDomain, DomainTargets},
+	},
+};
+
+/// How often verified domains and their certificates are checked again, and for how long their DNS
+/// records may not point to us before they are unverified
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DomainChecksConfig {
+	interval_secs: u64,
+	grace_period_secs: i64,
+	/// Certificates that aren't issued yet are checked more often, so that they show up as issued
+	/// shortly after Caddy obtains them
+	pending_certificate_interval_secs: u64,
+}
+
+impl Default for DomainChecksConfig {
+	fn default() -> Self {
+		Self {
+			interval_secs: 60 * 60,
+			grace_period_secs: 3 * 24 * 60 * 60,
+			pending_certificate_interval_secs: 60,
+		}
+	}
+}
+
+impl DomainChecksConfig {
+	pub fn from_figment(f: &rocket::figment::Figment) -> Self {
+		f.focus("domain_checks")
+			.extract::<Self>()
+			.expect("Failed to extract domain checks config from figment")
+	}
+}
+
+/// Loads the verified custom domains that were not checked for an interval, by any instance
+fn domains_due_for_check(
+	interval_secs: u64,
+	builtin_suffix: String,
+	c: &PgConnection,
+) -> QueryResult<Vec<Domain>> {
+	use db_models::schema::apps::dsl::{apps, slug};
+	use db_models::schema::domains::dsl::{domain, domains, last_checked_at, verified};
+
+	let checked_before =
+		chrono::Utc::now().naive_utc() - chrono::Duration::seconds(interval_secs as i64);
+
+	domains
+		.inner_join(apps)
+		.filter(verified.eq(true))
+		// Built-in domains are ours, and always stay verified
+		.filter(domain.ne(slug.concat(builtin_suffix)))
+		.filter(
+			last_checked_at
+				.is_null()
+				.or(last_checked_at.lt(checked_before)),
+		)
+		.select(db_models::schema::domains::all_columns)
+		.load(c)
+}
+
+/// Loads the apps whose domains changed since their Caddy route was last updated
+fn apps_due_for_hosts_sync(c: &PgConnection) -> QueryResult<Vec<i32>> {
+	use db_models::schema::apps::dsl::{apps, hosts_sync_requested_at, id};
+
+	apps.filter(hosts_sync_requested_at.is_not_null())
+		.select(id)
+		.load(c)
+}
+
+/// Loads the verified domains, built-in ones included, whose certificate was not checked for an
+/// interval, by any instance
+fn certificates_due_for_check(
+	interval_secs: u64,
+	pending_interval_secs: u64,
+	c: &PgConnection,
+) -> QueryResult<Vec<Domain>> {
+	use db_models::schema::domains::dsl::{
+		certificate_checked_at, certificate_state, domains, verified,
+	};
+
+	let now = chrono::Utc::now().naive_utc();
+	let checked_before = now - chrono::Duration::seconds(interval_secs as i64);
+	let pending_checked_before = now - chrono::Duration::seconds(pending_interval_secs as i64);
+
+	domains
+		.filter(verified.eq(true))
+		.filter(
+			certificate_checked_at
+				.is_null()
+				.or(certificate_checked_at.lt(checked_before))
+				.or(certificate_state
+					.ne(CertificateState::Issued)
+					.and(certificate_checked_at.lt(pending_checked_before))),
+		)
+		.load(c)
+}
+
+/// Periodically checks that verified domains still point to us, and unverifies the ones that
+/// stopped doing so for longer than the grace period. Also records the certificate Caddy obtained
+/// for each verified domain, since reading it takes too long to be done when domains are listed.
+pub struct DomainChecker {
+	dns: DnsClient,
+	targets: DomainTargets,
+	apps_domain: AppsDomain,
+	provisioner_manager: ProvisionerManager,
+	config: DomainChecksConfig,
+}
+
+impl DomainChecker {
+	pub fn new(
+		dns: DnsClient,
+		targets: DomainTargets,
+		apps_domain: AppsDomain,
+		provisioner_manager: ProvisionerManager,
+		config: DomainChecksConfig,
+	) -> Self {
+		Self {
+			dns,
+			targets,
+			apps_domain,
+			provisioner_manager,
+			config,
+		}
+	}
+
+	pub async fn run(self, pool: DbPool) {
+		let interval_secs = self.config.interval_secs;
+		let pending_interval_secs = self.config.pending_certificate_interval_secs;
+		// Passes start on a fixed schedule, however long the previous one took
+		let mut interval = tokio::time::interval(Duration::from_secs(
+			interval_secs.min(pending_interval_secs),
+		));
+		interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		loop {
+			interval.tick().await;
+
+			// Domain changes whose route update failed
+			match pool.run(apps_due_for_hosts_sync).await {
+				Ok(due) => {
+					for app_id in due {
+						if let Err(e) = self
+							.provisioner_manager
+							.update_app_hosts(&pool, app_id)
+							.await
+						{
+							log::error!("Failed to update the domains of app {}: {}", app_id, e);
+						}
+					}
+				}
+				Err(e) => log::error!("Failed to load apps to update the domains of: {}", e),
+			}
+
+			let builtin_suffix = self.apps_domain.suffix();
+			match pool
+				.run(move |c| domains_due_for_check(interval_secs, builtin_suffix, c))
+				.await
+			{
+				Ok(due) => {
+					for domain in due {
+						self.check(&pool, domain).await;
+					}
+				}
+				Err(e) => log::error!("Failed to load domains to check: {}", e),
+			}
+
+			// After the DNS checks, so that domains unverified by them aren't probed
+			match pool
+				.run(move |c| certificates_due_for_check(interval_secs, pending_interval_secs, c))
+				.await
+			{
+				Ok(due) => {
+					for domain in due {
+						self.check_certificate(&pool, domain).await;
+					}
+				}
+				Err(e) => log::error!("Failed to load certificates to check: {}", e),
+			}
+		}
+	}
+
+	async fn check_certificate(&self, pool: &DbPool, domain: Domain) {
+		let pem = match self
+			.provisioner_manager
+			.caddy_certificates(&domain.domain)
+			.await
+		{
+			Ok(pem) => pem,
+			// Says nothing about the certificate, try again next time
+			Err(e) => {
+				log::warn!(
+					"Failed to read the certificates of domain {}: {}",
+					domain.domain,
+					e
+				);
+				return;
+			}
+		};
+		let (state, expires_at, error) = match certificate_expiry(&pem, &domain.domain) {
+			Ok(expires_at) => (CertificateState::Issued, Some(expires_at), None),
+			Err(e) => (CertificateState::Pending, None, Some(e)),
+		};
+
+		let domain_id = domain.id;
+		let res = pool
+			.run(move |c| {
+				use db_models::schema::domains::dsl::{
+					certificate_checked_at, certificate_error, certificate_expires_at,
+					certificate_state, domains,
+				};
+
+				diesel::update(domains.find(domain_id))
+					.set((
+						certificate_state.eq(state),
+						certificate_expires_at.eq(expires_at),
+						certificate_error.eq(error),
+						certificate_checked_at.eq(diesel::dsl::now),
+					))
+					.execute(c)
+			})
+			.await;
+		if let Err(e) = res {
+			log::error!(
+				"Failed to record the certificate of domain {}: {}",
+				domain.domain,
+				e
+			);
+		}
+	}
+
+	async fn check(&self, pool: &DbPool, domain: Domain) {
+		// Ownership was proven when the domain was first verified, so the challenge record may be
+		// gone by now
+		let is_verified = match points_to_us(&self.dns, &self.targets, &domain.domain).await {
+			Ok(is_verified) => is_verified,
+			// Our resolver failing says nothing about the domain, try again next time
+			Err(e) => {
+				log::warn!("Failed to check domain {}: {}", domain.domain, e);
+				return;
+			}
+		};
+
+		let now = chrono::Utc::now().naive_utc();
+		let expired = domain.last_verified_at.map_or(true, |t| {
+			t < now - chrono::Duration::seconds(self.config.grace_period_secs)
+		});
+		let unverify = !is_verified && expired;
+		let domain_id = domain.id;
+		let app_id = domain.app_id;
+		let previously_verified_at = domain.last_verified_at;
+		let res = pool
+			.run(move |c| {
+				use db_models::schema::domains::dsl::{
+					domains, last_checked_at, last_verified_at, verified,
+				};
+
+				c.transaction(|| {
+					diesel::update(domains.find(domain_id))
+						.set((
+							verified.eq(!unverify),
+							last_checked_at.eq(now),
+							last_verified_at.eq(if is_verified {
+								Some(now)
+							} else {
+								previously_verified_at
+							}),
+						))
+						.execute(c)?;
+					if unverify {
+						request_hosts_sync(app_id, c)?;
+					}
+					Ok(())
+				})
+			})
+			.await;
+		if let Err(e) = res {
+			log::error!("Failed to record check of domain {}: {}", domain.domain, e);
+			return;
+		}
+
+		if unverify {
+			log::info!(
+				"Unverified domain {}, which stopped pointing to us",
+				domain.domain
+			);
+			if let Err(e) = self
+				.provisioner_manager
+				.update_app_hosts(pool, domain.app_id)
+				.await
+			{
+				log::error!(
+					"Failed to stop routing domain {} to app {}: {}",
+					domain.domain,
+					domain.app_id,
+					e
+				);
+			}
+		}
+	}
+}
@@ -6,14 +6,17 @@ extern crate lazy_static;
 
 use diesel::prelude::*;
 use dotenv::dotenv;
-use rocket::tokio::{net::UdpSocket, sync::RwLock};
+use rocket::fairing::AdHoc;
+use rocket::tokio::sync::RwLock;
 use rocket_sync_db_pools::database;
-use trust_dns_client::{client::AsyncClient, udp::UdpClientStream};
 
 mod api;
 mod auth;
+mod console;
+mod domain_checks;
 mod provision;
 mod slack;
+mod tasks;
 mod utils;
 
 #[database("db")]
@@ -28,14 +31,6 @@ async fn openapi() -> &'static str {
 async fn rocket() -> _ {
 	dotenv().ok();
 
-	// Instantiate a DNS client for domain verification
-	let stream = UdpClientStream::<UdpSocket>::new(([1, 1, 1, 1], 53).into());
-	let (dns_client, bg) = AsyncClient::connect(stream)
-		.await
-		.expect("Error instantiating DNS client");
-
-	rocket::tokio::spawn(bg);
-
 	let r = rocket::build()
 		.mount(
 			"/api",
@@ -46,12 +41,42 @@ async fn rocket() -> _ {
 				api::auth::code,
 				api::apps::app,
 				api::apps::create,
+				api::apps::update,
+				api::apps::delete,
+				api::apps::stop,
+				api::apps::start,
+				api::apps::restart,
 				api::apps::domains,
 				api::apps::deploy, // experimental - please do not use
+				api::apps::rollback,
+				api::apps::releases,
+				api::apps::logs,
+				api::apps::console_sessions,
+				api::tasks::create_run,
+				api::tasks::runs,
+				api::tasks::run,
+				api::tasks::schedules,
+				api::tasks::create_schedule,
+				api::tasks::delete_schedule,
+				api::apps::env,
+				api::apps::create_env_var,
+				api::apps::update_env_var,
+				api::apps::delete_env_var,
+				api::apps::deploy_key,
+				api::apps::set_git_token,
+				api::apps::delete_git_token,
+				api::apps::webhook_secret,
 				api::builds::build,
+				api::builds::events,
+				api::builds::event_page,
+				api::builds::cancel,
 				api::dev::login,
 				api::domains::create,
 				api::domains::verify,
+				api::domains::delete,
+				api::hooks::github,
+				api::hooks::gitea,
+				api::hooks::gitlab,
 				api::oauth::create_device_authorization,
 				api::oauth::device_authorization,
 				api::oauth::device_approve,
@@ -67,16 +92,112 @@ async fn rocket() -> _ {
 				api::users::teams,
 				api::invites::get,
 				api::invites::create,
-        api::invites::accept,
+				api::invites::accept,
 				api::invites::delete,
 			],
 		)
-		.attach(DbConn::fairing())
-		.manage(RwLock::new(dns_client));
+		.attach(DbConn::fairing());
+
+	// Instantiate a DNS client for domain verification (requires figment)
+	let dns_client =
+		utils::dns::DnsClient::connect(&utils::dns::DnsConfig::from_figment(r.figment()))
+			.await
+			.expect("Error instantiating DNS client");
+
+	// Setup secret encryption (requires figment)
+	let encryptor =
+		utils::crypto::Encryptor::from_figment(r.figment()).expect("Error instantiating encryptor");
 
 	// Setup provisioner manager (requires figment)
 	let provisioner_manager = provision::ProvisionerManager::from_figment(r.figment())
 		.expect("Error instantiating provisioner manager");
 
+	let limits = provision::ResourceLimitsConfig::from_figment(r.figment());
+
+	let domain_targets = utils::domain::DomainTargets::from_figment(r.figment());
+	let apps_domain = utils::domain::AppsDomain::from_figment(r.figment());
+	let domain_checks = domain_checks::DomainChecksConfig::from_figment(r.figment());
+	let console_config = console::ConsoleConfig::from_figment(r.figment());
+	let tasks_config = tasks::TasksConfig::from_figment(r.figment());
+	let task_queue = tasks::TaskQueue::default();
+
 	r.manage(RwLock::new(provisioner_manager))
+		.manage(encryptor)
+		.manage(limits)
+		.manage(dns_client.clone())
+		.manage(domain_targets.clone())
+		.manage(apps_domain.clone())
+		.manage(task_queue.clone())
+		.attach(AdHoc::on_liftoff("Build queue", |rocket| {
+			Box::pin(async move {
+				let provisioner_manager = rocket
+					.state::<RwLock<provision::ProvisionerManager>>()
+					.expect("Provisioner manager is not managed");
+				let pool = utils::db_pool::DbPool::from_rocket(rocket)
+					.await
+					.expect("Failed to get the database pool for build workers");
+				let provisioner_manager = provisioner_manager.read().await;
+				// Before any build deploys, prunes images or rolls back
+				match provisioner_manager.tag_legacy_images(&pool).await {
+					Ok(0) => {}
+					Ok(n) => log::info!("Tagged {} image(s) deployed before builds were tagged", n),
+					Err(e) => log::error!("Failed to tag images of earlier deploys: {}", e),
+				}
+				provisioner_manager.start_workers(&pool);
+			})
+		}))
+		.attach(AdHoc::on_liftoff("Domain checks", |rocket| {
+			Box::pin(async move {
+				let provisioner_manager = rocket
+					.state::<RwLock<provision::ProvisionerManager>>()
+					.expect("Provisioner manager is not managed")
+					.read()
+					.await
+					.clone();
+				let pool = utils::db_pool::DbPool::from_rocket(rocket)
+					.await
+					.expect("Failed to get the database pool for domain checks");
+				rocket::tokio::spawn(
+					domain_checks::DomainChecker::new(
+						dns_client,
+						domain_targets,
+						apps_domain,
+						provisioner_manager,
+						domain_checks,
+					)
+					.run(pool),
+				);
+			})
+		}))
+		.attach(AdHoc::on_liftoff("Tasks", |rocket| {
+			Box::pin(async move {
+				let provisioner_manager = rocket
+					.state::<RwLock<provision::ProvisionerManager>>()
+					.expect("Provisioner manager is not managed")
+					.read()
+					.await
+					.clone();
+				let pool = utils::db_pool::DbPool::from_rocket(rocket)
+					.await
+					.expect("Failed to get the database pool for tasks");
+				tasks::TaskRunner::new(provisioner_manager, task_queue, tasks_config).start(&pool);
+			})
+		}))
+		.attach(AdHoc::on_liftoff("Console", |rocket| {
+			Box::pin(async move {
+				let provisioner_manager = rocket
+					.state::<RwLock<provision::ProvisionerManager>>()
+					.expect("Provisioner manager is not managed")
+					.read()
+					.await
+					.clone();
+				let pool = utils::db_pool::DbPool::from_rocket(rocket)
+					.await
+					.expect("Failed to get the database pool for consoles");
+				rocket::tokio::spawn(
+					console::ConsoleServer::new(provisioner_manager, console_config, pool)
+						.run(rocket.config().address),
+				);
+			})
+		}))
 }
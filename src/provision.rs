@@ -1,13 +1,30 @@
 #![allow(dead_code)] // Remove once we have the API routes for this
 
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::DbConn;
+use crate::{
+	utils::{
+		advisory_lock::{try_lock, LockNamespace},
+		crypto::{Encryptor, SecretColumn},
+		db_pool::DbPool,
+	},
+	DbConn,
+};
 use chrono::NaiveDateTime;
+use db_models::{App, Build, BuildEvent, BuildEventKind, BuildStatus};
 use diesel::prelude::*;
-use provisioner::{Provisioner, ProvisionerEvent};
-use tokio::sync::broadcast::{self, Sender};
+use provisioner::{
+	CancellationToken, Console, DeployOptions, GitCredentials, GitSource, LogLine, LogOptions,
+	Provisioner, ProvisionerError, ProvisionerEvent, RepoConfig, ResourceLimits, TaskOptions,
+	TaskOutcome,
+};
+use rocket::futures::Stream;
+use tokio::sync::{
+	broadcast::{self, Sender},
+	mpsc, Notify,
+};
 
 pub use provisioner::hyper::Uri;
 
@@ -24,10 +41,48 @@ impl ProvisionerEvent2 {
 		let ts = chrono::Utc::now().naive_utc();
 		Self { ts, event }
 	}
+
+	pub fn kind(&self) -> BuildEventKind {
+		match &self.event {
+			Ok(ProvisionerEvent::GitClone(_)) => BuildEventKind::GitClone,
+			Ok(ProvisionerEvent::Builder(_)) | Ok(ProvisionerEvent::DockerBuild(_)) => {
+				BuildEventKind::DockerBuild
+			}
+			Ok(ProvisionerEvent::Deploy(_)) | Ok(ProvisionerEvent::DeployEvent(_)) => {
+				BuildEventKind::Deploy
+			}
+			Err(_) => BuildEventKind::Error,
+		}
+	}
+
+	pub fn into_build_event(self, build_id: i32, seq: i32) -> BuildEvent {
+		BuildEvent {
+			build_id,
+			seq,
+			ts: self.ts,
+			kind: self.kind(),
+			payload: serde_json::to_value(&self.event).unwrap(),
+		}
+	}
+}
+
+/// Lends database connections to the provisioner: the connection of a request, or connections
+/// from the pool for background tasks
+pub enum PooledDbRunner<'a> {
+	Conn(&'a DbConn),
+	Pool(&'a DbPool),
 }
 
-struct PooledDbRunner<'a> {
-	c: &'a DbConn,
+impl<'a> From<&'a DbConn> for PooledDbRunner<'a> {
+	fn from(conn: &'a DbConn) -> Self {
+		Self::Conn(conn)
+	}
+}
+
+impl<'a> From<&'a DbPool> for PooledDbRunner<'a> {
+	fn from(pool: &'a DbPool) -> Self {
+		Self::Pool(pool)
+	}
 }
 
 #[rocket::async_trait]
@@ -40,7 +95,15 @@ impl<'a> provisioner::DbRunner for &PooledDbRunner<'a> {
 				+ 'static,
 		>,
 	) -> Result<U, diesel::result::Error> {
-		self.c.run(f).await
+		match self {
+			PooledDbRunner::Conn(c) => c.run(f).await,
+			PooledDbRunner::Pool(pool) => {
+				let pool = (*pool).clone();
+				tokio::task::spawn_blocking(move || f(&mut *pool.get()?))
+					.await
+					.expect("Failed to run a database query")
+			}
+		}
 	}
 }
 
@@ -49,11 +112,629 @@ pub struct ProvisionerConfig {
 	#[serde(with = "crate::utils::url_serializer")]
 	caddy_api_base: provisioner::caddy::Url,
 	caddy_container_name: String,
+	/// Caddy HTTP server that app routes are added to
+	#[serde(default = "default_caddy_server_name")]
+	caddy_server_name: String,
+	/// Caddy's data directory within its container, where it stores the certificates it obtains
+	#[serde(default = "default_caddy_storage_dir")]
+	caddy_storage_dir: String,
+	#[serde(default)]
+	health_check: provisioner::HealthCheckConfig,
+	/// Number of builds this instance runs concurrently
+	#[serde(default = "default_build_workers")]
+	build_workers: usize,
+	/// SSH host keys trusted when cloning, in known_hosts format. Defaults to GitHub's.
+	#[serde(default)]
+	known_hosts: Option<Vec<String>>,
+	/// Number of most recent images kept for each app, which can be rolled back to
+	#[serde(default = "default_kept_images")]
+	kept_images: usize,
+}
+
+fn default_build_workers() -> usize {
+	1
+}
+
+fn default_caddy_server_name() -> String {
+	"srv0".to_owned()
+}
+
+fn default_caddy_storage_dir() -> String {
+	"/data/caddy".to_owned()
+}
+
+fn default_kept_images() -> usize {
+	5
+}
+
+/// How often idle workers look for builds queued by other API instances
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How often running builds are marked as alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// How long to wait for a cancelled build to stop. Builds running on other instances only notice
+/// their cancellation on their next heartbeat.
+pub const BUILD_CANCEL_TIMEOUT: Duration = Duration::from_secs(2 * HEARTBEAT_INTERVAL.as_secs());
+/// Running builds without a heartbeat for this long are considered orphaned
+const HEARTBEAT_TIMEOUT_SECS: i64 = 60;
+/// Docker builds can emit bursts of events faster than subscribers read them
+const EVENT_BUFFER_SIZE: usize = 1024;
+/// Maximum number of events written to the db in a single insert
+const EVENT_BATCH_SIZE: usize = 100;
+
+/// Server-side maximums for the resource limits teams can set on their apps
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ResourceLimitsConfig {
+	max_memory_limit: i64,
+	max_cpu_shares: i64,
+	max_cpu_quota: i64,
+	max_pids_limit: i64,
+	max_build_timeout: i32,
+}
+
+impl Default for ResourceLimitsConfig {
+	fn default() -> Self {
+		Self {
+			max_memory_limit: 2 * 1024 * 1024 * 1024,
+			max_cpu_shares: 4096,
+			max_cpu_quota: 2 * provisioner::CPU_PERIOD,
+			max_pids_limit: 1024,
+			max_build_timeout: 3600,
+		}
+	}
+}
+
+impl ResourceLimitsConfig {
+	pub fn from_figment(f: &rocket::figment::Figment) -> Self {
+		f.focus("limits")
+			.extract::<Self>()
+			.expect("Failed to extract resource limits from figment")
+	}
+
+	/// Checks that every limit being changed is at least what Docker accepts, and within the
+	/// server's maximums
+	pub fn allows(&self, app: &db_models::UpdatedApp) -> bool {
+		use provisioner::{MIN_CPU_QUOTA, MIN_CPU_SHARES, MIN_MEMORY_LIMIT};
+
+		fn within<T: PartialOrd>(value: Option<T>, min: T, max: T) -> bool {
+			value.map_or(true, |v| v >= min && v <= max)
+		}
+
+		within(app.memory_limit, MIN_MEMORY_LIMIT, self.max_memory_limit)
+			&& within(app.cpu_shares, MIN_CPU_SHARES, self.max_cpu_shares)
+			&& within(app.cpu_quota, MIN_CPU_QUOTA, self.max_cpu_quota)
+			&& within(app.pids_limit, 1, self.max_pids_limit)
+			&& within(app.build_timeout, 1, self.max_build_timeout)
+	}
+}
+
+/// A build being run by a worker of this instance
+struct RunningBuild {
+	events: Sender<BuildEvent>,
+	cancel: CancellationToken,
 }
 
+type RunningBuilds = Arc<Mutex<HashMap<i32, RunningBuild>>>;
+
+#[derive(Clone)]
 pub struct ProvisionerManager {
 	provisioner: Arc<Provisioner>,
-	event_channels: HashMap<i32, Sender<ProvisionerEvent2>>,
+	encryptor: Encryptor,
+	running_builds: RunningBuilds,
+	queue_notify: Arc<Notify>,
+	build_workers: usize,
+}
+
+/// Loads and decrypts the app's environment variables for its next container
+async fn deploy_options_for_app(
+	pool: &DbPool,
+	encryptor: &Encryptor,
+	app_id: i32,
+) -> Result<DeployOptions, String> {
+	let vars = pool
+		.run(move |c| {
+			use db_models::schema::app_env_vars::dsl::{app_env_vars, app_id as var_app_id};
+
+			app_env_vars
+				.filter(var_app_id.eq(app_id))
+				.load::<db_models::AppEnvVar>(c)
+		})
+		.await
+		.map_err(|e| e.to_string())?;
+
+	let env = vars
+		.into_iter()
+		.map(|v| {
+			encryptor
+				.decrypt(&v.encrypted_value, SecretColumn::EnvVarValue, app_id)
+				.map(|value| format!("{}={}", v.key, value))
+				.map_err(|_| format!("Failed to decrypt environment variable {}", v.key))
+		})
+		.collect::<Result<_, _>>()?;
+
+	Ok(DeployOptions {
+		env,
+		..Default::default()
+	})
+}
+
+/// Reads back the haas.toml settings stored on a build
+fn stored_repo_config(build: &Build) -> Result<Option<RepoConfig>, String> {
+	build
+		.config
+		.clone()
+		.map(serde_json::from_value)
+		.transpose()
+		.map_err(|e| format!("Build {} has invalid settings: {}", build.id, e))
+}
+
+/// Picks the app's credentials that can be used to clone `uri`, if any. Tokens are only ever sent
+/// over HTTPS.
+fn git_credentials_for_app(
+	app: &App,
+	uri: &Uri,
+	encryptor: &Encryptor,
+) -> Result<Option<GitCredentials>, String> {
+	let decrypt = |encrypted: &str, column: SecretColumn, what: &str| {
+		encryptor
+			.decrypt(encrypted, column, app.id)
+			.map_err(|_| format!("Failed to decrypt the app's {}", what))
+	};
+
+	match uri.scheme_str() {
+		Some("ssh") => app
+			.deploy_key_private
+			.as_deref()
+			.map(|key| {
+				decrypt(key, SecretColumn::DeployKey, "deploy key").map(GitCredentials::SshKey)
+			})
+			.transpose(),
+		Some("https") => app
+			.git_https_token
+			.as_deref()
+			.map(|token| {
+				decrypt(token, SecretColumn::GitHttpsToken, "git token")
+					.map(GitCredentials::HttpsToken)
+			})
+			.transpose(),
+		_ => Ok(None),
+	}
+}
+
+/// Claims the oldest queued build, if any. Rows locked by other workers are skipped, so that
+/// several workers (possibly in several API instances) never claim the same build.
+fn claim_next_build(c: &PgConnection) -> QueryResult<Option<Build>> {
+	use db_models::schema::builds::dsl::{app_id, builds, heartbeat_at, id, status};
+
+	c.transaction(|| {
+		// Apps whose builds can't be claimed right now
+		let mut busy_apps = Vec::new();
+		loop {
+			let build = builds
+				.filter(status.eq(BuildStatus::Queued))
+				.filter(app_id.ne_all(&busy_apps))
+				// Builds of the same app would race each other to deploy
+				.filter(diesel::dsl::sql::<diesel::sql_types::Bool>(
+					"NOT EXISTS (SELECT 1 FROM builds AS running \
+					WHERE running.app_id = builds.app_id AND running.status = 'running')",
+				))
+				.order(id.asc())
+				.for_update()
+				.skip_locked()
+				.first::<Build>(c)
+				.optional()?;
+			let build = match build {
+				Some(build) => build,
+				None => return Ok(None),
+			};
+
+			// The query above can't see a build of the same app that another worker is claiming,
+			// so claims are serialized per app. Once the lock is ours, builds claimed by whoever
+			// held it before are visible.
+			let is_free = try_lock(LockNamespace::AppBuilds, build.app_id, c)?
+				&& !diesel::select(diesel::dsl::exists(
+					builds
+						.filter(app_id.eq(build.app_id))
+						.filter(status.eq(BuildStatus::Running)),
+				))
+				.get_result::<bool>(c)?;
+			if !is_free {
+				busy_apps.push(build.app_id);
+				continue;
+			}
+
+			return diesel::update(&build)
+				.set((
+					status.eq(BuildStatus::Running),
+					heartbeat_at.eq(diesel::dsl::now),
+				))
+				.get_result::<Build>(c)
+				.map(Some);
+		}
+	})
+}
+
+/// Appends an error event to a build, from outside of the worker running it
+fn append_build_error(build_id: i32, message: String, c: &PgConnection) -> QueryResult<()> {
+	append_build_events(build_id, vec![ProvisionerEvent2::make(Err(message))], c).map(|_| ())
+}
+
+/// Stores events of a build, numbered after the ones already stored. The build is locked while
+/// they are, so that events appended at the same time, e.g. by the worker running the build and
+/// by the cleanup of orphaned builds, can't get the same number.
+fn append_build_events(
+	build_id: i32,
+	events: Vec<ProvisionerEvent2>,
+	c: &PgConnection,
+) -> QueryResult<Vec<BuildEvent>> {
+	use db_models::schema::build_events::dsl::{build_events, build_id as event_build_id, seq};
+	use db_models::schema::builds::dsl::{builds, id};
+
+	c.transaction(|| {
+		builds
+			.find(build_id)
+			.select(id)
+			.for_update()
+			.first::<i32>(c)?;
+		let last_seq = build_events
+			.filter(event_build_id.eq(build_id))
+			.select(diesel::dsl::max(seq))
+			.first::<Option<i32>>(c)?
+			.unwrap_or(0);
+
+		let events = events
+			.into_iter()
+			.zip(last_seq + 1..)
+			.map(|(ev, n)| ev.into_build_event(build_id, n))
+			.collect::<Vec<_>>();
+		diesel::insert_into(build_events)
+			.values(&events)
+			.execute(c)?;
+		Ok(events)
+	})
+}
+
+/// Cancels a queued build right away, or asks the worker running a build to stop it. Finished
+/// builds are returned as is.
+pub fn request_build_cancellation(build_id: i32, c: &PgConnection) -> QueryResult<Build> {
+	use db_models::schema::builds::dsl::{builds, cancel_requested, ended_at, id, status};
+
+	c.transaction(|| {
+		let cancelled = diesel::update(builds)
+			.filter(id.eq(build_id).and(status.eq(BuildStatus::Queued)))
+			.set((
+				status.eq(BuildStatus::Cancelled),
+				ended_at.eq(diesel::dsl::now),
+			))
+			.get_result::<Build>(c)
+			.optional()?;
+		if let Some(build) = cancelled {
+			append_build_error(build_id, ProvisionerError::Cancelled.to_string(), c)?;
+			return Ok(build);
+		}
+
+		let running = diesel::update(builds)
+			.filter(id.eq(build_id).and(status.eq(BuildStatus::Running)))
+			.set(cancel_requested.eq(true))
+			.get_result::<Build>(c)
+			.optional()?;
+		match running {
+			Some(build) => Ok(build),
+			None => builds.find(build_id).first::<Build>(c),
+		}
+	})
+}
+
+/// Marks running builds whose worker stopped sending heartbeats (e.g. because the API was
+/// restarted) as failed, so that they no longer block new deploys
+fn fail_orphaned_builds(c: &PgConnection) -> QueryResult<usize> {
+	use db_models::schema::builds::dsl::{builds, ended_at, heartbeat_at, id, status};
+
+	let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::seconds(HEARTBEAT_TIMEOUT_SECS);
+
+	c.transaction(|| {
+		let orphaned = diesel::update(builds)
+			.filter(
+				status
+					.eq(BuildStatus::Running)
+					.and(heartbeat_at.is_null().or(heartbeat_at.lt(cutoff))),
+			)
+			.set((
+				status.eq(BuildStatus::Failed),
+				ended_at.eq(diesel::dsl::now),
+			))
+			.returning(id)
+			.get_results::<i32>(c)?;
+
+		for orphan_id in &orphaned {
+			append_build_error(
+				*orphan_id,
+				"Build was interrupted by an API restart".to_owned(),
+				c,
+			)?;
+		}
+
+		Ok(orphaned.len())
+	})
+}
+
+/// Claims queued builds from the database and runs them one at a time
+#[derive(Clone)]
+struct BuildWorker {
+	provisioner: Arc<Provisioner>,
+	encryptor: Encryptor,
+	running_builds: RunningBuilds,
+	queue_notify: Arc<Notify>,
+}
+
+impl BuildWorker {
+	async fn run(self, pool: DbPool) {
+		loop {
+			match pool.run(fail_orphaned_builds).await {
+				Ok(0) => {}
+				Ok(n) => log::warn!("Marked {} orphaned build(s) as failed", n),
+				Err(e) => log::error!("Failed to clean up orphaned builds: {}", e),
+			}
+
+			match pool.run(claim_next_build).await {
+				Ok(Some(build)) => self.run_build(&pool, build).await,
+				Ok(None) => {
+					// Wait for a new build, or poll again in case another instance queued one
+					let _ = tokio::time::timeout(QUEUE_POLL_INTERVAL, self.queue_notify.notified())
+						.await;
+				}
+				Err(e) => {
+					log::error!("Failed to claim a build: {}", e);
+					tokio::time::sleep(QUEUE_POLL_INTERVAL).await;
+				}
+			}
+		}
+	}
+
+	async fn run_build(&self, pool: &DbPool, build: Build) {
+		use db_models::schema::builds::dsl::{builds, cancel_requested, heartbeat_at, id};
+
+		let build_id = build.id;
+		// Unbounded, so that no event is dropped when they come faster than they are written to the
+		// db. The queue only grows as much as the build's output.
+		let (tx, mut rx) = mpsc::unbounded_channel::<ProvisionerEvent2>();
+		// Events are only forwarded to subscribers once persisted, so that they can be numbered
+		// consistently with the events replayed from the db
+		let (live_tx, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+		let writer_pool = pool.clone();
+		let live = live_tx.clone();
+		// Receive build events and insert them into the db in batches
+		let writer = tokio::spawn(async move {
+			loop {
+				let mut batch = match rx.recv().await {
+					Some(ev) => vec![ev],
+					None => break,
+				};
+				// Pick up whatever else arrived in the meantime
+				while batch.len() < EVENT_BATCH_SIZE {
+					match rx.try_recv() {
+						Ok(ev) => batch.push(ev),
+						Err(_) => break,
+					}
+				}
+
+				let len = batch.len();
+				match writer_pool
+					.run(move |c| append_build_events(build_id, batch, c))
+					.await
+				{
+					Ok(batch) => {
+						for ev in batch {
+							// Nobody may be listening
+							let _ = live.send(ev);
+						}
+					}
+					Err(e) => log::error!(
+						"Failed to store {} event(s) of build {}: {}",
+						len,
+						build_id,
+						e
+					),
+				}
+			}
+		});
+		let cancel = CancellationToken::new();
+		self.running_builds.lock().unwrap().insert(
+			build_id,
+			RunningBuild {
+				events: live_tx,
+				cancel: cancel.clone(),
+			},
+		);
+
+		// Keep the build from being considered orphaned while it runs, and pick up cancellations
+		// requested through other instances
+		let heartbeat_pool = pool.clone();
+		let heartbeat_cancel = cancel.clone();
+		let heartbeat = tokio::spawn(async move {
+			loop {
+				let res = heartbeat_pool
+					.run(move |c| {
+						diesel::update(builds.filter(id.eq(build_id)))
+							.set(heartbeat_at.eq(diesel::dsl::now))
+							.returning(cancel_requested)
+							.get_result::<bool>(c)
+					})
+					.await;
+				match res {
+					Ok(true) => heartbeat_cancel.cancel(),
+					Ok(false) => {}
+					Err(e) => {
+						log::error!("Failed to record heartbeat for build {}: {}", build_id, e)
+					}
+				}
+				tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+			}
+		});
+
+		let result = self.build_and_deploy(pool, &build, &cancel, &tx).await;
+		let final_status = match result {
+			Ok(()) => BuildStatus::Succeeded,
+			Err(e) => {
+				let _ = tx.send(ProvisionerEvent2::make(Err(e)));
+				if cancel.is_cancelled() {
+					BuildStatus::Cancelled
+				} else {
+					BuildStatus::Failed
+				}
+			}
+		};
+
+		// The writer ends once every sender is dropped. Wait for it to store the last events, so
+		// that a build never ends before its log does.
+		drop(tx);
+		if let Err(e) = writer.await {
+			log::error!("Event writer of build {} failed: {}", build_id, e);
+		}
+		heartbeat.abort();
+
+		let res = pool
+			.run(move |c| {
+				use db_models::schema::builds::dsl::{ended_at, status};
+
+				diesel::update(builds)
+					.filter(id.eq(build_id))
+					.set((
+						status.eq(final_status),
+						ended_at.eq(chrono::Utc::now().naive_utc()),
+					))
+					.execute(c)
+			})
+			.await;
+		if let Err(e) = res {
+			// The build is failed as orphaned once its heartbeat stops
+			log::error!("Failed to record the outcome of build {}: {}", build_id, e);
+		}
+		// Dropping the last sender of live events closes the streams of subscribers
+		self.running_builds.lock().unwrap().remove(&build_id);
+	}
+
+	/// Builds the image of a build, and records the commit that was built along with the settings
+	/// of its haas.toml
+	async fn build_image(
+		&self,
+		pool: &DbPool,
+		build: &Build,
+		app: &App,
+		cancel: &CancellationToken,
+		tx: &broadcast::Sender<ProvisionerEvent>,
+	) -> Result<Option<RepoConfig>, String> {
+		let uri: Uri = build
+			.git_repository
+			.as_deref()
+			.ok_or_else(|| "Build has no git repository".to_owned())?
+			.parse()
+			.map_err(|_| "Build has an invalid git repository".to_owned())?;
+		let source = GitSource {
+			credentials: git_credentials_for_app(app, &uri, &self.encryptor)?,
+			uri,
+			git_ref: build.git_ref.clone(),
+		};
+
+		let (commit, repo_config) = self
+			.provisioner
+			.build_image_from_github(
+				app.id,
+				build.id,
+				&app.slug,
+				&source,
+				&ResourceLimits::from(app),
+				cancel,
+				Some(tx.clone()),
+			)
+			.await
+			.map_err(|e| e.to_string())?;
+		let build_id = build.id;
+		let stored_config = repo_config
+			.as_ref()
+			.map(|c| serde_json::to_value(c).unwrap());
+		pool.run(move |c| {
+			use db_models::schema::builds::dsl::{
+				builds, commit_author, commit_message, commit_sha, config, id,
+			};
+
+			diesel::update(builds.filter(id.eq(build_id)))
+				.set((
+					commit_sha.eq(commit.sha),
+					commit_author.eq(commit.author),
+					commit_message.eq(commit.message),
+					config.eq(stored_config),
+				))
+				.execute(c)
+		})
+		.await
+		.map_err(|e| e.to_string())?;
+
+		Ok(repo_config)
+	}
+
+	async fn build_and_deploy(
+		&self,
+		pool: &DbPool,
+		build: &Build,
+		cancel: &CancellationToken,
+		tx: &mpsc::UnboundedSender<ProvisionerEvent2>,
+	) -> Result<(), String> {
+		let app_id = build.app_id;
+		let app = pool
+			.run(move |c| {
+				use db_models::schema::apps::dsl::apps;
+
+				apps.find(app_id).first::<App>(c)
+			})
+			.await
+			.map_err(|e| e.to_string())?;
+		let (tx2, mut rx2) = broadcast::channel(EVENT_BUFFER_SIZE);
+		let tx_clone = tx.clone();
+		let build_id = build.id;
+		tokio::spawn(async move {
+			loop {
+				match rx2.recv().await {
+					Ok(ev) => {
+						let _ = tx_clone.send(ProvisionerEvent2::make(Ok(ev)));
+					}
+					Err(broadcast::error::RecvError::Closed) => break,
+					Err(broadcast::error::RecvError::Lagged(n)) => {
+						log::warn!("Build {} dropped {} events", build_id, n);
+					}
+				}
+			}
+		});
+
+		// Rollbacks deploy the image of an earlier build as is, with the settings it was built with
+		let repo_config = match build.image_build_id {
+			None => self.build_image(pool, build, &app, cancel, &tx2).await?,
+			Some(_) => stored_repo_config(build)?,
+		};
+		let options = DeployOptions {
+			cancel: cancel.clone(),
+			build_id: build.id,
+			image_build_id: build.image_build_id(),
+			release_command: app.release_command.clone(),
+			..deploy_options_for_app(pool, &self.encryptor, app.id).await?
+		}
+		.with_repo_config(repo_config.unwrap_or_default());
+		let runner = PooledDbRunner::from(pool);
+		self.provisioner
+			.deploy_app(app.id, &mut &runner, &options, Some(tx2))
+			.await
+			.map_err(|e| e.to_string())
+	}
+}
+
+/// Records that the app's domains changed, along with the change, so that its Caddy route is
+/// updated by the domain checker if updating it right away fails
+pub fn request_hosts_sync(app_id: i32, c: &PgConnection) -> QueryResult<usize> {
+	use db_models::schema::apps::dsl::{apps, hosts_sync_requested_at};
+
+	diesel::update(apps.find(app_id))
+		.set(hosts_sync_requested_at.eq(chrono::Utc::now().naive_utc()))
+		.execute(c)
 }
 
 impl ProvisionerManager {
@@ -62,116 +743,257 @@ impl ProvisionerManager {
 			.extract_inner::<ProvisionerConfig>("provisioner")
 			.expect("Failed to extract config from figment");
 		Ok(Self {
-			provisioner: Arc::new(Provisioner::connecting_with_local_defaults(
-				c.caddy_api_base,
-				c.caddy_container_name,
-			)?),
-			event_channels: Default::default(),
+			provisioner: Arc::new(
+				Provisioner::connecting_with_local_defaults(
+					c.caddy_api_base,
+					c.caddy_container_name,
+				)?
+				.with_caddy_server(c.caddy_server_name)
+				.with_caddy_storage(c.caddy_storage_dir)
+				.with_health_check(c.health_check)
+				.with_known_hosts(
+					c.known_hosts
+						.unwrap_or_else(|| vec![provisioner::GITHUB_KNOWN_HOST.to_owned()]),
+				)
+				.with_kept_images(c.kept_images),
+			),
+			encryptor: Encryptor::from_figment(f).expect("Failed to extract encryption key"),
+			running_builds: Default::default(),
+			queue_notify: Default::default(),
+			build_workers: c.build_workers,
 		})
 	}
 
+	/// Starts the workers that run queued builds. Must be called once the server is running.
+	pub fn start_workers(&self, pool: &DbPool) {
+		let worker = BuildWorker {
+			provisioner: Arc::clone(&self.provisioner),
+			encryptor: self.encryptor.clone(),
+			running_builds: Arc::clone(&self.running_builds),
+			queue_notify: Arc::clone(&self.queue_notify),
+		};
+		for _ in 0..self.build_workers {
+			tokio::spawn(worker.clone().run(pool.clone()));
+		}
+	}
+
+	/// Queues a build of the given repository, which will be picked up by the next free worker
 	pub async fn create_build(
-		&mut self,
+		&self,
 		conn: DbConn,
 		git_uri: Uri,
-		app_id: i32,
-		app_slug: &str,
-	) -> diesel::QueryResult<db_models::Build> {
+		git_ref: Option<String>,
+		from_webhook: bool,
+		app: &App,
+	) -> diesel::QueryResult<Build> {
 		use db_models::schema::builds::dsl::builds;
-		use db_models::{Build, NewBuild};
-		let app_slug = app_slug.to_owned();
+		use db_models::NewBuild;
+		let app_id = app.id;
 		let build = conn
 			.run(move |c| {
 				diesel::insert_into(builds)
-					.values(NewBuild { app_id })
+					.values(NewBuild {
+						app_id,
+						git_repository: git_uri.to_string(),
+						git_ref,
+						from_webhook,
+						..Default::default()
+					})
 					.get_result::<Build>(c)
 			})
 			.await?;
-		let build_id = build.id;
-		let (tx, mut rx) = broadcast::channel(10);
-		let pool = conn.get_pool();
-		// Receive build events and append them to the db
-		tokio::spawn(async move {
-			let conn = pool.get().unwrap();
-			loop {
-				match rx.recv().await {
-					Ok(ev) => {
-						use db_models::schema::builds::dsl::{events, id};
-						use diesel::{dsl::sql, sql_types::Text};
-
-						// please don't add overhead please don't add overhead
-						if let Ok(debug_ev) = serde_json::to_string(&ev) {
-							println!("debug: build {} event: {}", build_id, debug_ev);
-						}
+		self.queue_notify.notify_one();
+		Ok(build)
+	}
 
-						let q = diesel::update(builds).filter(id.eq(build_id)).set(
-							// diesel doesn't have a query builder for array_append
-							events.eq(sql("array_append(events, ")
-								.bind::<Text, _>(serde_json::to_string(&ev).unwrap())
-								.sql(")")),
-						);
-						#[cfg(debug_assertions)]
-						println!("query = {}", diesel::debug_query::<diesel::pg::Pg, _>(&q));
-						q.execute(&conn).unwrap();
-					}
-					Err(broadcast::error::RecvError::Closed) => break,
-					_ => {}
-				}
-			}
-		});
-		let (tx2, mut rx2) = broadcast::channel(10);
-		// Start the build / deploy
-		let provisioner = Arc::clone(&self.provisioner);
-		tokio::spawn({
-			let tx = tx.clone();
-			async move {
-				let tx_clone = tx.clone();
-				tokio::spawn(async move {
-					loop {
-						match rx2.recv().await {
-							Ok(ev) => {
-								tx_clone.send(ProvisionerEvent2::make(Ok(ev))).unwrap();
-							}
-							Err(broadcast::error::RecvError::Closed) => break,
-							_ => {}
-						}
-					}
-				});
-				let runner = PooledDbRunner { c: &conn };
-				let br = provisioner
-					.build_image_from_github(app_id, &app_slug, &git_uri, Some(tx2.clone()))
-					.await;
-				if let Err(e) = br {
-					tx.send(ProvisionerEvent2::make(Err(e.to_string())))
-						.unwrap();
-				} else {
-					let dr = provisioner
-						.deploy_app(app_id, &mut &runner, Some(tx2.clone()))
-						.await;
-					if let Err(e) = dr {
-						tx.send(ProvisionerEvent2::make(Err(e.to_string())))
-							.unwrap();
-					}
-				}
-				conn.run(move |c| {
-					use db_models::schema::builds::dsl::{ended_at, id};
-
-					diesel::update(builds)
-						.filter(id.eq(build_id))
-						.set(ended_at.eq(chrono::Utc::now().naive_utc()))
-						.execute(c)
-						.unwrap();
-				})
-				.await;
-			}
-		});
-		self.event_channels.insert(build.id, tx);
+	/// Queues a deploy of the image of an earlier build, without building it again
+	pub async fn create_rollback(
+		&self,
+		conn: DbConn,
+		target: &Build,
+	) -> diesel::QueryResult<Build> {
+		use db_models::schema::builds::dsl::builds;
+		use db_models::NewBuild;
+		let new_build = NewBuild {
+			app_id: target.app_id,
+			git_repository: target.git_repository.clone().unwrap_or_default(),
+			git_ref: target.commit_sha.clone().or_else(|| target.git_ref.clone()),
+			commit_sha: target.commit_sha.clone(),
+			commit_author: target.commit_author.clone(),
+			commit_message: target.commit_message.clone(),
+			image_build_id: Some(target.image_build_id()),
+			config: target.config.clone(),
+			from_webhook: false,
+		};
+		let build = conn
+			.run(move |c| {
+				diesel::insert_into(builds)
+					.values(new_build)
+					.get_result::<Build>(c)
+			})
+			.await?;
+		self.queue_notify.notify_one();
 		Ok(build)
 	}
 
-	pub fn receiver_for_build(&self, id: i32) -> Option<broadcast::Receiver<ProvisionerEvent2>> {
-		self.event_channels
+	/// Updates the domains the app's route matches on, see [Provisioner::update_app_hosts]. Once
+	/// done, the sync requested by [request_hosts_sync] is marked as done, unless the domains
+	/// changed again in the meantime.
+	pub async fn update_app_hosts<'a>(
+		&self,
+		db: impl Into<PooledDbRunner<'a>>,
+		app_id: i32,
+	) -> provisioner::Result<()> {
+		use db_models::schema::apps::dsl::{apps, hosts_sync_requested_at};
+		use provisioner::DbRunner;
+
+		let runner = db.into();
+		let started_at = chrono::Utc::now().naive_utc();
+		self.provisioner
+			.update_app_hosts(app_id, &mut &runner)
+			.await?;
+		(&runner)
+			.run(Box::new(move |c| {
+				diesel::update(
+					apps.find(app_id)
+						.filter(hosts_sync_requested_at.le(started_at)),
+				)
+				.set(hosts_sync_requested_at.eq(None::<NaiveDateTime>))
+				.execute(c)
+			}))
+			.await?;
+		Ok(())
+	}
+
+	/// Tears down the app's resources and deletes it, see [Provisioner::destroy_app]
+	pub async fn destroy_app<'a>(
+		&self,
+		db: impl Into<PooledDbRunner<'a>>,
+		app_id: i32,
+	) -> provisioner::Result<()> {
+		let runner = db.into();
+		self.provisioner.destroy_app(app_id, &mut &runner).await
+	}
+
+	/// See [Provisioner::stop_app]
+	pub async fn stop_app<'a>(
+		&self,
+		db: impl Into<PooledDbRunner<'a>>,
+		app_id: i32,
+	) -> provisioner::Result<()> {
+		let runner = db.into();
+		self.provisioner.stop_app(app_id, &mut &runner).await
+	}
+
+	/// See [Provisioner::start_app]
+	pub async fn start_app<'a>(
+		&self,
+		db: impl Into<PooledDbRunner<'a>>,
+		app_id: i32,
+	) -> provisioner::Result<()> {
+		let runner = db.into();
+		self.provisioner.start_app(app_id, &mut &runner).await
+	}
+
+	/// See [Provisioner::restart_app]
+	pub async fn restart_app<'a>(
+		&self,
+		db: impl Into<PooledDbRunner<'a>>,
+		app_id: i32,
+	) -> provisioner::Result<()> {
+		let runner = db.into();
+		self.provisioner.restart_app(app_id, &mut &runner).await
+	}
+
+	/// See [Provisioner::app_image_builds]
+	pub async fn app_image_builds(&self, app_id: i32) -> provisioner::Result<Vec<i32>> {
+		self.provisioner.app_image_builds(app_id).await
+	}
+
+	/// See [Provisioner::tag_legacy_images]
+	pub async fn tag_legacy_images(&self, pool: &DbPool) -> provisioner::Result<usize> {
+		let runner = PooledDbRunner::from(pool);
+		self.provisioner.tag_legacy_images(&mut &runner).await
+	}
+
+	/// See [Provisioner::caddy_certificates]
+	pub async fn caddy_certificates(&self, domain: &str) -> provisioner::Result<Vec<u8>> {
+		self.provisioner.caddy_certificates(domain).await
+	}
+
+	/// See [Provisioner::container_logs]
+	pub fn container_logs(
+		&self,
+		container_id: &str,
+		options: &LogOptions,
+	) -> impl Stream<Item = provisioner::Result<LogLine>> {
+		self.provisioner.container_logs(container_id, options)
+	}
+
+	/// Runs a command in a container from one of the app's images, with the app's environment
+	/// variables and the defaults of the image's haas.toml. See [Provisioner::run_task]
+	pub async fn run_task(
+		&self,
+		pool: &DbPool,
+		app: &App,
+		command: String,
+		image_build_id: i32,
+		timeout: Duration,
+	) -> Result<TaskOutcome, String> {
+		let image_build = pool
+			.run(move |c| {
+				use db_models::schema::builds::dsl::builds;
+
+				builds.find(image_build_id).first::<Build>(c)
+			})
+			.await
+			.map_err(|e| e.to_string())?;
+		let deploy_options = deploy_options_for_app(pool, &self.encryptor, app.id)
+			.await?
+			.with_repo_config(stored_repo_config(&image_build)?.unwrap_or_default());
+		let options = TaskOptions {
+			command,
+			env: deploy_options.env,
+			image_build_id,
+			timeout,
+		};
+		self.provisioner
+			.run_task(app, &options)
+			.await
+			.map_err(|e| e.to_string())
+	}
+
+	/// See [Provisioner::open_console]
+	pub async fn open_console(&self, container_id: &str) -> provisioner::Result<Console> {
+		self.provisioner.open_console(container_id).await
+	}
+
+	/// See [Provisioner::resize_console]
+	pub async fn resize_console(
+		&self,
+		exec_id: &str,
+		width: u16,
+		height: u16,
+	) -> provisioner::Result<()> {
+		self.provisioner
+			.resize_console(exec_id, width, height)
+			.await
+	}
+
+	/// Subscribes to the events of a build running on this instance
+	pub fn receiver_for_build(&self, id: i32) -> Option<broadcast::Receiver<BuildEvent>> {
+		self.running_builds
+			.lock()
+			.unwrap()
 			.get(&id)
-			.map(broadcast::Sender::subscribe)
+			.map(|build| build.events.subscribe())
+	}
+
+	/// Stops a build if it is running on this instance. Builds running on other instances notice
+	/// their `cancel_requested` flag on their next heartbeat instead.
+	pub fn cancel_local_build(&self, id: i32) {
+		if let Some(build) = self.running_builds.lock().unwrap().get(&id) {
+			build.cancel.cancel();
+		}
 	}
 }
@@ -0,0 +1,401 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use db_models::{App, ConcurrencyPolicy, NewRun, Run, RunStatus, TaskSchedule};
+use diesel::prelude::*;
+use tokio::sync::Notify;
+
+use crate::{
+	provision::ProvisionerManager,
+	utils::{
+		advisory_lock::{try_lock, LockNamespace},
+		db_pool::DbPool,
+	},
+	DbConn,
+};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// Runs whose heartbeat is older than this were interrupted
+const HEARTBEAT_TIMEOUT_SECS: i64 = 60;
+
+/// How one-off and scheduled runs are executed by this instance
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TasksConfig {
+	/// Number of runs executed at the same time
+	workers: usize,
+	/// How often schedules are checked, and the queue is polled for runs queued by other instances
+	poll_interval_secs: u64,
+	/// Runs are killed after this long
+	timeout_secs: u64,
+}
+
+impl Default for TasksConfig {
+	fn default() -> Self {
+		Self {
+			workers: 2,
+			poll_interval_secs: 15,
+			timeout_secs: 60 * 60,
+		}
+	}
+}
+
+impl TasksConfig {
+	pub fn from_figment(f: &rocket::figment::Figment) -> Self {
+		f.focus("tasks")
+			.extract::<Self>()
+			.expect("Failed to extract tasks config from figment")
+	}
+}
+
+/// Parses a cron expression. Besides the standard five fields, a leading seconds field and a
+/// trailing year field are accepted.
+pub fn parse_cron(expr: &str) -> Result<cron::Schedule, String> {
+	let expr = expr.trim();
+	let expr = if expr.split_whitespace().count() == 5 {
+		format!("0 {}", expr)
+	} else {
+		expr.to_owned()
+	};
+	cron::Schedule::from_str(&expr).map_err(|e| e.to_string())
+}
+
+/// Returns when the schedule is next due after `after`, both in UTC
+pub fn next_run_at(schedule: &cron::Schedule, after: NaiveDateTime) -> Option<NaiveDateTime> {
+	schedule
+		.after(&Utc.from_utc_datetime(&after))
+		.next()
+		.map(|t| t.naive_utc())
+}
+
+/// Wakes up the workers of this instance when a run is queued
+#[derive(Default, Clone)]
+pub struct TaskQueue {
+	notify: Arc<Notify>,
+}
+
+impl TaskQueue {
+	pub async fn create_run(
+		&self,
+		conn: &DbConn,
+		app_id: i32,
+		command: String,
+	) -> QueryResult<Run> {
+		let run = conn
+			.run(move |c| {
+				use db_models::schema::runs::dsl::runs;
+
+				diesel::insert_into(runs)
+					.values(NewRun {
+						app_id,
+						schedule_id: None,
+						command,
+						status: RunStatus::Queued,
+						ended_at: None,
+					})
+					.get_result::<Run>(c)
+			})
+			.await?;
+		self.notify.notify_one();
+		Ok(run)
+	}
+}
+
+/// Queues a run of every due schedule, or records it as skipped if the schedule's previous run is
+/// not done and its policy says so. Runs missed while no instance was up are only made up for
+/// once.
+fn queue_due_runs(c: &PgConnection) -> QueryResult<usize> {
+	use db_models::schema::runs::dsl::{runs, schedule_id, status};
+	use db_models::schema::task_schedules::dsl::{
+		next_run_at as schedule_next_run_at, task_schedules,
+	};
+
+	c.transaction(|| {
+		let now = Utc::now().naive_utc();
+		let due = task_schedules
+			.filter(schedule_next_run_at.le(now))
+			.for_update()
+			.skip_locked()
+			.load::<TaskSchedule>(c)?;
+
+		for schedule in &due {
+			let busy = runs
+				.filter(schedule_id.eq(schedule.id))
+				.filter(status.eq_any(vec![RunStatus::Queued, RunStatus::Running]))
+				.first::<Run>(c)
+				.optional()?
+				.is_some();
+			let skip = busy && schedule.concurrency_policy == ConcurrencyPolicy::Skip;
+			diesel::insert_into(runs)
+				.values(NewRun {
+					app_id: schedule.app_id,
+					schedule_id: Some(schedule.id),
+					command: schedule.command.clone(),
+					status: if skip {
+						RunStatus::Skipped
+					} else {
+						RunStatus::Queued
+					},
+					ended_at: if skip { Some(now) } else { None },
+				})
+				.execute(c)?;
+
+			// The expression was validated when the schedule was created
+			let next = parse_cron(&schedule.cron)
+				.ok()
+				.and_then(|cron| next_run_at(&cron, now));
+			diesel::update(schedule)
+				.set(schedule_next_run_at.eq(next))
+				.execute(c)?;
+		}
+
+		Ok(due.len())
+	})
+}
+
+/// Claims the oldest queued run that can start. Runs of the same schedule start one after the
+/// other.
+fn claim_next_run(c: &PgConnection) -> QueryResult<Option<Run>> {
+	use db_models::schema::runs::dsl::{heartbeat_at, id, runs, schedule_id, started_at, status};
+
+	c.transaction(|| {
+		// Schedules whose runs can't be claimed right now
+		let mut busy_schedules = Vec::new();
+		loop {
+			let run = runs
+				.filter(status.eq(RunStatus::Queued))
+				.filter(
+					schedule_id
+						.is_null()
+						.or(schedule_id.ne_all(&busy_schedules)),
+				)
+				.filter(diesel::dsl::sql::<diesel::sql_types::Bool>(
+					"NOT EXISTS (SELECT 1 FROM runs AS other \
+					WHERE other.schedule_id = runs.schedule_id AND (other.status = 'running' \
+					OR (other.status = 'queued' AND other.id < runs.id)))",
+				))
+				.order(id.asc())
+				.for_update()
+				.skip_locked()
+				.first::<Run>(c)
+				.optional()?;
+			let run = match run {
+				Some(run) => run,
+				None => return Ok(None),
+			};
+
+			// Like builds of an app, runs of a schedule are claimed one at a time, so that another
+			// worker can't start the next run while the previous one is being claimed
+			if let Some(schedule) = run.schedule_id {
+				let is_free = try_lock(LockNamespace::ScheduleRuns, schedule, c)?
+					&& !diesel::select(diesel::dsl::exists(
+						runs.filter(schedule_id.eq(schedule))
+							.filter(status.eq(RunStatus::Running)),
+					))
+					.get_result::<bool>(c)?;
+				if !is_free {
+					busy_schedules.push(schedule);
+					continue;
+				}
+			}
+
+			return diesel::update(&run)
+				.set((
+					status.eq(RunStatus::Running),
+					started_at.eq(diesel::dsl::now),
+					heartbeat_at.eq(diesel::dsl::now),
+				))
+				.get_result::<Run>(c)
+				.map(Some);
+		}
+	})
+}
+
+/// Fails the runs whose worker stopped sending heartbeats, e.g. because the API was restarted
+fn fail_orphaned_runs(c: &PgConnection) -> QueryResult<usize> {
+	use db_models::schema::runs::dsl::{ended_at, heartbeat_at, logs, runs, status};
+
+	let cutoff = Utc::now().naive_utc() - chrono::Duration::seconds(HEARTBEAT_TIMEOUT_SECS);
+
+	diesel::update(runs)
+		.filter(
+			status
+				.eq(RunStatus::Running)
+				.and(heartbeat_at.is_null().or(heartbeat_at.lt(cutoff))),
+		)
+		.set((
+			status.eq(RunStatus::Failed),
+			ended_at.eq(diesel::dsl::now),
+			logs.eq("Run was interrupted by an API restart"),
+		))
+		.execute(c)
+}
+
+/// Queues the runs of due schedules, and executes queued runs
+#[derive(Clone)]
+pub struct TaskRunner {
+	provisioner_manager: ProvisionerManager,
+	queue: TaskQueue,
+	config: TasksConfig,
+}
+
+impl TaskRunner {
+	pub fn new(
+		provisioner_manager: ProvisionerManager,
+		queue: TaskQueue,
+		config: TasksConfig,
+	) -> Self {
+		Self {
+			provisioner_manager,
+			queue,
+			config,
+		}
+	}
+
+	/// Starts the scheduler and the workers
+	pub fn start(self, pool: &DbPool) {
+		tokio::spawn(self.clone().schedule(pool.clone()));
+
+		for _ in 0..self.config.workers {
+			tokio::spawn(self.clone().work(pool.clone()));
+		}
+	}
+
+	async fn schedule(self, pool: DbPool) {
+		let interval = Duration::from_secs(self.config.poll_interval_secs);
+		loop {
+			match pool.run(queue_due_runs).await {
+				Ok(0) => {}
+				Ok(_) => self.queue.notify.notify_waiters(),
+				Err(e) => log::error!("Failed to queue scheduled runs: {}", e),
+			}
+			tokio::time::sleep(interval).await;
+		}
+	}
+
+	async fn work(self, pool: DbPool) {
+		let interval = Duration::from_secs(self.config.poll_interval_secs);
+		loop {
+			match pool.run(fail_orphaned_runs).await {
+				Ok(0) => {}
+				Ok(n) => log::warn!("Marked {} orphaned run(s) as failed", n),
+				Err(e) => log::error!("Failed to clean up orphaned runs: {}", e),
+			}
+
+			match pool.run(claim_next_run).await {
+				Ok(Some(run)) => self.execute(&pool, run).await,
+				Ok(None) => {
+					// Wait for a new run, or poll again in case another instance queued one
+					let _ = tokio::time::timeout(interval, self.queue.notify.notified()).await;
+				}
+				Err(e) => {
+					log::error!("Failed to claim a run: {}", e);
+					tokio::time::sleep(interval).await;
+				}
+			}
+		}
+	}
+
+	async fn execute(&self, pool: &DbPool, run: Run) {
+		use db_models::schema::runs::dsl::{
+			ended_at, exit_code, heartbeat_at, id, logs, runs, status,
+		};
+
+		let run_id = run.id;
+		let heartbeat_pool = pool.clone();
+		let heartbeat = tokio::spawn(async move {
+			loop {
+				let res = heartbeat_pool
+					.run(move |c| {
+						diesel::update(runs.filter(id.eq(run_id)))
+							.set(heartbeat_at.eq(diesel::dsl::now))
+							.execute(c)
+					})
+					.await;
+				if let Err(e) = res {
+					log::error!("Failed to record heartbeat for run {}: {}", run_id, e);
+				}
+				tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+			}
+		});
+
+		let (final_status, code, output) = match self.try_execute(pool, &run).await {
+			Ok(outcome) => {
+				let mut output = outcome.logs;
+				if outcome.timed_out {
+					output.push_str(&format!(
+						"\nRun timed out after {} seconds\n",
+						self.config.timeout_secs
+					));
+				}
+				let final_status = if outcome.exit_code == Some(0) {
+					RunStatus::Succeeded
+				} else {
+					RunStatus::Failed
+				};
+				(final_status, outcome.exit_code.map(|c| c as i32), output)
+			}
+			Err(e) => (RunStatus::Failed, None, e),
+		};
+		heartbeat.abort();
+
+		let res = pool
+			.run(move |c| {
+				diesel::update(runs.filter(id.eq(run_id)))
+					.set((
+						status.eq(final_status),
+						ended_at.eq(diesel::dsl::now),
+						exit_code.eq(code),
+						logs.eq(output),
+					))
+					.execute(c)
+			})
+			.await;
+		if let Err(e) = res {
+			log::error!("Failed to record the outcome of run {}: {}", run_id, e);
+		}
+	}
+
+	async fn try_execute(
+		&self,
+		pool: &DbPool,
+		run: &Run,
+	) -> Result<provisioner::TaskOutcome, String> {
+		let app_id = run.app_id;
+		let (app, image_build_id) = pool
+			.run(move |c| {
+				use db_models::schema::apps::dsl::apps;
+				use db_models::schema::releases::dsl::{
+					app_id as release_app_id, id, image_build_id, releases,
+				};
+
+				let app = apps.find(app_id).first::<App>(c)?;
+				// Runs use the image that is live
+				let image = releases
+					.filter(release_app_id.eq(app_id))
+					.order(id.desc())
+					.select(image_build_id)
+					.first::<i32>(c)
+					.optional()?;
+				Ok::<_, diesel::result::Error>((app, image))
+			})
+			.await
+			.map_err(|e| e.to_string())?;
+
+		if !app.enabled {
+			return Err("App is stopped".to_owned());
+		}
+		let image_build_id =
+			image_build_id.ok_or_else(|| "The app has not been deployed".to_owned())?;
+
+		self.provisioner_manager
+			.run_task(
+				pool,
+				&app,
+				run.command.clone(),
+				image_build_id,
+				Duration::from_secs(self.config.timeout_secs),
+			)
+			.await
+	}
+}
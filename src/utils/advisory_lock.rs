@@ -0,0 +1,28 @@
+use diesel::{
+	dsl::sql,
+	sql_types::{Bool, Integer},
+	PgConnection, QueryResult, RunQueryDsl,
+};
+
+/// What an advisory lock is taken on. The same key in different namespaces are different locks.
+#[derive(Clone, Copy, Debug)]
+pub enum LockNamespace {
+	/// Claims of the builds of an app, keyed by app id
+	AppBuilds = 1,
+	/// Claims of the runs of a task schedule, keyed by schedule id
+	ScheduleRuns = 2,
+}
+
+/// Takes an advisory lock until the end of the current transaction, unless another transaction
+/// holds it. Serializes work on a resource across workers and API instances, where row locks
+/// don't help.
+pub fn try_lock(namespace: LockNamespace, key: i32, c: &PgConnection) -> QueryResult<bool> {
+	diesel::select(
+		sql::<Bool>("pg_try_advisory_xact_lock(")
+			.bind::<Integer, _>(namespace as i32)
+			.sql(", ")
+			.bind::<Integer, _>(key)
+			.sql(")"),
+	)
+	.get_result(c)
+}
@@ -0,0 +1,147 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use db_models::{CertificateState, Domain};
+use x509_parser::{extensions::GeneralName, pem::Pem};
+
+/// The certificate Caddy obtained for a domain, as of its last check
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct CertificateStatus {
+	state: CertificateState,
+	expires_at: Option<NaiveDateTime>,
+	/// Why the certificate isn't considered issued, while pending
+	last_error: Option<String>,
+	/// Unset until the domain is first checked after being verified
+	checked_at: Option<NaiveDateTime>,
+}
+
+impl CertificateStatus {
+	/// Only verified domains have one, since Caddy doesn't obtain certificates for the others
+	pub fn of(domain: &Domain) -> Option<Self> {
+		if !domain.verified {
+			return None;
+		}
+
+		Some(Self {
+			state: domain
+				.certificate_state
+				.unwrap_or(CertificateState::Pending),
+			expires_at: domain.certificate_expires_at,
+			last_error: domain.certificate_error.clone(),
+			checked_at: domain.certificate_checked_at,
+		})
+	}
+}
+
+/// Finds the certificate for `domain` among the PEM chains Caddy stored for it, and returns its
+/// expiry unless it expired. Chains also hold the certificates of their issuers, which aren't
+/// valid for the domain.
+pub fn certificate_expiry(pem: &[u8], domain: &str) -> Result<NaiveDateTime, String> {
+	let mut not_after = None;
+	for pem in Pem::iter_from_buffer(pem) {
+		let pem = pem.map_err(|e| e.to_string())?;
+		let certificate = pem.parse_x509().map_err(|e| e.to_string())?;
+		let is_for_domain = certificate
+			.subject_alternative_name()
+			.ok()
+			.flatten()
+			.map_or(false, |san| {
+				san.value.general_names.iter().any(
+					|name| matches!(name, GeneralName::DNSName(name) if name.eq_ignore_ascii_case(domain)),
+				)
+			});
+		if is_for_domain {
+			not_after = not_after.max(Some(certificate.validity().not_after.timestamp()));
+		}
+	}
+	let not_after =
+		not_after.ok_or_else(|| "Caddy has not obtained a certificate yet".to_owned())?;
+
+	let expires_at =
+		DateTime::<Utc>::from(UNIX_EPOCH + Duration::from_secs(not_after.max(0) as u64))
+			.naive_utc();
+	if expires_at <= Utc::now().naive_utc() {
+		return Err(format!("The certificate expired at {}", expires_at));
+	}
+	Ok(expires_at)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Valid for dinopoll.example.com until 2126, and signed by itself
+	const CERTIFICATE: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBtzCCAVygAwIBAgIUGTBgscPiYXzT1PdeMm0CokZTt3gwCgYIKoZIzj0EAwIw
+HzEdMBsGA1UEAwwUZGlub3BvbGwuZXhhbXBsZS5jb20wIBcNMjYxMDE4MTAyNzQ3
+WhgPMjEyNjA5MjQxMDI3NDdaMB8xHTAbBgNVBAMMFGRpbm9wb2xsLmV4YW1wbGUu
+Y29tMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAESYnK6DPcOeyuNagHVSkZoUbz
+s9gDx5rqMjJbFNnjf4T+hu4gObPD+EAlls79FKwvzJZu6dlmehzLFgDmdkN+t6N0
+MHIwHQYDVR0OBBYEFHtGb1B7s11h4Sy/VkDsKyXk9oKvMB8GA1UdIwQYMBaAFHtG
+b1B7s11h4Sy/VkDsKyXk9oKvMA8GA1UdEwEB/wQFMAMBAf8wHwYDVR0RBBgwFoIU
+ZGlub3BvbGwuZXhhbXBsZS5jb20wCgYIKoZIzj0EAwIDSQAwRgIhALfsBZ4K8Xo3
+pycD1z+qnk42KloJpSqHWKQ67SvU8NH2AiEA8ceii/RIqVROGGxPGbpfGZ2U0y4U
+ZnYUFyDDiFVQA3o=
+-----END CERTIFICATE-----
+";
+	// Stands in for the issuer that follows the certificate in chains
+	const ISSUER: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBgjCCASegAwIBAgIUOACJkG+N0NFbXm/5MaCo0vBdgt8wCgYIKoZIzj0EAwIw
+FTETMBEGA1UEAwwKRXhhbXBsZSBDQTAgFw0yNjEwMTgxMDI3NDdaGA8yMTI2MDky
+NDEwMjc0N1owFTETMBEGA1UEAwwKRXhhbXBsZSBDQTBZMBMGByqGSM49AgEGCCqG
+SM49AwEHA0IABAeF1YOoDjLyNwBMvmuDMx3OQUjNUFqUBzOuDzJpOBu5wQZbX2hj
+Q/uqIr1AYJO+WNHWESaSeMtv7/cU3KuBpoKjUzBRMB0GA1UdDgQWBBQS94tBG9Bo
+cqOSezVjJrMGaD/IpTAfBgNVHSMEGDAWgBQS94tBG9BocqOSezVjJrMGaD/IpTAP
+BgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0kAMEYCIQDNLyITZAqFfsF2HXe2
+vdvT9BOA2MxXyQo6MyHVL1yOkAIhAMta44Vi5NKHyo1AYaOA6S7kQBPpfvQOtbRc
+e2f7ERKh
+-----END CERTIFICATE-----
+";
+	// Valid for dinopoll.example.com until 2020-04-01
+	const EXPIRED: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBtTCCAVqgAwIBAgIUWDtmDItpe3bmA8A8BJs6azoQtYswCgYIKoZIzj0EAwIw
+HzEdMBsGA1UEAwwUZGlub3BvbGwuZXhhbXBsZS5jb20wHhcNMjAwMTAxMDAwMDAw
+WhcNMjAwNDAxMDAwMDAwWjAfMR0wGwYDVQQDDBRkaW5vcG9sbC5leGFtcGxlLmNv
+bTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABEmJyugz3DnsrjWoB1UpGaFG87PY
+A8ea6jIyWxTZ43+E/obuIDmzw/hAJZbO/RSsL8yWbunZZnocyxYA5nZDfrejdDBy
+MB0GA1UdDgQWBBR7Rm9Qe7NdYeEsv1ZA7Csl5PaCrzAfBgNVHSMEGDAWgBR7Rm9Q
+e7NdYeEsv1ZA7Csl5PaCrzAPBgNVHRMBAf8EBTADAQH/MB8GA1UdEQQYMBaCFGRp
+bm9wb2xsLmV4YW1wbGUuY29tMAoGCCqGSM49BAMCA0kAMEYCIQCbWz3AVYXiJ0Cy
+midaibdrATxlir+tCnZHAGcVff6U5wIhAPGBn7BxmbxDVX6PGvgEcKq6Ncy0ftHg
+vKiwAnrpwaA6
+-----END CERTIFICATE-----
+";
+
+	#[test]
+	fn returns_the_expiry_of_the_domain_certificate() {
+		let chain = format!("{}{}", CERTIFICATE, ISSUER);
+		let expires_at = NaiveDateTime::parse_from_str("2126-09-24 10:27:47", "%Y-%m-%d %H:%M:%S");
+		for domain in ["dinopoll.example.com", "DinoPoll.example.com"] {
+			assert_eq!(
+				certificate_expiry(chain.as_bytes(), domain),
+				Ok(expires_at.unwrap())
+			);
+		}
+	}
+
+	#[test]
+	fn prefers_the_latest_certificate() {
+		let pem = format!("{}{}", EXPIRED, CERTIFICATE);
+		assert!(certificate_expiry(pem.as_bytes(), "dinopoll.example.com").is_ok());
+	}
+
+	#[test]
+	fn is_pending_without_a_certificate_for_the_domain() {
+		assert!(certificate_expiry(b"", "dinopoll.example.com").is_err());
+		assert!(certificate_expiry(ISSUER.as_bytes(), "dinopoll.example.com").is_err());
+		assert!(certificate_expiry(CERTIFICATE.as_bytes(), "example.com").is_err());
+	}
+
+	#[test]
+	fn is_pending_once_the_certificate_expired() {
+		assert!(certificate_expiry(EXPIRED.as_bytes(), "dinopoll.example.com").is_err());
+	}
+}
@@ -0,0 +1,138 @@
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use rocket::figment::Figment;
+
+const NONCE_LEN: usize = 12;
+
+/// Columns that hold encrypted secrets. A secret is bound to its column and app, and fails to
+/// decrypt once copied to another one.
+#[derive(Clone, Copy, Debug)]
+pub enum SecretColumn {
+	EnvVarValue,
+	DeployKey,
+	GitHttpsToken,
+	WebhookSecret,
+}
+
+impl SecretColumn {
+	fn name(self) -> &'static str {
+		match self {
+			SecretColumn::EnvVarValue => "app_env_vars:encrypted_value",
+			SecretColumn::DeployKey => "apps:deploy_key_private",
+			SecretColumn::GitHttpsToken => "apps:git_https_token",
+			SecretColumn::WebhookSecret => "apps:webhook_secret",
+		}
+	}
+
+	/// Associated data authenticated along with the secret, as `table:column:app_id`
+	fn aad(self, app_id: i32) -> String {
+		format!("{}:{}", self.name(), app_id)
+	}
+}
+
+/// Encrypts secrets at rest with the server's `encryption_key`, a base64-encoded 256-bit key
+#[derive(Clone)]
+pub struct Encryptor {
+	cipher: Aes256Gcm,
+}
+
+impl Encryptor {
+	pub fn from_figment(f: &Figment) -> Result<Self, String> {
+		let key = f
+			.extract_inner::<String>("encryption_key")
+			.map_err(|e| e.to_string())?;
+		let key = base64::decode(key).map_err(|e| e.to_string())?;
+
+		if key.len() != 32 {
+			return Err("encryption_key must be 32 bytes long".to_owned());
+		}
+
+		Ok(Self {
+			cipher: Aes256Gcm::new(Key::from_slice(&key)),
+		})
+	}
+
+	/// Encrypts `plaintext` stored in `column` of the app with a random nonce, returning the
+	/// base64-encoded nonce and ciphertext
+	pub fn encrypt(&self, plaintext: &str, column: SecretColumn, app_id: i32) -> String {
+		let nonce: [u8; NONCE_LEN] = rand::random();
+		let mut encrypted = nonce.to_vec();
+
+		encrypted.extend(
+			self.cipher
+				.encrypt(
+					Nonce::from_slice(&nonce),
+					Payload {
+						msg: plaintext.as_bytes(),
+						aad: column.aad(app_id).as_bytes(),
+					},
+				)
+				.expect("Encryption failure"),
+		);
+
+		base64::encode(encrypted)
+	}
+
+	pub fn decrypt(
+		&self,
+		encrypted: &str,
+		column: SecretColumn,
+		app_id: i32,
+	) -> Result<String, ()> {
+		let encrypted = base64::decode(encrypted).map_err(|_| ())?;
+
+		if encrypted.len() < NONCE_LEN {
+			return Err(());
+		}
+
+		let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+		let plaintext = self
+			.cipher
+			.decrypt(
+				Nonce::from_slice(nonce),
+				Payload {
+					msg: ciphertext,
+					aad: column.aad(app_id).as_bytes(),
+				},
+			)
+			.map_err(|_| ())?;
+
+		String::from_utf8(plaintext).map_err(|_| ())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rocket::figment::Figment;
+
+	use super::*;
+
+	fn encryptor() -> Encryptor {
+		let key = base64::encode([7u8; 32]);
+		Encryptor::from_figment(&Figment::new().merge(("encryption_key", key))).unwrap()
+	}
+
+	#[test]
+	fn decrypts_in_the_same_column_and_app() {
+		let encryptor = encryptor();
+		let encrypted = encryptor.encrypt("hunter2", SecretColumn::EnvVarValue, 1);
+
+		assert_eq!(
+			encryptor.decrypt(&encrypted, SecretColumn::EnvVarValue, 1),
+			Ok("hunter2".to_owned())
+		);
+	}
+
+	#[test]
+	fn refuses_secrets_moved_to_another_column_or_app() {
+		let encryptor = encryptor();
+		let encrypted = encryptor.encrypt("hunter2", SecretColumn::EnvVarValue, 1);
+
+		assert!(encryptor
+			.decrypt(&encrypted, SecretColumn::GitHttpsToken, 1)
+			.is_err());
+		assert!(encryptor
+			.decrypt(&encrypted, SecretColumn::EnvVarValue, 2)
+			.is_err());
+	}
+}
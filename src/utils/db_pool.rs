@@ -0,0 +1,49 @@
+use diesel::{
+	r2d2::{ConnectionManager, Pool, PooledConnection},
+	result::{DatabaseErrorKind, Error},
+	PgConnection, QueryResult,
+};
+use rocket::{Orbit, Rocket};
+
+use crate::DbConn;
+
+/// The pool behind [DbConn], for background tasks. Unlike a [DbConn], which holds on to its
+/// connection for as long as it lives, connections are only taken from the pool for the time of
+/// each query, so that long-running tasks don't starve requests of connections.
+#[derive(Clone)]
+pub struct DbPool(Pool<ConnectionManager<PgConnection>>);
+
+impl DbPool {
+	/// Must be called once the server is running
+	pub async fn from_rocket(rocket: &Rocket<Orbit>) -> Option<Self> {
+		DbConn::get_one(rocket).await.map(|conn| Self::from(&conn))
+	}
+
+	/// Takes a connection from the pool, blocking until one is free or the pool times out
+	pub fn get(&self) -> QueryResult<PooledConnection<ConnectionManager<PgConnection>>> {
+		self.0.get().map_err(|e| {
+			Error::DatabaseError(
+				DatabaseErrorKind::UnableToSendCommand,
+				Box::new(e.to_string()),
+			)
+		})
+	}
+
+	/// Runs `f` with a connection from the pool, like [DbConn::run]
+	pub async fn run<F, R>(&self, f: F) -> QueryResult<R>
+	where
+		F: FnOnce(&PgConnection) -> QueryResult<R> + Send + 'static,
+		R: Send + 'static,
+	{
+		let pool = self.clone();
+		tokio::task::spawn_blocking(move || f(&*pool.get()?))
+			.await
+			.expect("Failed to run a database query")
+	}
+}
+
+impl From<&DbConn> for DbPool {
+	fn from(conn: &DbConn) -> Self {
+		Self(conn.get_pool())
+	}
+}
@@ -0,0 +1,102 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use rocket::tokio::{
+	self,
+	net::{TcpStream, UdpSocket},
+};
+use trust_dns_client::{
+	client::{AsyncClient, ClientHandle},
+	op::DnsResponse,
+	proto::iocompat::AsyncIoTokioAsStd,
+	rr::{DNSClass, Name, RecordType},
+	tcp::TcpClientStream,
+	udp::UdpClientStream,
+};
+
+/// DNS resolvers used to verify domains, tried in order
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DnsConfig {
+	resolvers: Vec<SocketAddr>,
+}
+
+impl Default for DnsConfig {
+	fn default() -> Self {
+		Self {
+			resolvers: vec![(Ipv4Addr::new(1, 1, 1, 1), 53).into()],
+		}
+	}
+}
+
+impl DnsConfig {
+	pub fn from_figment(f: &rocket::figment::Figment) -> Self {
+		f.focus("dns")
+			.extract::<Self>()
+			.expect("Failed to extract DNS config from figment")
+	}
+}
+
+/// Queries the configured resolvers over UDP, falling back to TCP for responses too large for UDP
+/// and resolvers that don't answer
+#[derive(Clone)]
+pub struct DnsClient {
+	resolvers: Vec<(SocketAddr, AsyncClient)>,
+}
+
+impl DnsClient {
+	pub async fn connect(config: &DnsConfig) -> Result<Self, String> {
+		let mut resolvers = Vec::with_capacity(config.resolvers.len());
+		for addr in &config.resolvers {
+			let stream = UdpClientStream::<UdpSocket>::new(*addr);
+			let (client, bg) = AsyncClient::connect(stream)
+				.await
+				.map_err(|e| e.to_string())?;
+			tokio::spawn(bg);
+			resolvers.push((*addr, client));
+		}
+
+		Ok(Self { resolvers })
+	}
+
+	/// Opens a TCP connection just for this query, since resolvers close idle ones anyway
+	async fn query_tcp(
+		addr: SocketAddr,
+		name: Name,
+		record_type: RecordType,
+	) -> Result<DnsResponse, String> {
+		let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::new(addr);
+		let (mut client, bg) = AsyncClient::new(stream, sender, None)
+			.await
+			.map_err(|e| e.to_string())?;
+		tokio::spawn(bg);
+
+		client
+			.query(name, DNSClass::IN, record_type)
+			.await
+			.map_err(|e| e.to_string())
+	}
+
+	pub async fn query(&self, name: Name, record_type: RecordType) -> Result<DnsResponse, String> {
+		let mut last_error = "No DNS resolver configured".to_owned();
+
+		for (addr, client) in &self.resolvers {
+			match client
+				.clone()
+				.query(name.clone(), DNSClass::IN, record_type)
+				.await
+			{
+				Ok(response) if !response.truncated() => return Ok(response),
+				// Too large for UDP
+				Ok(_) => {}
+				Err(e) => log::debug!("UDP query to {} failed, retrying over TCP: {}", addr, e),
+			}
+
+			match Self::query_tcp(*addr, name.clone(), record_type).await {
+				Ok(response) => return Ok(response),
+				Err(e) => last_error = e,
+			}
+		}
+
+		Err(last_error)
+	}
+}
@@ -1,10 +1,12 @@
-use std::{net::Ipv4Addr, str::FromStr};
+use std::{
+	net::{IpAddr, Ipv4Addr},
+	str::FromStr,
+};
 
 use regex::Regex;
-use trust_dns_client::{
-	client::{AsyncClient, ClientHandle},
-	rr::{DNSClass, Name, RData, RecordType},
-};
+use trust_dns_client::rr::{Name, RData, RecordType};
+
+use super::dns::DnsClient;
 
 lazy_static! {
 	static ref DOMAIN_REGEX: Regex = Regex::new("^([A-Za-z0-9-]{1,63}\\.)+[A-Za-z]{2,}$").unwrap();
@@ -14,21 +16,134 @@ pub fn validate_domain(domain: &str) -> bool {
 	DOMAIN_REGEX.is_match(domain)
 }
 
-// Verifies that a domain's DNS configuration is correct
-pub async fn verify_domain(client: &mut AsyncClient, domain: &str) -> Result<bool, String> {
-	let name = Name::from_str(domain)?;
-	let response = client
-		.query(name, DNSClass::IN, RecordType::A)
-		.await
-		.map_err(|e| e.to_string())?;
-
-	for answer in response.answers() {
-		if let RData::A(addr) = answer.rdata() {
-			if addr == &Ipv4Addr::new(167, 99, 113, 134) {
-				return Ok(true);
+/// The domain under which apps get their built-in subdomain
+#[derive(Debug, Clone)]
+pub struct AppsDomain(String);
+
+impl AppsDomain {
+	pub fn from_figment(f: &rocket::figment::Figment) -> Self {
+		match f.extract_inner::<String>("apps_domain") {
+			Ok(domain) => Self(domain),
+			Err(e) if e.missing() => Self("hackclub.app".to_owned()),
+			Err(e) => panic!("Failed to extract apps domain from figment: {}", e),
+		}
+	}
+
+	/// Appended to an app's slug to get its built-in domain
+	pub fn suffix(&self) -> String {
+		format!(".{}", self.0)
+	}
+
+	/// The domain every app gets on creation, which is always verified
+	pub fn builtin_domain(&self, app_slug: &str) -> String {
+		format!("{}{}", app_slug, self.suffix())
+	}
+}
+
+/// Prefix of the TXT record that proves ownership of a domain
+pub const CHALLENGE_PREFIX: &str = "_haas-challenge";
+
+/// Where custom domains may point: addresses matched against A and AAAA records, and hostnames
+/// matched against CNAME records
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DomainTargets {
+	addresses: Vec<IpAddr>,
+	cnames: Vec<String>,
+}
+
+impl Default for DomainTargets {
+	fn default() -> Self {
+		Self {
+			addresses: vec![IpAddr::V4(Ipv4Addr::new(167, 99, 113, 134))],
+			cnames: Vec::new(),
+		}
+	}
+}
+
+impl DomainTargets {
+	pub fn from_figment(f: &rocket::figment::Figment) -> Self {
+		f.focus("domain_targets")
+			.extract::<Self>()
+			.expect("Failed to extract domain targets from figment")
+	}
+
+	fn matches(&self, rdata: &RData) -> bool {
+		match rdata {
+			RData::A(addr) => self.addresses.contains(&IpAddr::V4(*addr)),
+			RData::AAAA(addr) => self.addresses.contains(&IpAddr::V6(*addr)),
+			RData::CNAME(name) => {
+				let name = name.to_utf8();
+				let name = name.trim_end_matches('.');
+				self.cnames
+					.iter()
+					.any(|c| c.trim_end_matches('.').eq_ignore_ascii_case(name))
 			}
+			_ => false,
+		}
+	}
+}
+
+async fn query(
+	client: &DnsClient,
+	domain: &str,
+	record_type: RecordType,
+) -> Result<Vec<RData>, String> {
+	let name = Name::from_str(domain)?;
+	let response = client.query(name, record_type).await?;
+
+	Ok(response
+		.answers()
+		.iter()
+		.map(|answer| answer.rdata().clone())
+		.collect())
+}
+
+/// Checks that a domain resolves to one of our addresses, either directly (which covers apex
+/// domains flattened by their DNS provider) or through a CNAME to one of our hostnames
+pub async fn points_to_us(
+	client: &DnsClient,
+	targets: &DomainTargets,
+	domain: &str,
+) -> Result<bool, String> {
+	for record_type in [RecordType::A, RecordType::AAAA] {
+		// CNAMEs are returned along with the records they resolve to
+		if query(client, domain, record_type)
+			.await?
+			.iter()
+			.any(|rdata| targets.matches(rdata))
+		{
+			return Ok(true);
 		}
 	}
 
 	Ok(false)
 }
+
+/// Checks that the domain's challenge TXT record contains its token, which proves that whoever
+/// added the domain controls its DNS
+pub async fn has_challenge(client: &DnsClient, domain: &str, token: &str) -> Result<bool, String> {
+	let records = query(
+		client,
+		&format!("{}.{}", CHALLENGE_PREFIX, domain),
+		RecordType::TXT,
+	)
+	.await?;
+
+	Ok(records.iter().any(|rdata| match rdata {
+		// Long values are split in several strings
+		RData::TXT(txt) => txt.txt_data().concat() == token.as_bytes(),
+		_ => false,
+	}))
+}
+
+/// Verifies that a domain belongs to whoever added it, and that its DNS configuration is correct
+pub async fn verify_domain(
+	client: &DnsClient,
+	targets: &DomainTargets,
+	domain: &str,
+	challenge_token: &str,
+) -> Result<bool, String> {
+	Ok(has_challenge(client, domain, challenge_token).await?
+		&& points_to_us(client, targets, domain).await?)
+}
@@ -0,0 +1,9 @@
+use regex::Regex;
+
+lazy_static! {
+	static ref ENV_KEY_REGEX: Regex = Regex::new("^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+}
+
+pub fn validate_env_key(key: &str) -> bool {
+	ENV_KEY_REGEX.is_match(key)
+}
@@ -1,6 +1,12 @@
+pub mod advisory_lock;
+pub mod certificate;
+pub mod crypto;
+pub mod db_pool;
+pub mod dns;
 pub mod domain;
+pub mod env_var;
 pub mod oauth_device;
 pub mod slug;
 pub mod token;
-pub mod uri_serializer;
 pub mod url_serializer;
+pub mod webhook;
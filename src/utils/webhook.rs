@@ -0,0 +1,77 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+/// Checks a hex-encoded HMAC-SHA256 of `body`, as sent by GitHub and Gitea
+pub fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+	let signature = match hex::decode(signature) {
+		Ok(s) => s,
+		Err(_) => return false,
+	};
+	let mut mac =
+		Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+	mac.update(body);
+
+	// Constant time comparison
+	mac.verify(&signature).is_ok()
+}
+
+/// Compares a secret sent as is (as done by GitLab) without leaking its contents through timing
+pub fn verify_token(secret: &str, token: &str) -> bool {
+	secret.len() == token.len()
+		&& secret
+			.bytes()
+			.zip(token.bytes())
+			.fold(0, |acc, (a, b)| acc | (a ^ b))
+			== 0
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Example from GitHub's documentation on validating webhook deliveries
+	const SECRET: &str = "It's a Secret to Everybody";
+	const BODY: &[u8] = b"Hello, World!";
+	const SIGNATURE: &str = "757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+	#[test]
+	fn accepts_valid_signature() {
+		assert!(verify_signature(SECRET, BODY, SIGNATURE));
+		assert!(verify_signature(SECRET, BODY, &SIGNATURE.to_uppercase()));
+	}
+
+	#[test]
+	fn rejects_tampered_body() {
+		assert!(!verify_signature(SECRET, b"Hello, World?", SIGNATURE));
+	}
+
+	#[test]
+	fn rejects_wrong_secret() {
+		assert!(!verify_signature("Not the secret", BODY, SIGNATURE));
+	}
+
+	#[test]
+	fn rejects_malformed_signature() {
+		assert!(!verify_signature(SECRET, BODY, ""));
+		assert!(!verify_signature(SECRET, BODY, "not hex"));
+		assert!(!verify_signature(SECRET, BODY, &SIGNATURE[..62]));
+		assert!(!verify_signature(
+			SECRET,
+			BODY,
+			&format!("sha256={}", SIGNATURE)
+		));
+	}
+
+	#[test]
+	fn accepts_matching_token() {
+		assert!(verify_token(SECRET, SECRET));
+	}
+
+	#[test]
+	fn rejects_other_tokens() {
+		assert!(!verify_token(SECRET, ""));
+		assert!(!verify_token(SECRET, "It's a Secret to Everybody!"));
+		assert!(!verify_token(SECRET, "It's a Secret to Everybodz"));
+		assert!(!verify_token(SECRET, "it's a secret to everybody"));
+	}
+}